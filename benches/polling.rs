@@ -0,0 +1,30 @@
+//! A full polling cycle of the sync client against the simulator, includes the minimum delay
+//! between the commands so mainly additional round trips show up
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use dalybms_lib::{
+    serialport::DalyBMS,
+    simulator::{Simulator, SimulatorPort},
+    snapshot::Metric,
+};
+use std::sync::{Arc, Mutex};
+
+fn client(pipelining: bool) -> DalyBMS {
+    let simulator = Arc::new(Mutex::new(Simulator::default()));
+    let mut bms = DalyBMS::from_port(Box::new(SimulatorPort::new(simulator)));
+    bms.set_pipelining(pipelining);
+    bms
+}
+
+fn polling_cycle(c: &mut Criterion) {
+    let mut group = c.benchmark_group("polling cycle");
+    group.sample_size(20);
+    for (name, pipelining) in [("single commands", false), ("pipelined", true)] {
+        let mut bms = client(pipelining);
+        group.bench_function(name, |b| b.iter(|| bms.get_snapshot(&Metric::ALL).unwrap()));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, polling_cycle);
+criterion_main!(benches);
@@ -0,0 +1,39 @@
+//! Encoding of requests and decoding of replies, the replies are generated by the simulator
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use dalybms_lib::{analyzer, protocol::*, simulator::Simulator};
+
+fn encode(c: &mut Criterion) {
+    c.bench_function("encode soc request", |b| {
+        b.iter(|| Soc::request(black_box(Address::Host)))
+    });
+    c.bench_function("encode set soc request", |b| {
+        b.iter(|| SetSoc::request(black_box(Address::Host), black_box(75.3)))
+    });
+}
+
+fn decode(c: &mut Criterion) {
+    let mut simulator = Simulator::default();
+    let soc = simulator.respond(&Soc::request(Address::Host));
+    let status = simulator.respond(&Status::request(Address::Host));
+    let cell_voltages = simulator.respond(&CellVoltages::request(Address::Host));
+    let cell_temperatures = simulator.respond(&CellTemperatures::request(Address::Host));
+    let n_cells = Status::decode(&status).unwrap().cells;
+
+    c.bench_function("decode soc", |b| b.iter(|| Soc::decode(black_box(&soc))));
+    c.bench_function("decode status", |b| {
+        b.iter(|| Status::decode(black_box(&status)))
+    });
+    c.bench_function("decode cell voltages", |b| {
+        b.iter(|| CellVoltages::decode(black_box(&cell_voltages), n_cells))
+    });
+    c.bench_function("decode cell temperatures", |b| {
+        b.iter(|| CellTemperatures::decode(black_box(&cell_temperatures), 3))
+    });
+    c.bench_function("analyze cell voltages", |b| {
+        b.iter(|| analyzer::analyze(black_box(&cell_voltages)))
+    });
+}
+
+criterion_group!(benches, encode, decode);
+criterion_main!(benches);
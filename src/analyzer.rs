@@ -0,0 +1,300 @@
+//! Splits captured bytes into frames and describes them, independent of a client, e.g. for
+//! logic analyzer captures or traffic between another controller and the BMS.
+
+use crate::protocol::*;
+use std::fmt;
+
+const FRAME_LENGTH: usize = 13;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Sent by the host (address 0x40)
+    Request,
+    /// Sent by the BMS
+    Reply,
+}
+
+#[derive(Debug, Clone)]
+pub struct AnalyzedFrame {
+    /// Position of the frame in the analyzed bytes
+    pub offset: usize,
+    pub raw: [u8; FRAME_LENGTH],
+    pub direction: Direction,
+    pub checksum_ok: bool,
+    /// Decoded fields, `None` if the command or its layout is unknown
+    pub decoded: Option<String>,
+}
+
+impl AnalyzedFrame {
+    pub fn command(&self) -> u8 {
+        self.raw[2]
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.raw[4..12]
+    }
+}
+
+impl fmt::Display for AnalyzedFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "@{} {:?} {:02X} {} {:02X?}",
+            self.offset,
+            self.direction,
+            self.command(),
+            command_name(self.command()).unwrap_or("unknown"),
+            self.data()
+        )?;
+        if !self.checksum_ok {
+            write!(f, " invalid checksum")?;
+        }
+        if let Some(decoded) = &self.decoded {
+            write!(f, " {}", decoded)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn command_name(command: u8) -> Option<&'static str> {
+    commands::find(command).map(|info| info.name)
+}
+
+fn decode_request(frame: &[u8]) -> Option<String> {
+    let data = &frame[4..12];
+    match frame[2] {
+        0x21 => Some(format!(
+            "soc_percent={}",
+            u16::from_be_bytes([data[6], data[7]]) as f32 / 10.0
+        )),
+        0xD9 | 0xDA => Some(format!("enable={}", data[0] != 0)),
+        0x1B => Some(format!(
+            "charge_level1={} charge_level2={} discharge_level1={} discharge_level2={}",
+            -decode_current(data[0], data[1]),
+            -decode_current(data[2], data[3]),
+            decode_current(data[4], data[5]),
+            decode_current(data[6], data[7])
+        )),
+        _ => None,
+    }
+}
+
+fn decode_reply(frame: &[u8]) -> Option<String> {
+    let data = &frame[4..12];
+    match frame[2] {
+        0x90 => Soc::decode(frame).ok().map(|value| format!("{:?}", value)),
+        0x91 => CellVoltageRange::decode(frame)
+            .ok()
+            .map(|value| format!("{:?}", value)),
+        0x92 => TemperatureRange::decode(frame)
+            .ok()
+            .map(|value| format!("{:?}", value)),
+        0x93 if data[0] <= 2 => MosfetStatus::decode(frame)
+            .ok()
+            .map(|value| format!("{:?}", value)),
+        0x94 => Status::decode(frame)
+            .ok()
+            .map(|value| format!("{:?}", value)),
+        0x52 => CumulativeCapacity::decode(frame)
+            .ok()
+            .map(|value| format!("{:?}", value)),
+        0x5B => CurrentLimits::decode(frame)
+            .ok()
+            .map(|value| format!("{:?}", value)),
+        0x98 => ErrorCode::decode(frame)
+            .ok()
+            .map(|value| format!("{:?}", value)),
+        // multi frame replies, the number of cells is unknown so all values of the frame are shown
+        0x95 => Some(format!(
+            "frame={} voltages={:?}",
+            data[0],
+            (0..3)
+                .map(|i| u16::from_be_bytes([data[1 + i * 2], data[2 + i * 2]]) as f32 / 1000.0)
+                .collect::<Vec<_>>()
+        )),
+        0x96 => Some(format!(
+            "frame={} temperatures={:?}",
+            data[0],
+            data[1..8]
+                .iter()
+                .map(|value| *value as i32 - 40)
+                .collect::<Vec<_>>()
+        )),
+        0x97 => CellBalanceState::decode(frame, 48).ok().map(|balancing| {
+            let cells: Vec<usize> = balancing
+                .iter()
+                .enumerate()
+                .filter(|(_, active)| **active)
+                .map(|(n, _)| n + 1)
+                .collect();
+            format!("balancing cells={:?}", cells)
+        }),
+        0x53 | 0x62 | 0x63 => Some(format!(
+            "frame={} text={:?}",
+            data[0],
+            String::from_utf8_lossy(&data[1..8])
+        )),
+        _ => None,
+    }
+}
+
+fn analyze_frame(offset: usize, raw: [u8; FRAME_LENGTH]) -> AnalyzedFrame {
+    let checksum_ok = calc_crc(&raw) == raw[FRAME_LENGTH - 1];
+    let direction = if raw[1] == Address::Host as u8 {
+        Direction::Request
+    } else {
+        Direction::Reply
+    };
+    let decoded = match (checksum_ok, direction) {
+        (false, _) => None,
+        (true, Direction::Request) => decode_request(&raw),
+        (true, Direction::Reply) => decode_reply(&raw),
+    };
+    AnalyzedFrame {
+        offset,
+        raw,
+        direction,
+        checksum_ok,
+        decoded,
+    }
+}
+
+/// Finds all frames in `bytes`, bytes which don't belong to a frame are skipped
+pub fn analyze(bytes: &[u8]) -> Vec<AnalyzedFrame> {
+    StreamAnalyzer::default().push(bytes)
+}
+
+/// Reassembles frames from bytes arriving in arbitrary chunks, e.g. read from a serial port
+#[derive(Debug, Clone, Default)]
+pub struct StreamAnalyzer {
+    buffer: Vec<u8>,
+    /// Stream position of the first byte in `buffer`
+    position: usize,
+    skipped: usize,
+}
+
+impl StreamAnalyzer {
+    /// Returns the frames completed by `bytes`, an incomplete frame is kept for the next call.
+    /// A frame with an invalid checksum is only returned if no valid frame starts within it,
+    /// otherwise its start byte is skipped and the valid frame is found by the next iteration
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<AnalyzedFrame> {
+        self.buffer.extend_from_slice(bytes);
+        let mut frames = Vec::new();
+        let mut offset = 0;
+        while offset < self.buffer.len() {
+            if !self.is_frame_start(offset) {
+                offset += 1;
+                self.skipped += 1;
+                continue;
+            }
+            if offset + FRAME_LENGTH > self.buffer.len() {
+                break;
+            }
+            let mut raw = [0; FRAME_LENGTH];
+            raw.copy_from_slice(&self.buffer[offset..offset + FRAME_LENGTH]);
+            if calc_crc(&raw) != raw[FRAME_LENGTH - 1] {
+                match self.valid_frame_within(offset) {
+                    Some(true) => {
+                        offset += 1;
+                        self.skipped += 1;
+                        continue;
+                    }
+                    // wait for the rest of a frame which might start within this one
+                    None => break,
+                    Some(false) => {}
+                }
+            }
+            frames.push(analyze_frame(self.position + offset, raw));
+            offset += FRAME_LENGTH;
+        }
+        self.buffer.drain(..offset);
+        self.position += offset;
+        frames
+    }
+
+    // a start byte followed by the data length, the length is unknown at the end of the buffer
+    fn is_frame_start(&self, offset: usize) -> bool {
+        self.buffer[offset] == START_BYTE
+            && self
+                .buffer
+                .get(offset + 3)
+                .is_none_or(|length| *length == DATA_LENGTH)
+    }
+
+    // whether a frame with a valid checksum starts within the frame at `offset`, `None` if
+    // such a frame is still incomplete
+    fn valid_frame_within(&self, offset: usize) -> Option<bool> {
+        for start in offset + 1..offset + FRAME_LENGTH {
+            if !self.is_frame_start(start) {
+                continue;
+            }
+            let frame = self.buffer.get(start..start + FRAME_LENGTH)?;
+            if calc_crc(frame) == frame[FRAME_LENGTH - 1] {
+                return Some(true);
+            }
+        }
+        Some(false)
+    }
+
+    /// Number of bytes which didn't belong to a frame so far
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+}
+
+/// Parses hex strings like "A5 40 90 08", "a5:40:90:08" or "0xA5,0x40"
+pub fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    let digits: String = text
+        .split(|c: char| c.is_whitespace() || c == ':' || c == ',' || c == '-')
+        .map(|part| part.trim_start_matches("0x").trim_start_matches("0X"))
+        .collect();
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(digits.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn soc_reply() -> Vec<u8> {
+        crate::simulator::Simulator::default().respond(&Soc::request(Address::Host))
+    }
+
+    #[test]
+    fn corrupted_frame_is_reported() {
+        let mut reply = soc_reply();
+        reply[6] ^= 0x01;
+        let frames = analyze(&reply);
+        assert_eq!(frames.len(), 1);
+        assert!(!frames[0].checksum_ok);
+    }
+
+    #[test]
+    fn resync_after_false_start() {
+        // the start of a cut off frame, the valid frame begins within its 13 bytes
+        let mut bytes = vec![START_BYTE, 0x01, 0x90, DATA_LENGTH, 0x00];
+        bytes.extend(soc_reply());
+        let frames = analyze(&bytes);
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].checksum_ok);
+        assert_eq!(frames[0].offset, 5);
+    }
+
+    #[test]
+    fn resync_across_chunks() {
+        let mut bytes = vec![START_BYTE, 0x01, 0x90, DATA_LENGTH];
+        bytes.extend(soc_reply());
+        let mut stream = StreamAnalyzer::default();
+        let mut frames = stream.push(&bytes[..13]);
+        assert!(frames.is_empty());
+        frames.extend(stream.push(&bytes[13..]));
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].offset, 4);
+        assert_eq!(stream.skipped(), 4);
+    }
+}
@@ -0,0 +1,201 @@
+use crate::snapshot::BmsSnapshot;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// How the packs of a battery bank are wired
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum BankTopology {
+    Series,
+    Parallel,
+}
+
+/// Metrics of a battery bank built from the snapshots of its packs,
+/// a value is `None` if not all packs provide the metrics it is calculated from
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct BankMetrics {
+    pub voltage: Option<f32>,
+    pub current: Option<f32>,
+    pub soc_percent: Option<f32>,
+    pub lowest_cell_voltage: Option<f32>,
+    /// Index of the pack and number of the cell with the lowest voltage
+    pub lowest_cell: Option<(usize, u8)>,
+}
+
+impl BankMetrics {
+    pub fn aggregate(topology: BankTopology, packs: &[BmsSnapshot]) -> Self {
+        if packs.is_empty() {
+            return Self::default();
+        }
+        let n_packs = packs.len() as f32;
+        let socs: Option<Vec<_>> = packs.iter().map(|pack| pack.soc.as_ref()).collect();
+        let mut result = Self::default();
+
+        if let Some(socs) = &socs {
+            let voltage_sum: f32 = socs.iter().map(|soc| soc.total_voltage).sum();
+            let current_sum: f32 = socs.iter().map(|soc| soc.current).sum();
+            match topology {
+                BankTopology::Series => {
+                    result.voltage = Some(voltage_sum);
+                    result.current = Some(current_sum / n_packs);
+                    // the bank is empty as soon as the weakest pack is empty
+                    result.soc_percent = socs.iter().map(|soc| soc.soc_percent).reduce(f32::min);
+                }
+                BankTopology::Parallel => {
+                    result.voltage = Some(voltage_sum / n_packs);
+                    result.current = Some(current_sum);
+                    result.soc_percent =
+                        Some(Self::capacity_weighted_soc(packs).unwrap_or_else(|| {
+                            socs.iter().map(|soc| soc.soc_percent).sum::<f32>() / n_packs
+                        }));
+                }
+            }
+        }
+
+        for (index, pack) in packs.iter().enumerate() {
+            if let Some(range) = &pack.cell_voltage_range {
+                if result
+                    .lowest_cell_voltage
+                    .is_none_or(|lowest| range.lowest_voltage < lowest)
+                {
+                    result.lowest_cell_voltage = Some(range.lowest_voltage);
+                    result.lowest_cell = Some((index, range.lowest_cell));
+                }
+            } else {
+                result.lowest_cell_voltage = None;
+                result.lowest_cell = None;
+                break;
+            }
+        }
+        result
+    }
+
+    // The full capacity of each pack is estimated from its remaining capacity and SOC
+    fn capacity_weighted_soc(packs: &[BmsSnapshot]) -> Option<f32> {
+        let mut remaining_sum = 0.0;
+        let mut full_sum = 0.0;
+        for pack in packs {
+            let soc_percent = pack.soc.as_ref()?.soc_percent;
+            let remaining = pack.mosfet_status.as_ref()?.capacity_ah;
+            if soc_percent <= 0.0 {
+                return None;
+            }
+            remaining_sum += remaining;
+            full_sum += remaining / (soc_percent / 100.0);
+        }
+        if full_sum > 0.0 {
+            Some(remaining_sum / full_sum * 100.0)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{CellVoltageRange, MosfetStatus, Soc};
+
+    fn pack(total_voltage: f32, current: f32, soc_percent: f32) -> BmsSnapshot {
+        BmsSnapshot {
+            soc: Some(Soc {
+                total_voltage,
+                current,
+                soc_percent,
+            }),
+            ..BmsSnapshot::default()
+        }
+    }
+
+    fn with_capacity(mut pack: BmsSnapshot, capacity_ah: f32) -> BmsSnapshot {
+        pack.mosfet_status = Some(MosfetStatus {
+            capacity_ah,
+            ..MosfetStatus::default()
+        });
+        pack
+    }
+
+    fn with_lowest_cell(
+        mut pack: BmsSnapshot,
+        lowest_cell: u8,
+        lowest_voltage: f32,
+    ) -> BmsSnapshot {
+        pack.cell_voltage_range = Some(CellVoltageRange {
+            highest_voltage: 3.4,
+            highest_cell: 1,
+            lowest_voltage,
+            lowest_cell,
+        });
+        pack
+    }
+
+    #[test]
+    fn series() {
+        let packs = [pack(26.0, 10.0, 80.0), pack(26.4, 10.2, 70.0)];
+        let bank = BankMetrics::aggregate(BankTopology::Series, &packs);
+        assert!((bank.voltage.unwrap() - 52.4).abs() < 1e-4);
+        assert!((bank.current.unwrap() - 10.1).abs() < 1e-4);
+        assert_eq!(bank.soc_percent, Some(70.0));
+    }
+
+    #[test]
+    fn parallel_weighted_by_capacity() {
+        // 100Ah pack at 50% and 300Ah pack at 100%
+        let packs = [
+            with_capacity(pack(52.0, 5.0, 50.0), 50.0),
+            with_capacity(pack(52.2, 15.0, 100.0), 300.0),
+        ];
+        let bank = BankMetrics::aggregate(BankTopology::Parallel, &packs);
+        assert!((bank.voltage.unwrap() - 52.1).abs() < 1e-4);
+        assert_eq!(bank.current, Some(20.0));
+        assert!((bank.soc_percent.unwrap() - 87.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn parallel_without_capacity() {
+        let packs = [
+            with_capacity(pack(52.0, 5.0, 50.0), 50.0),
+            pack(52.2, 15.0, 100.0),
+        ];
+        let bank = BankMetrics::aggregate(BankTopology::Parallel, &packs);
+        assert_eq!(bank.soc_percent, Some(75.0));
+    }
+
+    #[test]
+    fn missing_pack() {
+        for topology in [BankTopology::Series, BankTopology::Parallel] {
+            let packs = [
+                with_lowest_cell(pack(26.0, 10.0, 80.0), 3, 3.21),
+                BmsSnapshot::default(),
+            ];
+            let bank = BankMetrics::aggregate(topology, &packs);
+            assert!(bank.voltage.is_none());
+            assert!(bank.current.is_none());
+            assert!(bank.soc_percent.is_none());
+            assert!(bank.lowest_cell_voltage.is_none());
+            assert!(bank.lowest_cell.is_none());
+        }
+    }
+
+    #[test]
+    fn lowest_cell_of_all_packs() {
+        let packs = [
+            with_lowest_cell(pack(26.0, 10.0, 80.0), 3, 3.21),
+            with_lowest_cell(pack(26.0, 10.0, 80.0), 7, 3.19),
+            with_lowest_cell(pack(26.0, 10.0, 80.0), 2, 3.25),
+        ];
+        let bank = BankMetrics::aggregate(BankTopology::Series, &packs);
+        assert_eq!(bank.lowest_cell_voltage, Some(3.19));
+        assert_eq!(bank.lowest_cell, Some((1, 7)));
+    }
+
+    #[test]
+    fn no_packs() {
+        let bank = BankMetrics::aggregate(BankTopology::Parallel, &[]);
+        assert!(bank.voltage.is_none());
+    }
+}
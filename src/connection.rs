@@ -0,0 +1,15 @@
+/// Changes of the connection to the BMS reported by the clients, e.g. to update the state of
+/// a user interface without parsing the log
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionEvent {
+    /// The BMS answered for the first time after opening the port or after a disconnect
+    Connected,
+    /// The BMS stopped answering or the port failed
+    Disconnected { error: String },
+    /// A write did not take effect and is repeated, see `set_verify_writes()`
+    Retry {
+        operation: &'static str,
+        /// Number of the upcoming attempt, starting at 2
+        attempt: u32,
+    },
+}
@@ -0,0 +1,15 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Identity of the connected BMS, read once after connecting, see `DalyBMS::identify()`.
+/// Values of commands the firmware does not support are `None`.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct DeviceInfo {
+    pub cells: u8,
+    pub temperature_sensors: u8,
+    pub battery_code: Option<String>,
+    pub software_version: Option<String>,
+    pub hardware_version: Option<String>,
+}
@@ -5,6 +5,19 @@ pub enum Error {
     CheckSumError,
     ReplySizeError,
     FrameNoError,
+    FrameHeaderError,
+    /// More values than fit into a fixed capacity output
+    CapacityError,
+    ImplausibleValue {
+        name: &'static str,
+        value: f64,
+    },
+    Unsupported(u8),
+    VerificationFailed {
+        name: &'static str,
+        expected: f64,
+        received: f64,
+    },
     Io(std::io::Error),
 }
 
@@ -19,6 +32,23 @@ impl fmt::Display for Error {
             Error::CheckSumError => write!(f, "Invalid checksum"),
             Error::ReplySizeError => write!(f, "Invalid reply size"),
             Error::FrameNoError => write!(f, "Frame out of order"),
+            Error::FrameHeaderError => write!(f, "Invalid frame header"),
+            Error::CapacityError => write!(f, "Output capacity exceeded"),
+            Error::ImplausibleValue { name, value } => {
+                write!(f, "Implausible value for {}: {}", name, value)
+            }
+            Error::VerificationFailed {
+                name,
+                expected,
+                received,
+            } => write!(
+                f,
+                "Verification of {} failed: expected {}, read back {}",
+                name, expected, received
+            ),
+            Error::Unsupported(command) => {
+                write!(f, "Command {:02X} not supported by the firmware", command)
+            }
         }
     }
 }
@@ -28,3 +58,21 @@ impl From<std::io::Error> for Error {
         Error::Io(err)
     }
 }
+
+/// Maps the reply of a command missing in the firmware (`Error::Unsupported`) to `None`.
+/// Timeouts and all other errors are passed on, a BMS which stopped answering must not look
+/// like a firmware without the command
+#[cfg(any(feature = "serialport", feature = "tokio-serial-async"))]
+pub(crate) fn unsupported_as_none<T>(
+    result: anyhow::Result<T>,
+    name: &str,
+) -> anyhow::Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(err) if matches!(err.downcast_ref::<Error>(), Some(Error::Unsupported(_))) => {
+            log::debug!("{} not supported by the firmware", name);
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
@@ -0,0 +1,169 @@
+use crate::protocol::ErrorCode;
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Life cycle of one error code since the tracking started
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ErrorHistory {
+    /// Set while the error is active
+    pub active_since: Option<SystemTime>,
+    /// How often the error was raised
+    pub occurrences: u32,
+    /// Accumulated active time of all cleared occurrences
+    pub total_active: Duration,
+}
+
+impl ErrorHistory {
+    /// Accumulated active time including the current occurrence
+    pub fn active_duration(&self, now: SystemTime) -> Duration {
+        self.total_active
+            + self
+                .active_since
+                .and_then(|since| now.duration_since(since).ok())
+                .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum ErrorTransition {
+    Raised(ErrorCode),
+    Cleared {
+        error: ErrorCode,
+        /// Duration of the occurrence which just ended
+        active: Duration,
+    },
+}
+
+/// Tracks the errors reported by consecutive polls and reports when they are raised or cleared
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ErrorTracker {
+    history: HashMap<ErrorCode, ErrorHistory>,
+}
+
+impl ErrorTracker {
+    pub fn update(&mut self, errors: &[ErrorCode]) -> Vec<ErrorTransition> {
+        self.update_at(errors, SystemTime::now())
+    }
+
+    /// Like `update()` with the time the errors were read
+    pub fn update_at(&mut self, errors: &[ErrorCode], now: SystemTime) -> Vec<ErrorTransition> {
+        let mut transitions = Vec::new();
+        for (error, history) in self.history.iter_mut() {
+            if errors.contains(error) {
+                continue;
+            }
+            if let Some(since) = history.active_since.take() {
+                let active = now.duration_since(since).unwrap_or_default();
+                history.total_active += active;
+                transitions.push(ErrorTransition::Cleared {
+                    error: *error,
+                    active,
+                });
+            }
+        }
+        for error in errors {
+            let history = self.history.entry(*error).or_default();
+            if history.active_since.is_none() {
+                history.active_since = Some(now);
+                history.occurrences += 1;
+                transitions.push(ErrorTransition::Raised(*error));
+            }
+        }
+        transitions
+    }
+
+    /// All errors seen since the tracking started
+    pub fn history(&self) -> &HashMap<ErrorCode, ErrorHistory> {
+        &self.history
+    }
+
+    pub fn active(&self) -> impl Iterator<Item = ErrorCode> + '_ {
+        self.history
+            .iter()
+            .filter(|(_, history)| history.active_since.is_some())
+            .map(|(error, _)| *error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(seconds: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn raise_and_clear() {
+        let mut tracker = ErrorTracker::default();
+        assert_eq!(
+            tracker.update_at(&[ErrorCode::CellVoltHighLevel1], at(0)),
+            vec![ErrorTransition::Raised(ErrorCode::CellVoltHighLevel1)]
+        );
+        // still active, no transition
+        assert!(tracker
+            .update_at(&[ErrorCode::CellVoltHighLevel1], at(10))
+            .is_empty());
+        assert_eq!(
+            tracker.update_at(&[], at(30)),
+            vec![ErrorTransition::Cleared {
+                error: ErrorCode::CellVoltHighLevel1,
+                active: Duration::from_secs(30),
+            }]
+        );
+        assert_eq!(tracker.active().count(), 0);
+    }
+
+    #[test]
+    fn occurrences_and_active_time() {
+        let mut tracker = ErrorTracker::default();
+        tracker.update_at(&[ErrorCode::SumVoltHighLevel1], at(0));
+        tracker.update_at(&[], at(20));
+        tracker.update_at(&[ErrorCode::SumVoltHighLevel1], at(100));
+        tracker.update_at(&[], at(105));
+        tracker.update_at(&[ErrorCode::SumVoltHighLevel1], at(200));
+
+        let history = &tracker.history()[&ErrorCode::SumVoltHighLevel1];
+        assert_eq!(history.occurrences, 3);
+        assert_eq!(history.total_active, Duration::from_secs(25));
+        assert_eq!(history.active_since, Some(at(200)));
+        // includes the occurrence in progress
+        assert_eq!(history.active_duration(at(210)), Duration::from_secs(35));
+        assert_eq!(
+            tracker.active().collect::<Vec<_>>(),
+            vec![ErrorCode::SumVoltHighLevel1]
+        );
+    }
+
+    #[test]
+    fn independent_errors() {
+        let mut tracker = ErrorTracker::default();
+        tracker.update_at(&[ErrorCode::CellVoltHighLevel1], at(0));
+        let transitions = tracker.update_at(&[ErrorCode::SumVoltHighLevel1], at(5));
+        assert_eq!(
+            transitions,
+            vec![
+                ErrorTransition::Cleared {
+                    error: ErrorCode::CellVoltHighLevel1,
+                    active: Duration::from_secs(5),
+                },
+                ErrorTransition::Raised(ErrorCode::SumVoltHighLevel1),
+            ]
+        );
+        assert_eq!(
+            tracker.history()[&ErrorCode::CellVoltHighLevel1].occurrences,
+            1
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::VecDeque;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Spread of the cell voltages of one reading
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct CellVoltageStats {
+    pub mean: f32,
+    pub std_dev: f32,
+    /// Difference between the highest and lowest cell voltage
+    pub max_delta: f32,
+    /// Number of the cell with the lowest voltage, starting at 1 like the BMS
+    pub lowest_cell: usize,
+    pub highest_cell: usize,
+}
+
+impl CellVoltageStats {
+    pub fn from_voltages(voltages: &[f32]) -> Option<Self> {
+        if voltages.is_empty() {
+            return None;
+        }
+        let n_cells = voltages.len() as f32;
+        let mean = voltages.iter().sum::<f32>() / n_cells;
+        let variance = voltages
+            .iter()
+            .map(|voltage| (voltage - mean).powi(2))
+            .sum::<f32>()
+            / n_cells;
+        let (lowest_cell, lowest) = voltages
+            .iter()
+            .enumerate()
+            .min_by(|a, b| a.1.total_cmp(b.1))?;
+        let (highest_cell, highest) = voltages
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))?;
+        Some(Self {
+            mean,
+            std_dev: variance.sqrt(),
+            max_delta: highest - lowest,
+            lowest_cell: lowest_cell + 1,
+            highest_cell: highest_cell + 1,
+        })
+    }
+}
+
+/// Counts the cell voltages per bucket of `bucket_width` volts, returns the lower bound
+/// of each non empty bucket with its count in ascending order
+pub fn voltage_histogram(voltages: &[f32], bucket_width: f32) -> Vec<(f32, usize)> {
+    let mut buckets: Vec<(i64, usize)> = Vec::new();
+    for voltage in voltages {
+        let bucket = (voltage / bucket_width).floor() as i64;
+        match buckets.binary_search_by_key(&bucket, |(bucket, _)| *bucket) {
+            Ok(index) => buckets[index].1 += 1,
+            Err(index) => buckets.insert(index, (bucket, 1)),
+        }
+    }
+    buckets
+        .into_iter()
+        .map(|(bucket, count)| (bucket as f32 * bucket_width, count))
+        .collect()
+}
+
+/// Imbalance over the last readings, helps to identify a weak cell before it triggers alarms
+#[derive(Debug, Clone)]
+pub struct ImbalanceTrend {
+    window: usize,
+    samples: VecDeque<CellVoltageStats>,
+}
+
+impl ImbalanceTrend {
+    pub fn new(window: usize) -> Self {
+        Self {
+            window: window.max(1),
+            samples: VecDeque::with_capacity(window.max(1)),
+        }
+    }
+
+    pub fn push(&mut self, stats: CellVoltageStats) {
+        if self.samples.len() == self.window {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(stats);
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn mean_delta(&self) -> Option<f32> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        Some(
+            self.samples
+                .iter()
+                .map(|stats| stats.max_delta)
+                .sum::<f32>()
+                / self.samples.len() as f32,
+        )
+    }
+
+    /// Change of the voltage delta from the oldest to the newest reading in the window,
+    /// a growing value means the cells are drifting apart
+    pub fn delta_change(&self) -> Option<f32> {
+        Some(self.samples.back()?.max_delta - self.samples.front()?.max_delta)
+    }
+
+    /// The cell which was lowest most often with the share of readings it was lowest
+    pub fn weakest_cell(&self) -> Option<(usize, f32)> {
+        let mut counts: Vec<(usize, usize)> = Vec::new();
+        for stats in &self.samples {
+            match counts
+                .iter_mut()
+                .find(|(cell, _)| *cell == stats.lowest_cell)
+            {
+                Some((_, count)) => *count += 1,
+                None => counts.push((stats.lowest_cell, 1)),
+            }
+        }
+        counts
+            .into_iter()
+            .max_by_key(|(_, count)| *count)
+            .map(|(cell, count)| (cell, count as f32 / self.samples.len() as f32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(lowest_cell: usize, max_delta: f32) -> CellVoltageStats {
+        CellVoltageStats {
+            mean: 3.3,
+            std_dev: 0.0,
+            max_delta,
+            lowest_cell,
+            highest_cell: 1,
+        }
+    }
+
+    #[test]
+    fn voltage_stats() {
+        let stats = CellVoltageStats::from_voltages(&[3.30, 3.32, 3.28, 3.30]).unwrap();
+        assert!((stats.mean - 3.30).abs() < 1e-6);
+        assert!((stats.max_delta - 0.04).abs() < 1e-6);
+        assert!((stats.std_dev - (0.0008_f32 / 4.0).sqrt()).abs() < 1e-5);
+        assert_eq!(stats.lowest_cell, 3);
+        assert_eq!(stats.highest_cell, 2);
+        assert!(CellVoltageStats::from_voltages(&[]).is_none());
+    }
+
+    #[test]
+    fn histogram_buckets() {
+        let histogram = voltage_histogram(&[3.31, 3.29, 3.305, 3.42], 0.05);
+        let counts: Vec<usize> = histogram.iter().map(|(_, count)| *count).collect();
+        assert_eq!(counts, vec![1, 2, 1]);
+        assert!((histogram[0].0 - 3.25).abs() < 1e-5);
+        assert!((histogram[2].0 - 3.40).abs() < 1e-5);
+    }
+
+    #[test]
+    fn trend_window() {
+        let mut trend = ImbalanceTrend::new(3);
+        assert!(trend.mean_delta().is_none());
+        assert!(trend.delta_change().is_none());
+        for delta in [0.01, 0.02, 0.03, 0.05] {
+            trend.push(stats(4, delta));
+        }
+        // the oldest reading dropped out of the window
+        assert_eq!(trend.len(), 3);
+        assert!((trend.mean_delta().unwrap() - 0.1 / 3.0).abs() < 1e-6);
+        assert!((trend.delta_change().unwrap() - 0.03).abs() < 1e-6);
+    }
+
+    #[test]
+    fn weakest_cell() {
+        let mut trend = ImbalanceTrend::new(4);
+        for cell in [5, 2, 5, 5] {
+            trend.push(stats(cell, 0.02));
+        }
+        assert_eq!(trend.weakest_cell(), Some((5, 0.75)));
+    }
+}
@@ -1,5 +1,17 @@
+pub mod analyzer;
+pub mod bank;
+pub mod connection;
+pub mod device;
 mod error;
+pub mod error_history;
+pub mod imbalance;
+pub mod middleware;
+pub mod parameters;
 pub mod protocol;
+pub mod session;
+pub mod simulator;
+pub mod snapshot;
+pub mod soc_sync;
 
 pub use error::Error;
 
@@ -8,3 +20,6 @@ pub mod serialport;
 
 #[cfg(feature = "tokio-serial-async")]
 pub mod tokio_serial_async;
+
+#[cfg(feature = "wasm")]
+pub mod wasm;
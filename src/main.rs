@@ -1,9 +1,34 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use clap::{Parser, Subcommand};
 use clap_verbosity_flag::{InfoLevel, Verbosity};
-use flexi_logger::{Logger, LoggerHandle};
+use dalybms_lib::{
+    analyzer,
+    device::DeviceInfo,
+    error_history::{ErrorTracker, ErrorTransition},
+    imbalance::CellVoltageStats,
+    middleware::FrameCapture,
+    parameters::Parameters,
+    protocol::{
+        commands, CurrentCalibration, CurrentLimits, DecodeMode, ErrorCode, FirmwareQuirks,
+        QuirkProfile, TemperatureUnit,
+    },
+    serialport::DalyBMS,
+    simulator::{Simulator, SimulatorPort},
+    snapshot::{BmsSnapshot, Metric, Precision},
+    soc_sync::{EmptyDetector, FullChargeDetector},
+};
+use flexi_logger::{Age, Cleanup, Criterion, DeferredNow, FileSpec, Logger, LoggerHandle, Naming};
 use log::*;
-use std::{ops::Deref, panic, time::Duration};
+use std::{
+    fmt::Write as _,
+    io::Read as _,
+    ops::Deref,
+    panic,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
 
 fn default_device_name() -> String {
     if cfg!(target_os = "windows") {
@@ -32,7 +57,14 @@ pub enum CliCommands {
     /// Show cell balancing status
     Balancing,
     /// Show BMS errors
-    Errors,
+    Errors {
+        /// Keep polling and show when errors are raised or cleared
+        #[clap(long, action)]
+        history: bool,
+        /// Polling interval of '--history'
+        #[arg(value_parser = humantime::parse_duration, long, default_value = "10s")]
+        interval: Duration,
+    },
     /// Show all
     All,
     /// Set SOC in percent from '0.0' to '100.0'
@@ -46,15 +78,212 @@ pub enum CliCommands {
     SetChargeMosfet {
         #[clap(long, short, action)]
         enable: bool,
+        /// Enable the mosfet even if an over voltage or over temperature error is active
+        #[clap(long, action)]
+        force: bool,
+    },
+    /// Keep polling and set the SOC to 100% whenever a full charge is detected, optionally to a floor value when empty
+    SyncSoc {
+        /// Pack voltage at which the charger holds the absorb level
+        #[arg(long)]
+        absorb_voltage: f32,
+        /// Charge current in ampere below which the pack counts as full
+        #[arg(long)]
+        tail_current: f32,
+        /// How long both conditions must be met
+        #[arg(value_parser = humantime::parse_duration, long, default_value = "2m")]
+        hold: Duration,
+        /// Lowest cell voltage at which the pack counts as empty, disabled if not given
+        #[arg(long)]
+        empty_cell_voltage: Option<f32>,
+        /// SOC in percent set when the pack is empty
+        #[arg(long, default_value_t = 0.0)]
+        empty_soc: f32,
+        /// Cell voltage recovery required before the empty detection is armed again
+        #[arg(long, default_value_t = 0.1)]
+        empty_hysteresis: f32,
+        /// Minimum time between two SOC changes on empty
+        #[arg(value_parser = humantime::parse_duration, long, default_value = "1h")]
+        empty_min_interval: Duration,
+        /// Polling interval
+        #[arg(value_parser = humantime::parse_duration, long, default_value = "10s")]
+        interval: Duration,
+    },
+    /// Disable both mosfets for shipping or storage ('on') or enable them again ('off')
+    StorageMode {
+        #[arg(value_enum)]
+        state: Switch,
+        /// Do not ask for confirmation
+        #[clap(long, short, action)]
+        yes: bool,
+    },
+    /// Show the level 1 and level 2 charge and discharge over-current alarm thresholds
+    CurrentLimits,
+    /// Set the level 2 charge over-current alarm threshold in ampere, no dynamic derating
+    ///
+    /// Level 1 is lowered if it exceeds the new threshold. The Daly protocol has no runtime
+    /// charge current limit, the threshold is stored in the EEPROM and every write wears it, so
+    /// this can't be used for dynamic derating, e.g. reducing the charge current of cold cells.
+    SetChargeCurrentLimit { amps: f32 },
+    /// Set the level 2 discharge over-current alarm threshold in ampere, no dynamic derating
+    ///
+    /// Level 1 is lowered if it exceeds the new threshold. The Daly protocol has no runtime
+    /// discharge current limit, the threshold is stored in the EEPROM and every write wears it,
+    /// so this can't be used for dynamic derating.
+    SetDischargeCurrentLimit { amps: f32 },
+    /// Sample the current while the pack is idle and print the offset to use with '--current-offset'
+    CalibrateCurrent {
+        /// Number of samples
+        #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+        samples: u32,
+        /// Store the offset in this quirk profile file, other values of an existing file are
+        /// kept. Load it with '--quirks <FILE>'
+        #[arg(long)]
+        save: Option<PathBuf>,
+    },
+    /// Diagnose the connection and recommend '--delay' and '--timeout' settings
+    Doctor {
+        /// Number of requests sent per tested delay
+        #[arg(long, default_value_t = 20, value_parser = clap::value_parser!(u32).range(1..))]
+        samples: u32,
+    },
+    /// Show the lifetime charge and discharge counters if supported by the firmware
+    CumulativeCapacity,
+    /// Save the configuration parameters to a TOML or JSON file. Only the over-current alarm
+    /// thresholds are covered, voltage and temperature thresholds, balancing and battery code are not
+    Backup {
+        /// Output file, the format is chosen by the extension '.toml' or '.json'
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Compare the configuration parameters with another BMS or a backup, fails if they differ.
+    /// Only the over-current alarm thresholds are compared
+    CompareParams {
+        /// Device of the other BMS, opened with the same settings as '--device'
+        #[arg(long, required_unless_present = "backup", conflicts_with = "backup")]
+        device_b: Option<String>,
+        /// File written by 'backup'
+        #[arg(long)]
+        backup: Option<PathBuf>,
+    },
+    /// Write the configuration parameters of a template to several BMS, e.g. to commission a fleet.
+    /// Only the over-current alarm thresholds can be written, voltage and temperature thresholds,
+    /// balancing and battery code are not supported
+    Provision {
+        /// File written by 'backup', parameters missing in it are not changed, unsupported
+        /// parameters are rejected
+        template: PathBuf,
+        /// Devices to provision, '--device' is not used
+        #[arg(required = true)]
+        devices: Vec<String>,
+        /// Do not ask for confirmation
+        #[clap(long, short, action)]
+        yes: bool,
+    },
+    /// Write the configuration parameters of a backup, the changes are shown before writing.
+    /// Only the over-current alarm thresholds are covered, see 'backup'
+    Restore {
+        /// File written by 'backup'
+        file: PathBuf,
+        /// Do not ask for confirmation
+        #[clap(long, short, action)]
+        yes: bool,
+    },
+    /// Show the version of this tool and the identity of the BMS
+    Version,
+    /// Nagios/Icinga plugin: check SOC, cell voltage difference and BMS errors
+    Check {
+        /// Warning if the SOC in percent is below
+        #[arg(long)]
+        warn_soc: Option<f32>,
+        /// Critical if the SOC in percent is below
+        #[arg(long)]
+        crit_soc: Option<f32>,
+        /// Warning if the difference between highest and lowest cell voltage is above
+        #[arg(long)]
+        warn_cell_diff: Option<f32>,
+        /// Critical if the difference between highest and lowest cell voltage is above
+        #[arg(long)]
+        crit_cell_diff: Option<f32>,
+    },
+    /// Read all values once with the minimal number of commands and print them as JSON
+    Snapshot {
+        /// Comma separated metrics to read, all if not given
+        #[arg(long, value_delimiter = ',')]
+        metrics: Vec<Metric>,
+    },
+    /// Decode captured frames given as hex string or file (hex text or binary)
+    Decode { input: String },
+    /// Only listen on the bus and print the decoded traffic of the BMS and any other controller
+    Sniff {
+        #[arg(long, default_value_t = 9600)]
+        baud_rate: u32,
+    },
+    /// List the metrics accepted by '--metrics' and their dependencies
+    ListMetrics,
+    /// Print the JSON Schema of the snapshot output or of one metric in it
+    #[cfg(feature = "schemars")]
+    Schema { metric: Option<Metric> },
+    /// List all known command IDs, which firmwares answer them and whether this tool implements them
+    Commands,
+    /// Read all values once and write them in the Prometheus text format, e.g. for the node_exporter textfile collector
+    Collect {
+        /// File replaced atomically with the metrics, stdout if not given
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Check every supported command against the built-in simulator and print a conformance report
+    Selftest {
+        /// Run the read only commands against the device instead of the simulator
+        #[clap(long, action)]
+        live: bool,
+    },
+    /// Send a command not known to this tool and show the undecoded reply, e.g. 'raw 5A' or 'raw 1B 75 30'
+    Raw {
+        /// Command byte in hex
+        #[arg(value_parser = parse_hex_byte)]
+        command: u8,
+        /// Up to 8 data bytes in hex, missing bytes are zero
+        #[arg(value_parser = parse_hex_byte, num_args = 0..=8)]
+        data: Vec<u8>,
+    },
+    /// Restore the factory parameters of the BMS
+    FactoryReset {
+        /// Device path to confirm the reset without asking, must match '--device'
+        #[arg(long)]
+        confirm_device: Option<String>,
     },
-    /// Reset the BMS
-    Reset,
+}
+
+fn parse_quirk_profile(arg: &str) -> Result<QuirkProfile, String> {
+    if let Ok(profile) = arg.parse() {
+        return Ok(profile);
+    }
+    let text = std::fs::read_to_string(arg)
+        .map_err(|err| format!("neither a quirk profile name nor a readable file: {}", err))?;
+    serde_json::from_str(&text).map_err(|err| format!("invalid quirk profile: {}", err))
+}
+
+fn parse_hex_byte(arg: &str) -> Result<u8, std::num::ParseIntError> {
+    u8::from_str_radix(arg.trim_start_matches("0x"), 16)
 }
 
 const fn about_text() -> &'static str {
     "daly bms command line tool"
 }
 
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Switch {
+    On,
+    Off,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
 #[derive(Parser, Debug)]
 #[command(version, about=about_text(), long_about = None)]
 struct CliArgs {
@@ -68,21 +297,146 @@ struct CliArgs {
     #[command(subcommand)]
     command: CliCommands,
 
+    /// Print errors as JSON object to stderr
+    #[arg(long, action)]
+    errors_json: bool,
+
+    /// Log to this file instead of stderr, the file is rotated when it grows too large
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+
+    /// Size in MiB at which the log file is rotated
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    log_rotate_size: u64,
+
+    /// Rotate the log file daily in addition to the size limit
+    #[arg(long, action)]
+    log_rotate_daily: bool,
+
+    /// Number of rotated log files to keep
+    #[arg(long, default_value_t = 5)]
+    log_keep: usize,
+
+    /// Format of the log lines
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Per-module log levels added to the verbosity, e.g. 'dalybms_lib::serialport=trace'
+    #[arg(long)]
+    log_modules: Option<String>,
+
+    /// Only log the frames of write commands instead of sending them
+    #[arg(long, action)]
+    dry_run: bool,
+
+    /// Read the SOC or mosfet state back after setting it and retry if it did not take effect
+    #[arg(long, action)]
+    verify: bool,
+
+    /// Tolerate replies which deviate from the protocol, e.g. leading garbage or a wrong frame header
+    #[arg(long, action)]
+    lenient: bool,
+
+    /// JSON list of firmware versions and the commands they don't answer correctly, these
+    /// commands are skipped. There are no built-in entries
+    #[arg(long)]
+    firmware_quirks: Option<PathBuf>,
+
+    /// Append all sent and received frames as hex lines to this file, readable by `decode`
+    #[arg(long)]
+    capture: Option<PathBuf>,
+
+    /// Send the requests of a snapshot back to back, not every firmware supports this
+    #[arg(long, action)]
+    pipelining: bool,
+
     /// Serial Input/Output operations timeout
     #[arg(value_parser = humantime::parse_duration, long, default_value = "500ms")]
     timeout: Duration,
 
+    /// Total time limit of commands with several round trips like cell voltages or verified writes
+    #[arg(value_parser = humantime::parse_duration, long)]
+    operation_timeout: Option<Duration>,
+
+    /// Offset in ampere added to the measured current, replaces the offset of the quirk profile
+    #[arg(long, allow_negative_numbers = true)]
+    current_offset: Option<f32>,
+
+    /// Scale factor applied to the measured current after the offset, replaces the scale of
+    /// the quirk profile
+    #[arg(long)]
+    current_scale: Option<f32>,
+
+    /// Deviations of a non genuine board: 'standard' or a JSON file with the fields of
+    /// `QuirkProfile`, e.g. {"frame_numbering": "ZeroBased", "unsupported_commands": [82]}
+    #[arg(long, default_value = "standard", value_parser = parse_quirk_profile)]
+    quirks: QuirkProfile,
+
+    /// Offset in °C added to all temperatures, replaces the offset of the quirk profile
+    #[arg(long, allow_negative_numbers = true)]
+    temperature_offset: Option<i8>,
+
+    /// Decimal places of the output, e.g. 'voltage=2,current=0,soc=0'
+    #[arg(long, default_value_t = Precision::default())]
+    precision: Precision,
+
+    /// Unit of displayed temperatures and of the Prometheus metrics, JSON output always uses °C
+    #[arg(long, default_value = "celsius")]
+    temperature_unit: TemperatureUnit,
+
     // Some USB - RS485 dongles requires at least 10ms to switch between TX and RX, so use a save delay between frames
     /// Delay between multiple commands
     #[arg(value_parser = humantime::parse_duration, long, default_value = "50ms")]
     delay: Duration,
 }
 
-fn logging_init(loglevel: LevelFilter) -> LoggerHandle {
-    let log_handle = Logger::try_with_env_or_str(loglevel.as_str())
-        .expect("Cannot init logging")
-        .start()
-        .expect("Cannot start logging");
+fn json_format(
+    w: &mut dyn std::io::Write,
+    now: &mut DeferredNow,
+    record: &Record,
+) -> std::io::Result<()> {
+    write!(
+        w,
+        "{}",
+        serde_json::json!({
+            "time": now.format_rfc3339(),
+            "level": record.level().as_str(),
+            "module": record.module_path().unwrap_or("<unknown>"),
+            "message": record.args().to_string(),
+        })
+    )
+}
+
+fn logging_init(args: &CliArgs) -> Result<LoggerHandle> {
+    let loglevel = args.verbose.log_level_filter();
+    let spec = match &args.log_modules {
+        Some(modules) => format!("{}, {}", loglevel.as_str(), modules),
+        None => loglevel.as_str().to_string(),
+    };
+    let mut logger = Logger::try_with_env_or_str(spec).with_context(|| "Cannot init logging")?;
+    if args.log_format == LogFormat::Json {
+        logger = logger.format(json_format);
+    }
+    if let Some(log_file) = &args.log_file {
+        let size = args.log_rotate_size * 1024 * 1024;
+        let criterion = if args.log_rotate_daily {
+            Criterion::AgeOrSize(Age::Day, size)
+        } else {
+            Criterion::Size(size)
+        };
+        logger = logger
+            .log_to_file(
+                FileSpec::try_from(log_file)
+                    .with_context(|| format!("Invalid log file '{}'", log_file.display()))?,
+            )
+            .append()
+            .rotate(
+                criterion,
+                Naming::NumbersDirect,
+                Cleanup::KeepLogFiles(args.log_keep),
+            );
+    }
+    let log_handle = logger.start().with_context(|| "Cannot start logging")?;
 
     panic::set_hook(Box::new(|panic_info| {
         let (filename, line, column) = panic_info
@@ -110,7 +464,7 @@ fn logging_init(loglevel: LevelFilter) -> LoggerHandle {
             cause
         );
     }));
-    log_handle
+    Ok(log_handle)
 }
 
 macro_rules! print_status {
@@ -122,12 +476,11 @@ macro_rules! print_status {
     };
 }
 macro_rules! print_soc {
-    ($bms:expr) => {
-        println!(
-            "SOC: {:?}",
-            $bms.get_soc().with_context(|| "Cannot get SOC")?
-        )
-    };
+    ($bms:expr, $precision:expr) => {{
+        let mut value = $bms.get_soc().with_context(|| "Cannot get SOC")?;
+        $precision.round_soc(&mut value);
+        println!("SOC: {:?}", value)
+    }};
 }
 macro_rules! print_mosfet_status {
     ($bms:expr) => {
@@ -139,38 +492,47 @@ macro_rules! print_mosfet_status {
     };
 }
 macro_rules! print_voltage_range {
-    ($bms:expr) => {
-        println!(
-            "Voltage range: {:?}",
-            $bms.get_cell_voltage_range()
-                .with_context(|| "Cannot get voltage range")?
-        )
-    };
+    ($bms:expr, $precision:expr) => {{
+        let mut value = $bms
+            .get_cell_voltage_range()
+            .with_context(|| "Cannot get voltage range")?;
+        $precision.round_voltage_range(&mut value);
+        println!("Voltage range: {:?}", value)
+    }};
 }
 macro_rules! print_temperature_range {
-    ($bms:expr) => {
+    ($bms:expr, $unit:expr) => {{
+        let range = $bms
+            .get_temperature_range()
+            .with_context(|| "Cannot get temperature range")?;
         println!(
-            "Temperature range: {:?}",
-            $bms.get_temperature_range()
-                .with_context(|| "Cannot get temperature range")?
+            "Temperature range: highest {} (sensor {}), lowest {} (sensor {})",
+            $unit.format(range.highest_temperature.into()),
+            range.highest_sensor,
+            $unit.format(range.lowest_temperature.into()),
+            range.lowest_sensor
         )
-    };
+    }};
 }
 macro_rules! print_cell_voltages {
-    ($bms:expr) => {
-        println!(
-            "Cell Voltages: {:?}",
-            $bms.get_cell_voltages()
-                .with_context(|| "Cannot get cell voltages")?
-        )
-    };
+    ($bms:expr, $precision:expr) => {{
+        let mut value = $bms
+            .get_cell_voltages()
+            .with_context(|| "Cannot get cell voltages")?;
+        $precision.round_voltages(&mut value);
+        println!("Cell Voltages: {:?}", value)
+    }};
 }
 macro_rules! print_cell_temperatures {
-    ($bms:expr) => {
+    ($bms:expr, $unit:expr) => {
         println!(
-            "Cell temperatures: {:?}",
+            "Cell temperatures: {:?} {}",
             $bms.get_cell_temperatures()
                 .with_context(|| "Cannot get cell temperatures")?
+                .into_iter()
+                .map(|temperature| $unit.convert(temperature as f32))
+                .collect::<Vec<_>>(),
+            $unit.symbol()
         )
     };
 }
@@ -192,56 +554,1521 @@ macro_rules! print_errors {
     };
 }
 
-fn main() -> Result<()> {
-    let args = CliArgs::parse();
+macro_rules! print_current_limits {
+    ($bms:expr) => {
+        println!(
+            "Current limits: {:?}",
+            $bms.get_current_limits()
+                .with_context(|| "Cannot get current limits")?
+        )
+    };
+}
+
+const DOCTOR_BAUD_RATES: [u32; 5] = [9600, 19200, 38400, 57600, 115200];
+const DOCTOR_DELAYS: [Duration; 5] = [
+    Duration::from_millis(4),
+    Duration::from_millis(10),
+    Duration::from_millis(20),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+];
+
+fn doctor(args: &CliArgs, samples: u32) -> Result<()> {
+    println!("Checking device '{}'", args.device);
+    let mut found = None;
+    for baud_rate in DOCTOR_BAUD_RATES {
+        let mut bms = DalyBMS::with_baud_rate(&args.device, baud_rate)?;
+        bms.set_timeout(args.timeout)?;
+        bms.set_delay(args.delay);
+        match bms.ping() {
+            Ok(latency) => {
+                println!(
+                    "Baud rate {}: response received in {:?}",
+                    baud_rate, latency
+                );
+                found = Some((baud_rate, bms));
+                break;
+            }
+            Err(err) => println!("Baud rate {}: no valid response ({:#})", baud_rate, err),
+        }
+    }
+    let Some((baud_rate, mut bms)) = found else {
+        bail!(
+            "No response at any baud rate, check the wiring, the device and that the BMS is awake"
+        );
+    };
+    if baud_rate != 9600 {
+        println!("Warning: the BMS does not use the standard baud rate 9600");
+    }
+    match bms.identify() {
+        Ok(info) => {
+            let or_unsupported = |value: &Option<String>| {
+                value
+                    .clone()
+                    .unwrap_or_else(|| String::from("not supported"))
+            };
+            println!(
+                "Detected BMS: {} cells, {} temperature sensors",
+                info.cells, info.temperature_sensors
+            );
+            println!(
+                "Firmware version: {}, hardware version: {}, battery code: {}",
+                or_unsupported(&info.software_version),
+                or_unsupported(&info.hardware_version),
+                or_unsupported(&info.battery_code)
+            );
+        }
+        Err(err) => println!("Cannot identify the BMS: {:#}", err),
+    }
+
+    let mut recommended = None;
+    for delay in DOCTOR_DELAYS {
+        bms.set_delay(delay);
+        let mut round_trips = Vec::new();
+        let mut checksum_errors = 0;
+        for _ in 0..samples {
+            match bms.ping() {
+                Ok(round_trip) => round_trips.push(round_trip),
+                Err(err) => {
+                    if matches!(
+                        err.downcast_ref::<dalybms_lib::Error>(),
+                        Some(dalybms_lib::Error::CheckSumError)
+                    ) {
+                        checksum_errors += 1;
+                    }
+                }
+            }
+        }
+        let failures = samples as usize - round_trips.len();
+        let max_round_trip = round_trips.iter().max().copied().unwrap_or_default();
+        let avg_round_trip = round_trips
+            .iter()
+            .sum::<Duration>()
+            .checked_div(round_trips.len() as u32)
+            .unwrap_or_default();
+        println!(
+            "Delay {:?}: {}/{} failed ({} checksum errors), round trip avg={:?} max={:?}",
+            delay, failures, samples, checksum_errors, avg_round_trip, max_round_trip
+        );
+        if failures == 0 && recommended.is_none() {
+            recommended = Some((delay, max_round_trip));
+        }
+    }
+
+    match recommended {
+        Some((delay, max_round_trip)) => {
+            // leave enough headroom for a slow reply, rounded to 10ms
+            let timeout_ms = ((max_round_trip.as_millis() as u64 * 3).div_ceil(10) * 10).max(100);
+            println!(
+                "Recommended settings: --delay {} --timeout {}",
+                humantime::format_duration(delay),
+                humantime::format_duration(Duration::from_millis(timeout_ms))
+            );
+        }
+        None => println!(
+            "No delay worked reliably, try a longer '--timeout' or check the RS485 adapter"
+        ),
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct ConformanceReport {
+    passed: u32,
+    failed: u32,
+    max_round_trip: Duration,
+}
+
+impl ConformanceReport {
+    fn check(&mut self, name: &str, check: impl FnOnce() -> Result<()>) {
+        let start = Instant::now();
+        let result = check();
+        let elapsed = start.elapsed();
+        self.max_round_trip = self.max_round_trip.max(elapsed);
+        match result {
+            Ok(()) => {
+                self.passed += 1;
+                println!("PASS {} ({:?})", name, elapsed);
+            }
+            Err(err) => {
+                self.failed += 1;
+                println!("FAIL {}: {:#}", name, err);
+            }
+        }
+    }
+}
+
+fn approx_eq(a: f32, b: f32) -> bool {
+    (a - b).abs() < 0.01
+}
+
+fn selftest(args: &CliArgs, live: bool) -> Result<()> {
+    let simulator = Arc::new(Mutex::new(Simulator::default()));
+    let mut bms = if live {
+        println!("Conformance report for device '{}'", args.device);
+        DalyBMS::new(&args.device)?
+    } else {
+        println!("Conformance report for the built-in simulator");
+        let mut state = simulator.lock().unwrap();
+        // exercise some bits which are clear by default
+        state.balancing[1] = true;
+        state.balancing[9] = true;
+        state.errors = vec![
+            ErrorCode::CellVoltHighLevel1,
+            ErrorCode::DiffTempLevel2,
+            ErrorCode::LowVoltForbiddenChargeFault,
+        ];
+        drop(state);
+        DalyBMS::from_port(Box::new(SimulatorPort::new(Arc::clone(&simulator))))
+    };
+    bms.set_timeout(args.timeout)?;
+    bms.set_delay(args.delay);
+    bms.set_verify_writes(!live);
+    match bms.identify() {
+        Ok(info) => println!(
+            "Firmware version: {}",
+            info.software_version
+                .as_deref()
+                .unwrap_or("not supported by the firmware")
+        ),
+        Err(err) => println!("Firmware version: unknown ({:#})", err),
+    }
+
+    let expected = (!live).then(|| simulator.lock().unwrap().clone());
+    let mut report = ConformanceReport::default();
+
+    report.check("status", || {
+        let status = bms.get_status()?;
+        if let Some(sim) = &expected {
+            ensure!(
+                usize::from(status.cells) == sim.cell_voltages.len(),
+                "cells {} != {}",
+                status.cells,
+                sim.cell_voltages.len()
+            );
+            ensure!(
+                usize::from(status.temperature_sensors) == sim.cell_temperatures.len(),
+                "temperature sensors {} != {}",
+                status.temperature_sensors,
+                sim.cell_temperatures.len()
+            );
+            ensure!(
+                status.charger_running == sim.charger_running,
+                "charger running"
+            );
+            ensure!(status.load_running == sim.load_running, "load running");
+            ensure!(
+                status.cycles == sim.cycles,
+                "cycles {} != {}",
+                status.cycles,
+                sim.cycles
+            );
+        }
+        Ok(())
+    });
+    report.check("soc", || {
+        let soc = bms.get_soc()?;
+        if let Some(sim) = &expected {
+            ensure!(
+                approx_eq(soc.total_voltage, sim.total_voltage),
+                "total voltage {} != {}",
+                soc.total_voltage,
+                sim.total_voltage
+            );
+            ensure!(
+                approx_eq(soc.current, sim.current),
+                "current {} != {}",
+                soc.current,
+                sim.current
+            );
+            ensure!(
+                approx_eq(soc.soc_percent, sim.soc_percent),
+                "SOC {} != {}",
+                soc.soc_percent,
+                sim.soc_percent
+            );
+        }
+        Ok(())
+    });
+    report.check("mosfet", || {
+        let mosfet = bms.get_mosfet_status()?;
+        if let Some(sim) = &expected {
+            ensure!(
+                mosfet.mode == sim.mode,
+                "mode {:?} != {:?}",
+                mosfet.mode,
+                sim.mode
+            );
+            ensure!(
+                mosfet.charging_mosfet == sim.charging_mosfet,
+                "charging mosfet"
+            );
+            ensure!(
+                mosfet.discharging_mosfet == sim.discharging_mosfet,
+                "discharging mosfet"
+            );
+            ensure!(mosfet.bms_cycles == sim.bms_cycles, "BMS cycles");
+            ensure!(
+                approx_eq(mosfet.capacity_ah, sim.capacity_ah),
+                "capacity {} != {}",
+                mosfet.capacity_ah,
+                sim.capacity_ah
+            );
+        }
+        Ok(())
+    });
+    report.check("voltage range", || {
+        let range = bms.get_cell_voltage_range()?;
+        if let Some(sim) = &expected {
+            let highest = sim.cell_voltages.iter().copied().fold(f32::MIN, f32::max);
+            let lowest = sim.cell_voltages.iter().copied().fold(f32::MAX, f32::min);
+            ensure!(
+                approx_eq(range.highest_voltage, highest),
+                "highest voltage {} != {}",
+                range.highest_voltage,
+                highest
+            );
+            ensure!(
+                approx_eq(range.lowest_voltage, lowest),
+                "lowest voltage {} != {}",
+                range.lowest_voltage,
+                lowest
+            );
+        }
+        Ok(())
+    });
+    report.check("temperature range", || {
+        let range = bms.get_temperature_range()?;
+        if let Some(sim) = &expected {
+            let highest = sim
+                .cell_temperatures
+                .iter()
+                .max()
+                .copied()
+                .unwrap_or_default();
+            let lowest = sim
+                .cell_temperatures
+                .iter()
+                .min()
+                .copied()
+                .unwrap_or_default();
+            ensure!(
+                i32::from(range.highest_temperature) == highest,
+                "highest temperature {} != {}",
+                range.highest_temperature,
+                highest
+            );
+            ensure!(
+                i32::from(range.lowest_temperature) == lowest,
+                "lowest temperature {} != {}",
+                range.lowest_temperature,
+                lowest
+            );
+        }
+        Ok(())
+    });
+    report.check("cell voltages", || {
+        let voltages = bms.get_cell_voltages()?;
+        if let Some(sim) = &expected {
+            ensure!(
+                voltages.len() == sim.cell_voltages.len()
+                    && voltages
+                        .iter()
+                        .zip(&sim.cell_voltages)
+                        .all(|(a, b)| approx_eq(*a, *b)),
+                "{:?} != {:?}",
+                voltages,
+                sim.cell_voltages
+            );
+        }
+        Ok(())
+    });
+    report.check("cell temperatures", || {
+        let temperatures = bms.get_cell_temperatures()?;
+        if let Some(sim) = &expected {
+            ensure!(
+                temperatures == sim.cell_temperatures,
+                "{:?} != {:?}",
+                temperatures,
+                sim.cell_temperatures
+            );
+        }
+        Ok(())
+    });
+    report.check("balancing", || {
+        let balancing = bms.get_balancing_status()?;
+        if let Some(sim) = &expected {
+            ensure!(
+                balancing.starts_with(&sim.balancing),
+                "{:?} != {:?}",
+                balancing,
+                sim.balancing
+            );
+        }
+        Ok(())
+    });
+    report.check("errors", || {
+        let errors = bms.get_errors()?;
+        if let Some(sim) = &expected {
+            ensure!(errors == sim.errors, "{:?} != {:?}", errors, sim.errors);
+        }
+        Ok(())
+    });
+    report.check("cumulative capacity", || {
+        let capacity = bms.get_cumulative_capacity()?;
+        if let Some(sim) = &expected {
+            match (&capacity, &sim.cumulative_capacity) {
+                (Some(capacity), Some(sim)) => ensure!(
+                    approx_eq(capacity.charge_ah, sim.charge_ah)
+                        && approx_eq(capacity.discharge_ah, sim.discharge_ah),
+                    "{:?} != {:?}",
+                    capacity,
+                    sim
+                ),
+                (None, None) => {}
+                _ => bail!("{:?} != {:?}", capacity, sim.cumulative_capacity),
+            }
+        }
+        Ok(())
+    });
+    report.check("battery code", || {
+        let code = bms.get_battery_code()?;
+        if let Some(sim) = &expected {
+            ensure!(
+                code == sim.battery_code,
+                "{:?} != {:?}",
+                code,
+                sim.battery_code
+            );
+        }
+        Ok(())
+    });
+    report.check("identify", || {
+        let info = bms.identify()?;
+        if let Some(sim) = &expected {
+            ensure!(
+                info.software_version.as_ref() == Some(&sim.software_version)
+                    && info.hardware_version.as_ref() == Some(&sim.hardware_version),
+                "{:?} != {:?} / {:?}",
+                info,
+                sim.software_version,
+                sim.hardware_version
+            );
+        }
+        Ok(())
+    });
+    report.check("current limits", || {
+        let limits = bms.get_current_limits()?;
+        if let Some(sim) = &expected {
+            let sim = &sim.current_limits;
+            ensure!(
+                approx_eq(limits.charge_level1, sim.charge_level1)
+                    && approx_eq(limits.charge_level2, sim.charge_level2)
+                    && approx_eq(limits.discharge_level1, sim.discharge_level1)
+                    && approx_eq(limits.discharge_level2, sim.discharge_level2),
+                "{:?} != {:?}",
+                limits,
+                sim
+            );
+        }
+        Ok(())
+    });
+
+    if !live {
+        report.check("set soc", || {
+            bms.set_soc(42.5)?;
+            let soc_percent = simulator.lock().unwrap().soc_percent;
+            ensure!(approx_eq(soc_percent, 42.5), "SOC {} != 42.5", soc_percent);
+            Ok(())
+        });
+        report.check("set charge mosfet", || {
+            bms.set_charge_mosfet(false)?;
+            ensure!(!simulator.lock().unwrap().charging_mosfet, "still enabled");
+            bms.set_charge_mosfet(true)?;
+            ensure!(simulator.lock().unwrap().charging_mosfet, "still disabled");
+            Ok(())
+        });
+        report.check("set discharge mosfet", || {
+            bms.set_discharge_mosfet(false)?;
+            ensure!(
+                !simulator.lock().unwrap().discharging_mosfet,
+                "still enabled"
+            );
+            bms.set_discharge_mosfet(true)?;
+            ensure!(
+                simulator.lock().unwrap().discharging_mosfet,
+                "still disabled"
+            );
+            Ok(())
+        });
+        report.check("set current limits", || {
+            let limits = CurrentLimits {
+                charge_level1: 40.0,
+                charge_level2: 50.5,
+                discharge_level1: 80.0,
+                discharge_level2: 100.0,
+            };
+            bms.set_current_limits(&limits)?;
+            let sim = simulator.lock().unwrap().current_limits.clone();
+            ensure!(
+                approx_eq(limits.charge_level1, sim.charge_level1)
+                    && approx_eq(limits.charge_level2, sim.charge_level2)
+                    && approx_eq(limits.discharge_level1, sim.discharge_level1)
+                    && approx_eq(limits.discharge_level2, sim.discharge_level2),
+                "{:?} != {:?}",
+                sim,
+                limits
+            );
+            Ok(())
+        });
+        report.check("factory reset", || {
+            let requests = simulator.lock().unwrap().requests;
+            bms.factory_reset()?;
+            ensure!(
+                simulator.lock().unwrap().requests == requests + 1,
+                "request not answered"
+            );
+            Ok(())
+        });
+        report.check("delay between requests", || {
+            let min_request_gap = simulator.lock().unwrap().min_request_gap;
+            ensure!(
+                min_request_gap.is_some_and(|gap| gap >= args.delay),
+                "shortest gap {:?} < {:?}",
+                min_request_gap,
+                args.delay
+            );
+            Ok(())
+        });
+    }
+
+    println!(
+        "Summary: {} passed, {} failed, max round trip {:?} (timeout {:?})",
+        report.passed, report.failed, report.max_round_trip, args.timeout
+    );
+    if report.failed > 0 {
+        bail!("{} conformance checks failed", report.failed);
+    }
+    Ok(())
+}
+
+fn prometheus_metric(
+    text: &mut String,
+    name: &str,
+    help: &str,
+    samples: impl IntoIterator<Item = (String, impl std::fmt::Display)>,
+) {
+    let _ = writeln!(text, "# HELP dalybms_{} {}", name, help);
+    let _ = writeln!(text, "# TYPE dalybms_{} gauge", name);
+    for (labels, value) in samples {
+        let _ = writeln!(text, "dalybms_{}{} {}", name, labels, value);
+    }
+}
+
+// label values must not break out of the quotes
+fn prometheus_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn prometheus_text(
+    snapshot: &BmsSnapshot,
+    device_info: Option<&DeviceInfo>,
+    temperature_unit: TemperatureUnit,
+) -> String {
+    let mut text = String::new();
+    if let Some(info) = device_info {
+        let labels = [
+            ("battery_code", &info.battery_code),
+            ("software_version", &info.software_version),
+            ("hardware_version", &info.hardware_version),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| {
+            value
+                .as_ref()
+                .map(|value| format!("{}=\"{}\"", name, prometheus_label_value(value)))
+        })
+        .collect::<Vec<_>>();
+        prometheus_metric(
+            &mut text,
+            "info",
+            "Identity of the BMS",
+            [(format!("{{{}}}", labels.join(",")), 1)],
+        );
+    }
+    if let Some(status) = &snapshot.status {
+        prometheus_metric(
+            &mut text,
+            "cells",
+            "Number of cells",
+            [(String::new(), status.cells)],
+        );
+        prometheus_metric(
+            &mut text,
+            "cycles",
+            "Charge cycles",
+            [(String::new(), status.cycles)],
+        );
+    }
+    if let Some(soc) = &snapshot.soc {
+        prometheus_metric(
+            &mut text,
+            "voltage_volts",
+            "Total voltage",
+            [(String::new(), soc.total_voltage)],
+        );
+        prometheus_metric(
+            &mut text,
+            "current_amperes",
+            "Current, negative while charging",
+            [(String::new(), soc.current)],
+        );
+        prometheus_metric(
+            &mut text,
+            "soc_percent",
+            "State of charge",
+            [(String::new(), soc.soc_percent)],
+        );
+    }
+    if let Some(mosfet) = &snapshot.mosfet_status {
+        prometheus_metric(
+            &mut text,
+            "charging_mosfet",
+            "Charge mosfet enabled",
+            [(String::new(), u8::from(mosfet.charging_mosfet))],
+        );
+        prometheus_metric(
+            &mut text,
+            "discharging_mosfet",
+            "Discharge mosfet enabled",
+            [(String::new(), u8::from(mosfet.discharging_mosfet))],
+        );
+        prometheus_metric(
+            &mut text,
+            "capacity_ah",
+            "Remaining capacity",
+            [(String::new(), mosfet.capacity_ah)],
+        );
+    }
+    if let Some(voltages) = &snapshot.cell_voltages {
+        prometheus_metric(
+            &mut text,
+            "cell_voltage_volts",
+            "Cell voltage",
+            voltages
+                .iter()
+                .enumerate()
+                .map(|(n, voltage)| (format!("{{cell=\"{}\"}}", n + 1), *voltage)),
+        );
+    }
+    if let Some(temperatures) = &snapshot.cell_temperatures {
+        prometheus_metric(
+            &mut text,
+            &format!("temperature_{}", temperature_unit.name()),
+            "Temperature sensor value",
+            temperatures.iter().enumerate().map(|(n, temperature)| {
+                (
+                    format!("{{sensor=\"{}\"}}", n + 1),
+                    temperature_unit.convert(*temperature as f32),
+                )
+            }),
+        );
+    }
+    if let Some(balancing) = &snapshot.balancing_status {
+        prometheus_metric(
+            &mut text,
+            "cell_balancing",
+            "Cell is balancing",
+            balancing
+                .iter()
+                .enumerate()
+                .map(|(n, active)| (format!("{{cell=\"{}\"}}", n + 1), u8::from(*active))),
+        );
+    }
+    if let Some(errors) = &snapshot.errors {
+        prometheus_metric(
+            &mut text,
+            "errors",
+            "Number of active BMS errors",
+            [(String::new(), errors.len())],
+        );
+    }
+    if let Some(duration) = snapshot.duration {
+        prometheus_metric(
+            &mut text,
+            "poll_duration_seconds",
+            "Time to read all values from the BMS",
+            [(String::new(), duration.as_secs_f64())],
+        );
+    }
+    text
+}
+
+fn collect(
+    bms: &mut DalyBMS,
+    output: Option<&PathBuf>,
+    temperature_unit: TemperatureUnit,
+    precision: Precision,
+) -> Result<()> {
+    let mut snapshot = bms
+        .get_snapshot(&Metric::ALL)
+        .with_context(|| "Cannot get snapshot")?;
+    precision.round_snapshot(&mut snapshot);
+    let info = bms.identify().with_context(|| "Cannot identify BMS")?;
+    let text = prometheus_text(&snapshot, Some(&info), temperature_unit);
+    match output {
+        Some(output) => {
+            // write to a temporary file first so the collector never reads a partial file
+            let mut temporary = output.clone().into_os_string();
+            temporary.push(".tmp");
+            std::fs::write(&temporary, text)
+                .with_context(|| format!("Cannot write '{}'", temporary.to_string_lossy()))?;
+            std::fs::rename(&temporary, output)
+                .with_context(|| format!("Cannot replace '{}'", output.display()))?;
+        }
+        None => print!("{}", text),
+    }
+    Ok(())
+}
+
+fn sync_soc(
+    bms: &mut DalyBMS,
+    mut full: FullChargeDetector,
+    mut empty: Option<(EmptyDetector, f32)>,
+    interval: Duration,
+) -> Result<()> {
+    loop {
+        let soc = bms.get_soc().with_context(|| "Cannot get SOC")?;
+        if let Some((detector, empty_soc)) = &mut empty {
+            let range = bms
+                .get_cell_voltage_range()
+                .with_context(|| "Cannot get voltage range")?;
+            if detector.update(range.lowest_voltage) && soc.soc_percent > *empty_soc {
+                info!(
+                    "Empty pack detected (cell {} at {:.3}V), set SOC from {:.1}% to {:.1}%",
+                    range.lowest_cell, range.lowest_voltage, soc.soc_percent, empty_soc
+                );
+                bms.set_soc(*empty_soc).with_context(|| "Cannot set SOC")?;
+            }
+        }
+        if full.update(&soc) {
+            if soc.soc_percent < 100.0 {
+                info!(
+                    "Full charge detected, set SOC from {:.1}% to 100%",
+                    soc.soc_percent
+                );
+                bms.set_soc(100.0).with_context(|| "Cannot set SOC")?;
+            } else {
+                info!("Full charge detected, SOC already at 100%");
+            }
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+fn decode(input: &str) -> Result<()> {
+    let bytes = if std::path::Path::new(input).is_file() {
+        let content = std::fs::read(input).with_context(|| format!("Cannot read '{}'", input))?;
+        match std::str::from_utf8(&content)
+            .ok()
+            .and_then(analyzer::parse_hex)
+        {
+            Some(bytes) => bytes,
+            None => content,
+        }
+    } else {
+        analyzer::parse_hex(input)
+            .with_context(|| format!("'{}' is neither a file nor a hex string", input))?
+    };
+    let frames = analyzer::analyze(&bytes);
+    for frame in &frames {
+        println!("{}", frame);
+    }
+    let framed = frames.len() * 13;
+    if framed < bytes.len() {
+        println!("{} bytes outside of frames", bytes.len() - framed);
+    }
+    Ok(())
+}
+
+fn sniff(device: &str, baud_rate: u32) -> Result<()> {
+    let mut serial = serialport::new(device, baud_rate)
+        .data_bits(serialport::DataBits::Eight)
+        .parity(serialport::Parity::None)
+        .stop_bits(serialport::StopBits::One)
+        .flow_control(serialport::FlowControl::None)
+        .timeout(Duration::from_millis(500))
+        .open()
+        .with_context(|| format!("Cannot open serial port '{}'", device))?;
+    info!("Listening on '{}', nothing is sent", device);
+    let mut stream = analyzer::StreamAnalyzer::default();
+    let mut buffer = [0; 256];
+    loop {
+        let n = match serial.read(&mut buffer) {
+            Ok(n) => n,
+            Err(err) if err.kind() == std::io::ErrorKind::TimedOut => continue,
+            Err(err) => return Err(err).with_context(|| "Cannot read from serial port"),
+        };
+        let skipped = stream.skipped();
+        for frame in stream.push(&buffer[..n]) {
+            println!(
+                "{} {}",
+                humantime::format_rfc3339_millis(SystemTime::now()),
+                frame
+            );
+        }
+        if stream.skipped() > skipped {
+            debug!("{} bytes outside of frames", stream.skipped() - skipped);
+        }
+    }
+}
 
-    let _log_handle = logging_init(args.verbose.log_level_filter());
+fn confirm(question: &str) -> Result<bool> {
+    print!("{} [y/N] ", question);
+    std::io::Write::flush(&mut std::io::stdout())?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
 
-    let mut bms = dalybms_lib::serialport::DalyBMS::new(&args.device)?;
+fn open_device(args: &CliArgs, device: &str) -> Result<DalyBMS> {
+    let mut bms = DalyBMS::new(device)?;
     bms.set_timeout(args.timeout)?;
+    bms.set_operation_timeout(args.operation_timeout);
     bms.set_delay(args.delay);
+    bms.set_read_only(args.dry_run);
+    bms.set_verify_writes(args.verify);
+    bms.set_pipelining(args.pipelining);
+    if args.lenient {
+        bms.set_decode_mode(DecodeMode::Lenient);
+    }
+    bms.set_quirk_profile(quirk_profile(args));
+    if let Some(path) = &args.firmware_quirks {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Cannot read '{}'", path.display()))?;
+        let table: Vec<FirmwareQuirks> = serde_json::from_str(&text)
+            .with_context(|| format!("Invalid firmware quirks '{}'", path.display()))?;
+        bms.set_firmware_quirks(table);
+    }
+    if let Some(path) = &args.capture {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Cannot open capture file {:?}", path))?;
+        bms.add_middleware(FrameCapture::new(file));
+    }
+    Ok(bms)
+}
+
+fn backup(bms: &mut DalyBMS, out: &PathBuf) -> Result<()> {
+    let parameters = bms
+        .get_parameters()
+        .with_context(|| "Cannot get parameters")?;
+    for (name, value) in parameters.values() {
+        if value.is_none() {
+            println!("{}: not supported by the firmware", name);
+        }
+    }
+    let text = if is_toml(out)? {
+        toml::to_string_pretty(&parameters)?
+    } else {
+        serde_json::to_string_pretty(&parameters)?
+    };
+    std::fs::write(out, text).with_context(|| format!("Cannot write '{}'", out.display()))?;
+    println!("Parameters saved to '{}'", out.display());
+    Ok(())
+}
+
+// backups are TOML or JSON files, other extensions are rejected to not write JSON into a '.toml'
+fn is_toml(file: &Path) -> Result<bool> {
+    match file.extension().and_then(|extension| extension.to_str()) {
+        Some("toml") => Ok(true),
+        Some("json") => Ok(false),
+        _ => bail!(
+            "Unknown backup format of '{}', use the extension '.toml' or '.json'",
+            file.display()
+        ),
+    }
+}
+
+fn read_backup(file: &PathBuf) -> Result<Parameters> {
+    let toml = is_toml(file)?;
+    let text = std::fs::read_to_string(file)
+        .with_context(|| format!("Cannot read '{}'", file.display()))?;
+    if toml {
+        toml::from_str(&text).with_context(|| format!("Invalid backup '{}'", file.display()))
+    } else {
+        serde_json::from_str(&text).with_context(|| format!("Invalid backup '{}'", file.display()))
+    }
+}
+
+fn compare_params(
+    bms: &mut DalyBMS,
+    args: &CliArgs,
+    device_b: Option<&str>,
+    backup: Option<&PathBuf>,
+) -> Result<()> {
+    let parameters = bms
+        .get_parameters()
+        .with_context(|| format!("Cannot get parameters of '{}'", args.device))?;
+    let (name_b, parameters_b) = match (device_b, backup) {
+        (Some(device_b), _) => {
+            let mut bms_b = open_device(args, device_b)?;
+            let parameters_b = bms_b
+                .get_parameters()
+                .with_context(|| format!("Cannot get parameters of '{}'", device_b))?;
+            (device_b.to_string(), parameters_b)
+        }
+        (None, Some(backup)) => (backup.display().to_string(), read_backup(backup)?),
+        (None, None) => unreachable!("required by the argument parser"),
+    };
+
+    let format = |value: Option<f32>| value.map_or(String::from("-"), |value| value.to_string());
+    println!("{:<26} {:>16} {:>16}", "parameter", args.device, name_b);
+    let differences = parameters.differs(&parameters_b);
+    for ((name, value), (_, value_b)) in parameters.values().into_iter().zip(parameters_b.values())
+    {
+        let differs = differences.iter().any(|change| change.name == name);
+        println!(
+            "{:<26} {:>16} {:>16}{}",
+            name,
+            format(value),
+            format(value_b),
+            if differs { "  differs" } else { "" }
+        );
+    }
+    ensure!(
+        differences.is_empty(),
+        "{} parameters differ",
+        differences.len()
+    );
+    Ok(())
+}
+
+fn provision(args: &CliArgs, template: &PathBuf, devices: &[String], yes: bool) -> Result<()> {
+    let parameters = read_backup(template)?;
+    ensure!(
+        parameters.values().iter().any(|(_, value)| value.is_some()),
+        "The template '{}' contains no parameters which can be written",
+        template.display()
+    );
+    for (name, value) in parameters.values() {
+        if let Some(value) = value {
+            println!("{}: {}", name, value);
+        }
+    }
+    if !yes
+        && !confirm(&format!(
+            "Write these parameters to {} devices?",
+            devices.len()
+        ))?
+    {
+        bail!("Aborted");
+    }
+    let mut failed = 0;
+    for device in devices {
+        let result = (|| -> Result<usize> {
+            let mut bms = open_device(args, device)?;
+            let changes = bms
+                .get_parameters()
+                .with_context(|| "Cannot get parameters")?
+                .diff(&parameters);
+            if changes.is_empty() {
+                return Ok(0);
+            }
+            bms.set_parameters(&parameters)
+                .with_context(|| "Cannot set parameters")?;
+            if !args.dry_run {
+                let remaining = bms
+                    .get_parameters()
+                    .with_context(|| "Cannot read back parameters")?
+                    .diff(&parameters);
+                ensure!(
+                    remaining.is_empty(),
+                    "{} parameters not applied",
+                    remaining.len()
+                );
+            }
+            Ok(changes.len())
+        })();
+        match result {
+            Ok(0) => println!("{}: already matches the template", device),
+            Ok(changed) => println!("{}: {} parameters changed", device, changed),
+            Err(err) => {
+                failed += 1;
+                println!("{}: failed, {:#}", device, err);
+            }
+        }
+    }
+    ensure!(
+        failed == 0,
+        "{} of {} devices failed",
+        failed,
+        devices.len()
+    );
+    Ok(())
+}
+
+fn restore(bms: &mut DalyBMS, file: &PathBuf, yes: bool) -> Result<()> {
+    let parameters = read_backup(file)?;
+    for (name, value) in parameters.values() {
+        if value.is_none() {
+            println!("{}: not in the backup, not changed", name);
+        }
+    }
+    let current = bms
+        .get_parameters()
+        .with_context(|| "Cannot get parameters")?;
+    let changes = current.diff(&parameters);
+    if changes.is_empty() {
+        println!("All parameters match the backup");
+        return Ok(());
+    }
+    for change in &changes {
+        println!("{}", change);
+    }
+    if !yes && !confirm("Write these parameters?")? {
+        bail!("Aborted");
+    }
+    bms.set_parameters(&parameters)
+        .with_context(|| "Cannot set parameters")
+}
+
+fn factory_reset(bms: &mut DalyBMS, device: &str, confirm_device: Option<&str>) -> Result<()> {
+    let confirmed = match confirm_device {
+        Some(confirm_device) => confirm_device == device,
+        None => {
+            print!(
+                "All parameters of the BMS will be reset. Type the device path '{}' to confirm: ",
+                device
+            );
+            std::io::Write::flush(&mut std::io::stdout())?;
+            let mut answer = String::new();
+            std::io::stdin().read_line(&mut answer)?;
+            answer.trim() == device
+        }
+    };
+    if !confirmed {
+        bail!("Aborted, the device path does not match '{}'", device);
+    }
+    bms.factory_reset()
+        .with_context(|| "Cannot reset the BMS to factory parameters")
+}
+
+fn storage_mode(bms: &mut DalyBMS, state: Switch, yes: bool) -> Result<()> {
+    let question = match state {
+        Switch::On => "Disable charge and discharge mosfet? The pack will not deliver power.",
+        Switch::Off => "Enable charge and discharge mosfet?",
+    };
+    if !yes && !confirm(question)? {
+        bail!("Aborted");
+    }
+    let enable = state == Switch::Off;
+    // on: stop charging first, off: restore the output first
+    if enable {
+        bms.ensure_discharge_mosfet(true)
+            .with_context(|| "Cannot enable discharge mosfet")?;
+        bms.ensure_charge_mosfet(true)
+            .with_context(|| "Cannot enable charge mosfet")?;
+    } else {
+        bms.ensure_charge_mosfet(false)
+            .with_context(|| "Cannot disable charge mosfet")?;
+        if let Err(err) = bms.ensure_discharge_mosfet(false) {
+            // don't leave the pack half switched off
+            if let Err(restore_err) = bms.set_charge_mosfet(true) {
+                warn!("Cannot restore charge mosfet: {:#}", restore_err);
+            }
+            return Err(err.context("Cannot disable discharge mosfet"));
+        }
+    }
+    println!("Mosfets: {:?}", bms.get_mosfet_status()?);
+    Ok(())
+}
+
+fn error_history(bms: &mut DalyBMS, interval: Duration) -> Result<()> {
+    let mut tracker = ErrorTracker::default();
+    loop {
+        let errors = bms.get_errors().with_context(|| "Cannot get errors")?;
+        let now = SystemTime::now();
+        for transition in tracker.update_at(&errors, now) {
+            let (error, change) = match transition {
+                ErrorTransition::Raised(error) => (error, String::from("raised")),
+                ErrorTransition::Cleared { error, active } => (
+                    error,
+                    format!(
+                        "cleared after {}",
+                        humantime::format_duration(Duration::from_secs(active.as_secs()))
+                    ),
+                ),
+            };
+            let history = &tracker.history()[&error];
+            println!(
+                "{} {}: {} (occurrences {}, active {})",
+                humantime::format_rfc3339_seconds(now),
+                error,
+                change,
+                history.occurrences,
+                humantime::format_duration(Duration::from_secs(
+                    history.active_duration(now).as_secs()
+                ))
+            );
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+// Nagios plugin states, used as exit code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum PluginState {
+    Ok = 0,
+    Warning = 1,
+    Critical = 2,
+    Unknown = 3,
+}
+
+impl PluginState {
+    fn from_thresholds(
+        exceeds: impl Fn(f32) -> bool,
+        warn: Option<f32>,
+        crit: Option<f32>,
+    ) -> Self {
+        if crit.is_some_and(&exceeds) {
+            PluginState::Critical
+        } else if warn.is_some_and(&exceeds) {
+            PluginState::Warning
+        } else {
+            PluginState::Ok
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            PluginState::Ok => "OK",
+            PluginState::Warning => "WARNING",
+            PluginState::Critical => "CRITICAL",
+            PluginState::Unknown => "UNKNOWN",
+        }
+    }
+}
+
+fn save_current_offset(path: &Path, offset: f32) -> Result<()> {
+    let mut profile = if path.exists() {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Cannot read '{}'", path.display()))?;
+        serde_json::from_str(&text)
+            .with_context(|| format!("Invalid quirk profile '{}'", path.display()))?
+    } else {
+        QuirkProfile::standard()
+    };
+    profile.current_calibration.offset = offset;
+    std::fs::write(path, serde_json::to_string_pretty(&profile)?)
+        .with_context(|| format!("Cannot write '{}'", path.display()))
+}
+
+/// The selected quirk profile with the calibration options applied
+fn quirk_profile(args: &CliArgs) -> QuirkProfile {
+    let mut quirks = args.quirks.clone();
+    if let Some(offset) = args.current_offset {
+        quirks.current_calibration.offset = offset;
+    }
+    if let Some(scale) = args.current_scale {
+        quirks.current_calibration.scale = scale;
+    }
+    if let Some(offset) = args.temperature_offset {
+        quirks.temperature_offset = offset;
+    }
+    quirks
+}
+
+fn perfdata_threshold(threshold: Option<f32>) -> String {
+    threshold.map(|value| value.to_string()).unwrap_or_default()
+}
+
+fn check(
+    args: &CliArgs,
+    warn_soc: Option<f32>,
+    crit_soc: Option<f32>,
+    warn_cell_diff: Option<f32>,
+    crit_cell_diff: Option<f32>,
+) -> ExitCode {
+    let read = || -> Result<_> {
+        let mut bms = open_device(args, &args.device)?;
+        // one operation, so '--operation-timeout' bounds the whole probe
+        let snapshot = bms
+            .get_snapshot(&[Metric::Soc, Metric::VoltageRange, Metric::Errors])
+            .with_context(|| "Cannot get snapshot")?;
+        Ok((
+            snapshot.soc.context("SOC missing in the snapshot")?,
+            snapshot
+                .cell_voltage_range
+                .context("Voltage range missing in the snapshot")?,
+            snapshot.errors.context("Errors missing in the snapshot")?,
+        ))
+    };
+    let (soc, range, errors) = match read() {
+        Ok(values) => values,
+        Err(err) => {
+            println!("DALYBMS {} - {:#}", PluginState::Unknown.label(), err);
+            return ExitCode::from(PluginState::Unknown as u8);
+        }
+    };
+
+    let cell_diff = range.highest_voltage - range.lowest_voltage;
+    let soc_state =
+        PluginState::from_thresholds(|value| soc.soc_percent < value, warn_soc, crit_soc);
+    let cell_diff_state =
+        PluginState::from_thresholds(|value| cell_diff > value, warn_cell_diff, crit_cell_diff);
+    let errors_state = if errors.is_empty() {
+        PluginState::Ok
+    } else {
+        PluginState::Warning
+    };
+    let state = soc_state.max(cell_diff_state).max(errors_state);
+
+    let mut message = format!("SOC {:.1}%, cell diff {:.3}V", soc.soc_percent, cell_diff);
+    if !errors.is_empty() {
+        let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+        message.push_str(&format!(", errors: {}", errors.join(", ")));
+    }
+    println!(
+        "DALYBMS {} - {} | soc={}%;{};{};0;100 cell_diff={}V;{};{} voltage={}V current={}A errors={}",
+        state.label(),
+        message,
+        soc.soc_percent,
+        perfdata_threshold(warn_soc),
+        perfdata_threshold(crit_soc),
+        cell_diff,
+        perfdata_threshold(warn_cell_diff),
+        perfdata_threshold(crit_cell_diff),
+        soc.total_voltage,
+        soc.current,
+        errors.len()
+    );
+    ExitCode::from(state as u8)
+}
+
+// Exit codes for scripting, see '--errors-json'
+const EXIT_FAILURE: u8 = 1;
+const EXIT_COMMUNICATION: u8 = 2;
+const EXIT_CHECKSUM: u8 = 3;
+const EXIT_UNSUPPORTED: u8 = 4;
+const EXIT_INVALID_ARGUMENT: u8 = 5;
+// the BMS answered, but with implausible values or a rejected write, retrying won't help
+const EXIT_INVALID_DATA: u8 = 6;
+
+fn classify_error(err: &anyhow::Error) -> (u8, &'static str) {
+    for cause in err.chain() {
+        if let Some(err) = cause.downcast_ref::<dalybms_lib::Error>() {
+            return match err {
+                dalybms_lib::Error::CheckSumError => (EXIT_CHECKSUM, "checksum"),
+                dalybms_lib::Error::Unsupported(_) => (EXIT_UNSUPPORTED, "unsupported"),
+                dalybms_lib::Error::ImplausibleValue { .. }
+                | dalybms_lib::Error::VerificationFailed { .. } => {
+                    (EXIT_INVALID_DATA, "invalid_data")
+                }
+                _ => (EXIT_COMMUNICATION, "communication"),
+            };
+        }
+        if cause.is::<std::io::Error>() || cause.is::<serialport::Error>() {
+            return (EXIT_COMMUNICATION, "communication");
+        }
+    }
+    (EXIT_FAILURE, "failure")
+}
+
+fn main() -> ExitCode {
+    let args = match CliArgs::try_parse() {
+        Ok(args) => args,
+        Err(err) if !err.use_stderr() => err.exit(),
+        Err(err) => {
+            // the arguments could not be parsed, so look for the flag by hand
+            if std::env::args().any(|arg| arg == "--errors-json") {
+                eprintln!(
+                    "{}",
+                    serde_json::json!({
+                        "exit_code": EXIT_INVALID_ARGUMENT,
+                        "kind": "invalid_argument",
+                        "error": err.kind().to_string(),
+                    })
+                );
+            } else {
+                let _ = err.print();
+            }
+            return ExitCode::from(EXIT_INVALID_ARGUMENT);
+        }
+    };
+
+    let _log_handle = match logging_init(&args) {
+        Ok(log_handle) => log_handle,
+        Err(err) => return exit_with_error(&args, &err),
+    };
+
+    if let CliCommands::Check {
+        warn_soc,
+        crit_soc,
+        warn_cell_diff,
+        crit_cell_diff,
+    } = args.command
+    {
+        return check(&args, warn_soc, crit_soc, warn_cell_diff, crit_cell_diff);
+    }
+
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => exit_with_error(&args, &err),
+    }
+}
+
+fn exit_with_error(args: &CliArgs, err: &anyhow::Error) -> ExitCode {
+    let (exit_code, kind) = classify_error(err);
+    if args.errors_json {
+        eprintln!(
+            "{}",
+            serde_json::json!({
+                "exit_code": exit_code,
+                "kind": kind,
+                "error": format!("{:#}", err),
+            })
+        );
+    } else {
+        eprintln!("Error: {:?}", err);
+    }
+    ExitCode::from(exit_code)
+}
+
+fn run(args: &CliArgs) -> Result<()> {
+    match args.command {
+        CliCommands::Decode { ref input } => return decode(input),
+        CliCommands::Sniff { baud_rate } => return sniff(&args.device, baud_rate),
+        CliCommands::ListMetrics => {
+            for metric in Metric::ALL {
+                if metric.requires_status() {
+                    println!("{} (reads {} first)", metric, Metric::Status);
+                } else {
+                    println!("{}", metric);
+                }
+            }
+            return Ok(());
+        }
+        CliCommands::Commands => {
+            println!(
+                "{:<4} {:<26} {:<6} {:<7} {:<8} implemented",
+                "id", "name", "access", "frames", "support"
+            );
+            for info in commands::ALL {
+                println!(
+                    "{:02X}   {:<26} {:<6} {:<7} {:<8} {}",
+                    info.id,
+                    info.name,
+                    format!("{:?}", info.access),
+                    info.reply_frames
+                        .map_or_else(|| String::from("varies"), |frames| frames.to_string()),
+                    format!("{:?}", info.support),
+                    if info.implemented { "yes" } else { "no" }
+                );
+            }
+            return Ok(());
+        }
+        #[cfg(feature = "schemars")]
+        CliCommands::Schema { metric } => {
+            let schema = dalybms_lib::snapshot::json_schema(metric);
+            println!("{}", serde_json::to_string_pretty(&schema)?);
+            return Ok(());
+        }
+        CliCommands::Doctor { samples } => return doctor(args, samples),
+        CliCommands::Selftest { live } => return selftest(args, live),
+        CliCommands::Provision {
+            ref template,
+            ref devices,
+            yes,
+        } => return provision(args, template, devices, yes),
+        _ => {}
+    }
+
+    let mut bms = open_device(args, &args.device)?;
 
     match args.command {
         CliCommands::Status => print_status!(bms),
-        CliCommands::Soc => print_soc!(bms),
-        CliCommands::VoltageRange => print_voltage_range!(bms),
-        CliCommands::TemperatureRange => print_temperature_range!(bms),
+        CliCommands::Soc => print_soc!(bms, args.precision),
+        CliCommands::VoltageRange => print_voltage_range!(bms, args.precision),
+        CliCommands::TemperatureRange => print_temperature_range!(bms, args.temperature_unit),
         CliCommands::Mosfet => print_mosfet_status!(bms),
         CliCommands::CellVoltages => {
             let _ = bms.get_status().with_context(|| "Cannot get status")?;
-            print_cell_voltages!(bms);
+            let mut voltages = bms
+                .get_cell_voltages()
+                .with_context(|| "Cannot get cell voltages")?;
+            args.precision.round_voltages(&mut voltages);
+            println!("Cell Voltages: {:?}", voltages);
+            if let Some(stats) = CellVoltageStats::from_voltages(&voltages) {
+                println!("Cell voltage stats: {:?}", stats);
+            }
         }
         CliCommands::CellTemperatures => {
             let _ = bms.get_status().with_context(|| "Cannot get status")?;
-            print_cell_temperatures!(bms);
+            print_cell_temperatures!(bms, args.temperature_unit);
         }
         CliCommands::Balancing => {
             let _ = bms.get_status().with_context(|| "Cannot get status")?;
             print_balancing_status!(bms);
         }
-        CliCommands::Errors => print_errors!(bms),
+        CliCommands::Errors { history: false, .. } => print_errors!(bms),
+        CliCommands::Errors {
+            history: true,
+            interval,
+        } => error_history(&mut bms, interval)?,
         CliCommands::All => {
             print_status!(bms);
-            print_soc!(bms);
-            print_voltage_range!(bms);
-            print_temperature_range!(bms);
+            print_soc!(bms, args.precision);
+            print_voltage_range!(bms, args.precision);
+            print_temperature_range!(bms, args.temperature_unit);
             print_mosfet_status!(bms);
-            print_cell_voltages!(bms);
-            print_cell_temperatures!(bms);
+            print_cell_voltages!(bms, args.precision);
+            print_cell_temperatures!(bms, args.temperature_unit);
             print_balancing_status!(bms);
             print_errors!(bms);
-            print_soc!(bms);
+            print_soc!(bms, args.precision);
         }
         CliCommands::SetSoc { soc_percent } => {
             bms.set_soc(soc_percent).with_context(|| "Cannot set SOC")?
         }
-        CliCommands::SetChargeMosfet { enable } => bms
-            .set_charge_mosfet(enable)
-            .with_context(|| "Cannot set charge mosfet")?,
+        CliCommands::SetChargeMosfet { enable, force } => {
+            bms.set_mosfet_interlock(!force);
+            bms.set_charge_mosfet(enable)
+                .with_context(|| "Cannot set charge mosfet")?
+        }
         CliCommands::SetDischargeMosfet { enable } => bms
             .set_discharge_mosfet(enable)
             .with_context(|| "Cannot set discharge mosfet")?,
-        CliCommands::Reset => bms.reset()?,
+        CliCommands::SyncSoc {
+            absorb_voltage,
+            tail_current,
+            hold,
+            empty_cell_voltage,
+            empty_soc,
+            empty_hysteresis,
+            empty_min_interval,
+            interval,
+        } => sync_soc(
+            &mut bms,
+            FullChargeDetector::new(absorb_voltage, tail_current, hold),
+            empty_cell_voltage.map(|voltage| {
+                (
+                    EmptyDetector::new(voltage, empty_hysteresis, empty_min_interval),
+                    empty_soc,
+                )
+            }),
+            interval,
+        )?,
+        CliCommands::StorageMode { state, yes } => storage_mode(&mut bms, state, yes)?,
+        CliCommands::CurrentLimits => print_current_limits!(bms),
+        CliCommands::Backup { ref out } => backup(&mut bms, out)?,
+        CliCommands::Restore { ref file, yes } => restore(&mut bms, file, yes)?,
+        CliCommands::CompareParams {
+            ref device_b,
+            ref backup,
+        } => compare_params(&mut bms, args, device_b.as_deref(), backup.as_ref())?,
+        CliCommands::SetChargeCurrentLimit { amps } => bms
+            .set_charge_current_limit(amps)
+            .with_context(|| "Cannot set charge current limit")?,
+        CliCommands::SetDischargeCurrentLimit { amps } => bms
+            .set_discharge_current_limit(amps)
+            .with_context(|| "Cannot set discharge current limit")?,
+        CliCommands::CalibrateCurrent { samples, ref save } => {
+            bms.set_current_calibration(CurrentCalibration::default());
+            let mut sum = 0.0;
+            for _ in 0..samples {
+                sum += bms.get_soc().with_context(|| "Cannot get SOC")?.current;
+            }
+            let offset = -sum / samples as f32;
+            match save {
+                Some(path) => {
+                    save_current_offset(path, offset)?;
+                    println!(
+                        "Current offset {:.2} saved to '{}', use '--quirks {}'",
+                        offset,
+                        path.display(),
+                        path.display()
+                    );
+                }
+                None => println!(
+                    "Current offset: {:.2} (use '--current-offset={:.2}')",
+                    offset, offset
+                ),
+            }
+        }
+        CliCommands::Doctor { .. }
+        | CliCommands::Selftest { .. }
+        | CliCommands::Check { .. }
+        | CliCommands::ListMetrics
+        | CliCommands::Commands
+        | CliCommands::Decode { .. }
+        | CliCommands::Sniff { .. }
+        | CliCommands::Provision { .. } => unreachable!(),
+        #[cfg(feature = "schemars")]
+        CliCommands::Schema { .. } => unreachable!(),
+        CliCommands::CumulativeCapacity => match bms
+            .get_cumulative_capacity()
+            .with_context(|| "Cannot get cumulative capacity")?
+        {
+            Some(capacity) => println!("Cumulative capacity: {:?}", capacity),
+            None => println!("Cumulative capacity: not supported by the firmware"),
+        },
+        CliCommands::Snapshot { ref metrics } => {
+            let metrics = if metrics.is_empty() {
+                &Metric::ALL[..]
+            } else {
+                &metrics[..]
+            };
+            let mut snapshot = bms
+                .get_snapshot(metrics)
+                .with_context(|| "Cannot get snapshot")?;
+            args.precision.round_snapshot(&mut snapshot);
+            println!("{}", serde_json::to_string(&snapshot)?);
+        }
+        CliCommands::Collect { ref output } => collect(
+            &mut bms,
+            output.as_ref(),
+            args.temperature_unit,
+            args.precision,
+        )?,
+        CliCommands::Version => {
+            println!("dalybms {}", env!("CARGO_PKG_VERSION"));
+            let info = bms.identify().with_context(|| "Cannot identify BMS")?;
+            let unknown = String::from("not supported");
+            println!(
+                "Battery code: {}",
+                info.battery_code.as_ref().unwrap_or(&unknown)
+            );
+            println!(
+                "Software version: {}",
+                info.software_version.as_ref().unwrap_or(&unknown)
+            );
+            println!(
+                "Hardware version: {}",
+                info.hardware_version.as_ref().unwrap_or(&unknown)
+            );
+            println!(
+                "Cells: {}, temperature sensors: {}",
+                info.cells, info.temperature_sensors
+            );
+        }
+        CliCommands::Raw { command, ref data } => {
+            let mut payload = [0; 8];
+            payload[..data.len()].copy_from_slice(data);
+            println!(
+                "Reply: {}",
+                bms.raw_command(command, payload)
+                    .with_context(|| format!("Cannot send raw command {:02X}", command))?
+            );
+        }
+        CliCommands::FactoryReset { ref confirm_device } => {
+            factory_reset(&mut bms, &args.device, confirm_device.as_deref())?
+        }
     }
 
     Ok(())
@@ -0,0 +1,54 @@
+//! Hooks to inspect or modify the frames exchanged with the BMS without changing the
+//! clients, e.g. to capture the traffic or rewrite addresses for a gateway.
+
+use std::{fmt, io::Write};
+
+/// Added to a client with `add_middleware()`, multiple middlewares are called in the order
+/// they were added
+pub trait FrameMiddleware: fmt::Debug + Send + Sync {
+    /// Called with every request before it is sent
+    fn on_send(&mut self, _tx_buffer: &mut Vec<u8>) {}
+
+    /// Called with the bytes of every reply before they are validated and decoded
+    fn on_receive(&mut self, _rx_buffer: &mut Vec<u8>) {}
+}
+
+/// Writes every frame as a line of hex bytes, the output can be read by `analyzer::parse_hex()`
+/// and the `decode` subcommand
+pub struct FrameCapture<W> {
+    writer: W,
+}
+
+impl<W: Write + Send + Sync> FrameCapture<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        let line = bytes
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(" ");
+        // a failing capture must not break the communication
+        if let Err(err) = writeln!(self.writer, "{}", line).and_then(|_| self.writer.flush()) {
+            log::warn!("Cannot write frame capture: {}", err);
+        }
+    }
+}
+
+impl<W> fmt::Debug for FrameCapture<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FrameCapture").finish_non_exhaustive()
+    }
+}
+
+impl<W: Write + Send + Sync> FrameMiddleware for FrameCapture<W> {
+    fn on_send(&mut self, tx_buffer: &mut Vec<u8>) {
+        self.write(tx_buffer);
+    }
+
+    fn on_receive(&mut self, rx_buffer: &mut Vec<u8>) {
+        self.write(rx_buffer);
+    }
+}
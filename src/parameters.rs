@@ -0,0 +1,124 @@
+//! Configuration parameters which can be read and written, e.g. to back them up before a
+//! firmware reset or to compare the configuration of several packs.
+//!
+//! Only the over-current alarm thresholds (commands 0x5B/0x1B) are covered. The voltage and
+//! temperature thresholds, the balancing configuration and the battery code can be read
+//! (see `protocol::commands`), but their write commands are not known, so they can't be
+//! restored or provisioned.
+
+use crate::protocol::CurrentLimits;
+use std::fmt;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Parameters which were not read or are not supported by the firmware are `None` and not written
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+// parameters which can't be written, e.g. thresholds in a hand written template, are rejected
+// instead of silently ignored
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct Parameters {
+    pub current_limits: Option<CurrentLimits>,
+}
+
+/// A parameter which differs between two `Parameters`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParameterChange {
+    pub name: &'static str,
+    pub old: Option<f32>,
+    pub new: Option<f32>,
+}
+
+impl fmt::Display for ParameterChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let format =
+            |value: Option<f32>| value.map_or(String::from("-"), |value| value.to_string());
+        write!(
+            f,
+            "{}: {} -> {}",
+            self.name,
+            format(self.old),
+            format(self.new)
+        )
+    }
+}
+
+impl Parameters {
+    /// All parameters by name, in the order they are shown
+    pub fn values(&self) -> Vec<(&'static str, Option<f32>)> {
+        let limits = self.current_limits.as_ref();
+        vec![
+            ("charge_current_level1", limits.map(|l| l.charge_level1)),
+            ("charge_current_level2", limits.map(|l| l.charge_level2)),
+            (
+                "discharge_current_level1",
+                limits.map(|l| l.discharge_level1),
+            ),
+            (
+                "discharge_current_level2",
+                limits.map(|l| l.discharge_level2),
+            ),
+        ]
+    }
+
+    /// Parameters which differ between `self` and `other`, a parameter which is `None` in only
+    /// one of them differs as well
+    pub fn differs(&self, other: &Parameters) -> Vec<ParameterChange> {
+        self.values()
+            .into_iter()
+            .zip(other.values())
+            .filter_map(|((name, old), (_, new))| {
+                let differs = match (old, new) {
+                    // the values are transferred with a resolution of 0.1
+                    (Some(old), Some(new)) => (old - new).abs() > 0.05,
+                    (old, new) => old.is_some() != new.is_some(),
+                };
+                differs.then_some(ParameterChange { name, old, new })
+            })
+            .collect()
+    }
+
+    /// Parameters which differ when `self` is changed to `new`, parameters missing in `new` are
+    /// kept and not reported
+    pub fn diff(&self, new: &Parameters) -> Vec<ParameterChange> {
+        self.differs(new)
+            .into_iter()
+            .filter(|change| change.new.is_some())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parameters(charge_level2: f32) -> Parameters {
+        Parameters {
+            current_limits: Some(CurrentLimits {
+                charge_level1: 100.0,
+                charge_level2,
+                discharge_level1: 150.0,
+                discharge_level2: 160.0,
+            }),
+        }
+    }
+
+    #[test]
+    fn differences_within_resolution() {
+        assert!(parameters(110.0).differs(&parameters(110.04)).is_empty());
+        let changes = parameters(110.0).differs(&parameters(110.1));
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].name, "charge_current_level2");
+    }
+
+    #[test]
+    fn missing_parameters() {
+        // missing in the other pack
+        assert_eq!(parameters(110.0).differs(&Parameters::default()).len(), 4);
+        // missing in a backup, not changed by a restore
+        assert!(parameters(110.0).diff(&Parameters::default()).is_empty());
+        assert_eq!(Parameters::default().diff(&parameters(110.0)).len(), 4);
+    }
+}
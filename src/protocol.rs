@@ -1,10 +1,13 @@
 use crate::Error;
-use std::fmt;
+use std::{fmt, ops::RangeInclusive};
 
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
+pub mod commands;
+
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum Address {
     Host = 0x40,
@@ -16,8 +19,8 @@ pub const MINIMUM_DELAY: std::time::Duration = std::time::Duration::from_millis(
 
 const TX_BUFFER_LENGTH: usize = 13;
 const RX_BUFFER_LENGTH: usize = 13;
-const START_BYTE: u8 = 0xa5;
-const DATA_LENGTH: u8 = 0x08;
+pub(crate) const START_BYTE: u8 = 0xa5;
+pub(crate) const DATA_LENGTH: u8 = 0x08;
 
 fn create_request_header(address: Address, command: u8) -> Vec<u8> {
     let mut tx_buffer = vec![0; TX_BUFFER_LENGTH];
@@ -28,7 +31,7 @@ fn create_request_header(address: Address, command: u8) -> Vec<u8> {
     tx_buffer
 }
 
-fn calc_crc(buffer: &[u8]) -> u8 {
+pub(crate) fn calc_crc(buffer: &[u8]) -> u8 {
     let mut checksum: u8 = 0;
     let slice = &buffer[0..buffer.len() - 1];
     for b in slice {
@@ -37,7 +40,19 @@ fn calc_crc(buffer: &[u8]) -> u8 {
     checksum
 }
 
-fn calc_crc_and_set(buffer: &mut [u8]) {
+// The current values are given with a 30000 unit offset (see /docs/)
+const CURRENT_OFFSET: i32 = 30000;
+
+pub(crate) fn decode_current(high: u8, low: u8) -> f32 {
+    (((u16::from_be_bytes([high, low]) as i32) - CURRENT_OFFSET) as f32) / 10.0
+}
+
+pub(crate) fn encode_current(current: f32) -> [u8; 2] {
+    let value = ((current * 10.0).round() as i32 + CURRENT_OFFSET).clamp(0, u16::MAX as i32);
+    (value as u16).to_be_bytes()
+}
+
+pub(crate) fn calc_crc_and_set(buffer: &mut [u8]) {
     let len = buffer.len();
     buffer[len - 1] = calc_crc(buffer)
 }
@@ -74,14 +89,290 @@ fn validate_checksum(buffer: &[u8]) -> std::result::Result<(), Error> {
     Ok(())
 }
 
+/// Numbering of the frames of multi frame replies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FrameNumbering {
+    /// The first frame has the number 1 like specified
+    #[default]
+    OneBased,
+    ZeroBased,
+    /// The frame number is not checked
+    Ignore,
+}
+
+fn validate_frame_number(
+    numbering: FrameNumbering,
+    n_frame: usize,
+    received: u8,
+) -> std::result::Result<(), Error> {
+    let expected = match numbering {
+        FrameNumbering::OneBased => n_frame,
+        FrameNumbering::ZeroBased => n_frame - 1,
+        FrameNumbering::Ignore => return Ok(()),
+    };
+    if expected != usize::from(received) {
+        log::warn!(
+            "Frame out of order - expected={} received={}",
+            expected,
+            received
+        );
+        return Err(Error::FrameNoError);
+    }
+    Ok(())
+}
+
+/// How the clients handle replies which deviate from the protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DecodeMode {
+    /// Reject frames with a wrong start byte, command or length
+    #[default]
+    Strict,
+    /// Skip leading garbage and decode frames with a wrong header as long as the checksum
+    /// matches, every deviation is logged
+    Lenient,
+}
+
+/// Position of the first frame of a reply to `command` in `rx_buffer`, a frame which is cut
+/// off at the end of the buffer counts if its bytes so far match
+pub fn find_frame_start(command: u8, rx_buffer: &[u8]) -> Option<usize> {
+    (0..rx_buffer.len()).find(|offset| {
+        let header = &rx_buffer[*offset..(*offset + 4).min(rx_buffer.len())];
+        header[0] == START_BYTE
+            && header.get(2).is_none_or(|value| *value == command)
+            && header.get(3).is_none_or(|value| *value == DATA_LENGTH)
+    })
+}
+
+/// Checks the header of each frame of a reply to `command`, in lenient mode deviations are
+/// only logged
+pub fn validate_frames(
+    command: u8,
+    rx_buffer: &[u8],
+    mode: DecodeMode,
+) -> std::result::Result<(), Error> {
+    for frame in rx_buffer.chunks(RX_BUFFER_LENGTH) {
+        if frame.len() == RX_BUFFER_LENGTH
+            && frame[0] == START_BYTE
+            && frame[2] == command
+            && frame[3] == DATA_LENGTH
+        {
+            continue;
+        }
+        match mode {
+            DecodeMode::Strict => {
+                log::warn!(
+                    "Invalid frame header - command={:02X?} frame={:02X?}",
+                    command,
+                    frame
+                );
+                return Err(Error::FrameHeaderError);
+            }
+            DecodeMode::Lenient => log::info!(
+                "Tolerated invalid frame header - command={:02X?} frame={:02X?}",
+                command,
+                frame
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Detects the replies of firmwares which do not support a command, they answer with an
+/// all-zero frame or echo the request
+pub fn validate_supported(command: u8, rx_buffer: &[u8]) -> std::result::Result<(), Error> {
+    let frame = &rx_buffer[..rx_buffer.len().min(RX_BUFFER_LENGTH)];
+    if frame.iter().all(|byte| *byte == 0) || frame.get(1) == Some(&(Address::Host as u8)) {
+        log::warn!(
+            "Command not supported - command={:02X?} reply={:02X?}",
+            command,
+            frame
+        );
+        return Err(Error::Unsupported(command));
+    }
+    Ok(())
+}
+
+/// Physically possible ranges of measurements, used to reject readings garbled on the bus
 #[derive(Debug, Clone)]
+pub struct PlausibilityLimits {
+    pub cell_voltage: RangeInclusive<f32>,
+    pub temperature: RangeInclusive<i32>,
+}
+
+impl Default for PlausibilityLimits {
+    fn default() -> Self {
+        Self {
+            cell_voltage: 0.5..=5.0,
+            temperature: -60..=150,
+        }
+    }
+}
+
+impl PlausibilityLimits {
+    pub fn check_cell_voltages(
+        &self,
+        voltages: impl IntoIterator<Item = f32>,
+    ) -> std::result::Result<(), Error> {
+        for voltage in voltages {
+            if !self.cell_voltage.contains(&voltage) {
+                log::warn!(
+                    "Implausible cell voltage - received={} accepted={:?}",
+                    voltage,
+                    self.cell_voltage
+                );
+                return Err(Error::ImplausibleValue {
+                    name: "cell voltage",
+                    value: voltage.into(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_temperatures(
+        &self,
+        temperatures: impl IntoIterator<Item = i32>,
+    ) -> std::result::Result<(), Error> {
+        for temperature in temperatures {
+            if !self.temperature.contains(&temperature) {
+                log::warn!(
+                    "Implausible temperature - received={} accepted={:?}",
+                    temperature,
+                    self.temperature
+                );
+                return Err(Error::ImplausibleValue {
+                    name: "temperature",
+                    value: temperature.into(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "wasm", wasm_bindgen::prelude::wasm_bindgen)]
+// missing fields are zero, e.g. in JSON of other tools or older versions
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct Soc {
     pub total_voltage: f32,
     pub current: f32, // negative=charging, positive=discharging
     pub soc_percent: f32,
 }
 
+/// Correction of the current sensor, the corrected current is `(current + offset) * scale`
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct CurrentCalibration {
+    pub offset: f32,
+    pub scale: f32,
+}
+
+impl Default for CurrentCalibration {
+    fn default() -> Self {
+        Self {
+            offset: 0.0,
+            scale: 1.0,
+        }
+    }
+}
+
+impl CurrentCalibration {
+    pub fn apply(&self, current: f32) -> f32 {
+        (current + self.offset) * self.scale
+    }
+}
+
+/// Deviations of non genuine boards from the Daly protocol, applied by the clients to
+/// every reply. Use `QuirkProfile::default()` for genuine boards and adjust the fields to the
+/// behavior of a board, there are no predefined profiles of clones.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+// fields missing in a profile file keep the standard behavior
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct QuirkProfile {
+    pub current_calibration: CurrentCalibration,
+    /// Added to all temperatures in °C
+    pub temperature_offset: i8,
+    pub frame_numbering: FrameNumbering,
+    /// Commands the board does not answer, they fail with `Error::Unsupported` without
+    /// being sent
+    pub unsupported_commands: Vec<u8>,
+}
+
+impl QuirkProfile {
+    /// Names accepted by `from_str()`
+    pub const NAMES: [&'static str; 1] = ["standard"];
+
+    pub fn standard() -> Self {
+        Self::default()
+    }
+
+    pub fn is_supported(&self, command: u8) -> bool {
+        !self.unsupported_commands.contains(&command)
+    }
+
+    pub fn apply_temperature(&self, temperature: i8) -> i8 {
+        temperature.saturating_add(self.temperature_offset)
+    }
+
+    /// Adds the unsupported commands of the first entry of `table` matching the software version
+    pub fn apply_firmware(&mut self, software_version: &str, table: &[FirmwareQuirks]) {
+        let Some(quirks) = table
+            .iter()
+            .find(|quirks| software_version.starts_with(&quirks.software_version))
+        else {
+            return;
+        };
+        log::info!(
+            "Firmware {} has known quirks, skip commands {:02X?}",
+            software_version,
+            quirks.unsupported_commands
+        );
+        for command in &quirks.unsupported_commands {
+            if self.is_supported(*command) {
+                self.unsupported_commands.push(*command);
+            }
+        }
+    }
+}
+
+/// Commands which are broken on a firmware version, applied by `identify()` of the clients.
+/// This is an override mechanism: no firmware version with broken commands has been confirmed,
+/// so the clients start with an empty table and the entries are supplied by the user
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct FirmwareQuirks {
+    /// Matches all software versions starting with it
+    pub software_version: String,
+    pub unsupported_commands: Vec<u8>,
+}
+
+impl std::str::FromStr for QuirkProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "standard" => Ok(Self::standard()),
+            _ => Err(format!(
+                "unknown quirk profile '{}', expected one of: {}",
+                s,
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
 impl Soc {
     pub fn request(address: Address) -> Vec<u8> {
         let mut tx_buffer = create_request_header(address, 0x90);
@@ -98,16 +389,17 @@ impl Soc {
         validate_checksum(rx_buffer)?;
         Ok(Self {
             total_voltage: u16::from_be_bytes([rx_buffer[4], rx_buffer[5]]) as f32 / 10.0,
-            // The current measurement is given with a 30000 unit offset (see /docs/)
-            current: (((u16::from_be_bytes([rx_buffer[8], rx_buffer[9]]) as i32) - 30000) as f32)
-                / 10.0,
+            current: decode_current(rx_buffer[8], rx_buffer[9]),
             soc_percent: u16::from_be_bytes([rx_buffer[10], rx_buffer[11]]) as f32 / 10.0,
         })
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct CellVoltageRange {
     pub highest_voltage: f32,
     pub highest_cell: u8,
@@ -138,8 +430,66 @@ impl CellVoltageRange {
     }
 }
 
-#[derive(Debug, Clone)]
+/// Unit for displaying temperatures, the BMS and all structs of this crate use °C
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum TemperatureUnit {
+    #[default]
+    Celsius,
+    Fahrenheit,
+}
+
+impl TemperatureUnit {
+    /// Converts a temperature in °C, rounded to 0.1
+    pub fn convert(&self, celsius: f32) -> f32 {
+        match self {
+            TemperatureUnit::Celsius => celsius,
+            TemperatureUnit::Fahrenheit => ((celsius * 1.8 + 32.0) * 10.0).round() / 10.0,
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            TemperatureUnit::Celsius => "°C",
+            TemperatureUnit::Fahrenheit => "°F",
+        }
+    }
+
+    /// Lower case name, e.g. as metric name suffix
+    pub fn name(&self) -> &'static str {
+        match self {
+            TemperatureUnit::Celsius => "celsius",
+            TemperatureUnit::Fahrenheit => "fahrenheit",
+        }
+    }
+
+    /// Converts a temperature in °C and appends the unit symbol
+    pub fn format(&self, celsius: f32) -> String {
+        format!("{}{}", self.convert(celsius), self.symbol())
+    }
+}
+
+impl std::str::FromStr for TemperatureUnit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "celsius" | "c" => Ok(TemperatureUnit::Celsius),
+            "fahrenheit" | "f" => Ok(TemperatureUnit::Fahrenheit),
+            _ => Err(format!(
+                "unknown temperature unit '{}', expected celsius or fahrenheit",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct TemperatureRange {
     pub highest_temperature: i8,
     pub highest_sensor: u8,
@@ -171,16 +521,22 @@ impl TemperatureRange {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum MosfetMode {
+    #[default]
     Stationary,
     Charging,
     Discharging,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct MosfetStatus {
     pub mode: MosfetMode,
     pub charging_mosfet: bool,
@@ -225,8 +581,11 @@ impl MosfetStatus {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct IOState {
     pub di1: bool,
     pub di2: bool,
@@ -238,8 +597,11 @@ pub struct IOState {
     pub do4: bool,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct Status {
     pub cells: u8,
     pub temperature_sensors: u8,
@@ -249,7 +611,56 @@ pub struct Status {
     pub cycles: u16,
 }
 
+const MAX_BALANCING_CELLS: u8 = 48;
+
+/// Accepted ranges for the number of cells and temperature sensors reported by `Status`,
+/// protects against huge reads caused by a corrupt status frame
+#[derive(Debug, Clone)]
+pub struct StatusLimits {
+    pub cells: RangeInclusive<u8>,
+    pub temperature_sensors: RangeInclusive<u8>,
+}
+
+impl Default for StatusLimits {
+    fn default() -> Self {
+        Self {
+            // the balancing reply has one bit for each of at most 48 cells
+            cells: 1..=MAX_BALANCING_CELLS,
+            temperature_sensors: 0..=16,
+        }
+    }
+}
+
 impl Status {
+    pub fn validate(&self, limits: &StatusLimits) -> std::result::Result<(), Error> {
+        if !limits.cells.contains(&self.cells) {
+            log::warn!(
+                "Implausible number of cells - received={} accepted={:?}",
+                self.cells,
+                limits.cells
+            );
+            return Err(Error::ImplausibleValue {
+                name: "number of cells",
+                value: self.cells.into(),
+            });
+        }
+        if !limits
+            .temperature_sensors
+            .contains(&self.temperature_sensors)
+        {
+            log::warn!(
+                "Implausible number of temperature sensors - received={} accepted={:?}",
+                self.temperature_sensors,
+                limits.temperature_sensors
+            );
+            return Err(Error::ImplausibleValue {
+                name: "number of temperature sensors",
+                value: self.temperature_sensors.into(),
+            });
+        }
+        Ok(())
+    }
+
     pub fn request(address: Address) -> Vec<u8> {
         let mut tx_buffer = create_request_header(address, 0x94);
         calc_crc_and_set(&mut tx_buffer);
@@ -301,33 +712,61 @@ impl CellVoltages {
     }
 
     pub fn decode(rx_buffer: &[u8], n_cells: u8) -> std::result::Result<Vec<f32>, Error> {
-        validate_len(rx_buffer, Self::reply_size(n_cells))?;
+        Self::decode_numbered(rx_buffer, n_cells, FrameNumbering::OneBased)
+    }
+
+    pub fn decode_numbered(
+        rx_buffer: &[u8],
+        n_cells: u8,
+        numbering: FrameNumbering,
+    ) -> std::result::Result<Vec<f32>, Error> {
         let mut result = Vec::with_capacity(n_cells as usize);
+        Self::decode_each(rx_buffer, n_cells, numbering, |volt| {
+            result.push(volt);
+            Ok(())
+        })?;
+        Ok(result)
+    }
+
+    /// Decodes without allocating, fails with `Error::CapacityError` if `N` is less than `n_cells`
+    #[cfg(feature = "heapless")]
+    pub fn decode_heapless<const N: usize>(
+        rx_buffer: &[u8],
+        n_cells: u8,
+        numbering: FrameNumbering,
+    ) -> std::result::Result<heapless::Vec<f32, N>, Error> {
+        let mut result = heapless::Vec::new();
+        Self::decode_each(rx_buffer, n_cells, numbering, |volt| {
+            result.push(volt).map_err(|_| Error::CapacityError)
+        })?;
+        Ok(result)
+    }
+
+    fn decode_each(
+        rx_buffer: &[u8],
+        n_cells: u8,
+        numbering: FrameNumbering,
+        mut push: impl FnMut(f32) -> std::result::Result<(), Error>,
+    ) -> std::result::Result<(), Error> {
+        validate_len(rx_buffer, Self::reply_size(n_cells))?;
         let mut n_cell = 1;
 
         for n_frame in 1..=Self::n_frames(n_cells) {
             let part =
                 &rx_buffer[((n_frame - 1) * RX_BUFFER_LENGTH)..((n_frame) * RX_BUFFER_LENGTH)];
-            if n_frame != usize::from(part[4]) {
-                log::warn!(
-                    "Frame out of order - expected={} received={}",
-                    n_frame,
-                    part[4]
-                );
-                return Err(Error::FrameNoError);
-            }
+            validate_frame_number(numbering, n_frame, part[4])?;
             validate_checksum(part)?;
             for i in 0..3 {
                 let volt = u16::from_be_bytes([part[5 + i + i], part[6 + i + i]]) as f32 / 1000.0;
                 log::trace!("Frame #{} cell #{} volt={}", n_frame, n_cell, volt);
-                result.push(volt);
+                push(volt)?;
                 n_cell += 1;
                 if n_cell > n_cells {
                     break;
                 }
             }
         }
-        Ok(result)
+        Ok(())
     }
 }
 
@@ -349,33 +788,61 @@ impl CellTemperatures {
     }
 
     pub fn decode(rx_buffer: &[u8], n_sensors: u8) -> std::result::Result<Vec<i32>, Error> {
-        validate_len(rx_buffer, Self::reply_size(n_sensors))?;
+        Self::decode_numbered(rx_buffer, n_sensors, FrameNumbering::OneBased)
+    }
+
+    pub fn decode_numbered(
+        rx_buffer: &[u8],
+        n_sensors: u8,
+        numbering: FrameNumbering,
+    ) -> std::result::Result<Vec<i32>, Error> {
         let mut result = Vec::with_capacity(n_sensors as usize);
+        Self::decode_each(rx_buffer, n_sensors, numbering, |temperature| {
+            result.push(temperature);
+            Ok(())
+        })?;
+        Ok(result)
+    }
+
+    /// Decodes without allocating, fails with `Error::CapacityError` if `N` is less than `n_sensors`
+    #[cfg(feature = "heapless")]
+    pub fn decode_heapless<const N: usize>(
+        rx_buffer: &[u8],
+        n_sensors: u8,
+        numbering: FrameNumbering,
+    ) -> std::result::Result<heapless::Vec<i32, N>, Error> {
+        let mut result = heapless::Vec::new();
+        Self::decode_each(rx_buffer, n_sensors, numbering, |temperature| {
+            result.push(temperature).map_err(|_| Error::CapacityError)
+        })?;
+        Ok(result)
+    }
+
+    fn decode_each(
+        rx_buffer: &[u8],
+        n_sensors: u8,
+        numbering: FrameNumbering,
+        mut push: impl FnMut(i32) -> std::result::Result<(), Error>,
+    ) -> std::result::Result<(), Error> {
+        validate_len(rx_buffer, Self::reply_size(n_sensors))?;
         let mut n_sensor = 1;
 
         for n_frame in 1..=Self::n_frames(n_sensors) {
             let part =
                 &rx_buffer[((n_frame - 1) * RX_BUFFER_LENGTH)..((n_frame) * RX_BUFFER_LENGTH)];
-            if n_frame != usize::from(part[4]) {
-                log::warn!(
-                    "Frame out of order - expected={} received={}",
-                    n_frame,
-                    part[4]
-                );
-                return Err(Error::FrameNoError);
-            }
+            validate_frame_number(numbering, n_frame, part[4])?;
             validate_checksum(part)?;
             for i in 0..7 {
                 let temperature = part[5 + i] as i32 - 40;
                 log::trace!("Frame #{} sensor #{} °C={}", n_frame, n_sensor, temperature);
-                result.push(temperature);
+                push(temperature)?;
                 n_sensor += 1;
                 if n_sensor > n_sensors {
                     break;
                 }
             }
         }
-        Ok(result)
+        Ok(())
     }
 }
 
@@ -392,28 +859,54 @@ impl CellBalanceState {
         RX_BUFFER_LENGTH
     }
 
+    /// The reply has room for 48 cells, more fail with `Error::ImplausibleValue`
     pub fn decode(rx_buffer: &[u8], n_cells: u8) -> std::result::Result<Vec<bool>, Error> {
+        let mut result = Vec::with_capacity(n_cells as usize);
+        Self::decode_each(rx_buffer, n_cells, |balancing| {
+            result.push(balancing);
+            Ok(())
+        })?;
+        Ok(result)
+    }
+
+    /// Decodes without allocating, fails with `Error::CapacityError` if `N` is less than `n_cells`
+    #[cfg(feature = "heapless")]
+    pub fn decode_heapless<const N: usize>(
+        rx_buffer: &[u8],
+        n_cells: u8,
+    ) -> std::result::Result<heapless::Vec<bool, N>, Error> {
+        let mut result = heapless::Vec::new();
+        Self::decode_each(rx_buffer, n_cells, |balancing| {
+            result.push(balancing).map_err(|_| Error::CapacityError)
+        })?;
+        Ok(result)
+    }
+
+    fn decode_each(
+        rx_buffer: &[u8],
+        n_cells: u8,
+        mut push: impl FnMut(bool) -> std::result::Result<(), Error>,
+    ) -> std::result::Result<(), Error> {
         validate_len(rx_buffer, Self::reply_size())?;
         validate_checksum(rx_buffer)?;
-        let mut result = Vec::with_capacity(n_cells as usize);
-        let mut n_cell = 0;
-        // We expect 6 bytes response for this command
-        for i in 0..6 {
-            // For each bit in the byte, pull out the cell balance state boolean
-            for j in 0..8 {
-                result.push(read_bit!(rx_buffer[4 + i], j));
-                n_cell += 1;
-                if n_cell >= n_cells {
-                    break;
-                }
-            }
+        if n_cells > MAX_BALANCING_CELLS {
+            return Err(Error::ImplausibleValue {
+                name: "number of cells",
+                value: n_cells.into(),
+            });
         }
-        Ok(result)
+        // We expect 6 bytes response for this command, one bit per cell
+        for n_cell in 0..usize::from(n_cells) {
+            push(read_bit!(rx_buffer[4 + n_cell / 8], n_cell % 8))?;
+        }
+        Ok(())
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum ErrorCode {
     CellVoltHighLevel1,
     CellVoltHighLevel2,
@@ -465,7 +958,69 @@ pub enum ErrorCode {
     LowVoltForbiddenChargeFault,
 }
 
+// Position (byte, bit) of each error code within the reply of the 0x98 command
+pub(crate) const ERROR_CODE_BITS: [(ErrorCode, usize, u8); 48] = [
+    (ErrorCode::CellVoltHighLevel1, 4, 0),
+    (ErrorCode::CellVoltHighLevel2, 4, 1),
+    (ErrorCode::CellVoltLowLevel1, 4, 2),
+    (ErrorCode::CellVoltLowLevel2, 4, 3),
+    (ErrorCode::SumVoltHighLevel1, 4, 4),
+    (ErrorCode::SumVoltHighLevel2, 4, 5),
+    (ErrorCode::SumVoltLowLevel1, 4, 6),
+    (ErrorCode::SumVoltLowLevel2, 4, 7),
+    (ErrorCode::ChargeTempHighLevel1, 5, 0),
+    (ErrorCode::ChargeTempHighLevel2, 5, 1),
+    (ErrorCode::ChargeTempLowLevel1, 5, 2),
+    (ErrorCode::ChargeTempLowLevel2, 5, 3),
+    (ErrorCode::DischargeTempHighLevel1, 5, 4),
+    (ErrorCode::DischargeTempHighLevel2, 5, 5),
+    (ErrorCode::DischargeTempLowLevel1, 5, 6),
+    (ErrorCode::DischargeTempLowLevel2, 5, 7),
+    (ErrorCode::ChargeOvercurrentLevel1, 6, 0),
+    (ErrorCode::ChargeOvercurrentLevel2, 6, 1),
+    (ErrorCode::DischargeOvercurrentLevel1, 6, 2),
+    (ErrorCode::DischargeOvercurrentLevel2, 6, 3),
+    (ErrorCode::SocHighLevel1, 6, 4),
+    (ErrorCode::SocHighLevel2, 6, 5),
+    (ErrorCode::SocLowLevel1, 6, 6),
+    (ErrorCode::SocLowLevel2, 6, 7),
+    (ErrorCode::DiffVoltLevel1, 7, 0),
+    (ErrorCode::DiffVoltLevel2, 7, 1),
+    (ErrorCode::DiffTempLevel1, 7, 2),
+    (ErrorCode::DiffTempLevel2, 7, 3),
+    (ErrorCode::ChargeMosTempHighAlarm, 8, 0),
+    (ErrorCode::DischargeMosTempHighAlarm, 8, 1),
+    (ErrorCode::ChargeMosTempSensorErr, 8, 2),
+    (ErrorCode::DischargeMosTempSensorErr, 8, 3),
+    (ErrorCode::ChargeMosAdhesionErr, 8, 4),
+    (ErrorCode::DischargeMosAdhesionErr, 8, 5),
+    (ErrorCode::ChargeMosOpenCircuitErr, 8, 6),
+    (ErrorCode::DischargeMosOpenCircuitErr, 8, 7),
+    (ErrorCode::AfeCollectChipErr, 9, 0),
+    (ErrorCode::VoltageCollectDropped, 9, 1),
+    (ErrorCode::CellTempSensorErr, 9, 2),
+    (ErrorCode::EepromErr, 9, 3),
+    (ErrorCode::RtcErr, 9, 4),
+    (ErrorCode::PrechangeFailure, 9, 5),
+    (ErrorCode::CommunicationFailure, 9, 6),
+    (ErrorCode::InternalCommunicationFailure, 9, 7),
+    (ErrorCode::CurrentModuleFault, 10, 0),
+    (ErrorCode::SumVoltageDetectFault, 10, 1),
+    (ErrorCode::ShortCircuitProtectFault, 10, 2),
+    (ErrorCode::LowVoltForbiddenChargeFault, 10, 3),
+];
+
 impl ErrorCode {
+    /// Errors for which the charge mosfet must not be enabled by the host
+    pub fn blocks_charging(&self) -> bool {
+        matches!(
+            self,
+            ErrorCode::CellVoltHighLevel2
+                | ErrorCode::SumVoltHighLevel2
+                | ErrorCode::ChargeTempHighLevel2
+        )
+    }
+
     pub fn request(address: Address) -> Vec<u8> {
         let mut tx_buffer = create_request_header(address, 0x98);
         calc_crc_and_set(&mut tx_buffer);
@@ -481,69 +1036,12 @@ impl ErrorCode {
         validate_checksum(rx_buffer)?;
         let mut result = Vec::new();
 
-        macro_rules! ck_and_add {
-            ($byte:expr,$position:expr,$enum_type:expr) => {
-                if read_bit!(rx_buffer[$byte], $position) {
-                    result.push($enum_type);
-                }
-            };
+        for (error_code, byte, position) in ERROR_CODE_BITS {
+            if read_bit!(rx_buffer[byte], position) {
+                result.push(error_code);
+            }
         }
 
-        ck_and_add!(4, 0, ErrorCode::CellVoltHighLevel1);
-        ck_and_add!(4, 1, ErrorCode::CellVoltHighLevel2);
-        ck_and_add!(4, 2, ErrorCode::CellVoltLowLevel1);
-        ck_and_add!(4, 3, ErrorCode::CellVoltLowLevel2);
-        ck_and_add!(4, 4, ErrorCode::SumVoltHighLevel1);
-        ck_and_add!(4, 5, ErrorCode::SumVoltHighLevel2);
-        ck_and_add!(4, 6, ErrorCode::SumVoltLowLevel1);
-        ck_and_add!(4, 7, ErrorCode::SumVoltLowLevel2);
-
-        ck_and_add!(5, 0, ErrorCode::ChargeTempHighLevel1);
-        ck_and_add!(5, 1, ErrorCode::ChargeTempHighLevel2);
-        ck_and_add!(5, 2, ErrorCode::ChargeTempLowLevel1);
-        ck_and_add!(5, 3, ErrorCode::ChargeTempLowLevel2);
-        ck_and_add!(5, 4, ErrorCode::DischargeTempHighLevel1);
-        ck_and_add!(5, 5, ErrorCode::DischargeTempHighLevel2);
-        ck_and_add!(5, 6, ErrorCode::DischargeTempLowLevel1);
-        ck_and_add!(5, 7, ErrorCode::DischargeTempLowLevel2);
-
-        ck_and_add!(6, 1, ErrorCode::ChargeOvercurrentLevel2);
-        ck_and_add!(6, 0, ErrorCode::ChargeOvercurrentLevel1);
-        ck_and_add!(6, 2, ErrorCode::DischargeOvercurrentLevel1);
-        ck_and_add!(6, 3, ErrorCode::DischargeOvercurrentLevel2);
-        ck_and_add!(6, 4, ErrorCode::SocHighLevel1);
-        ck_and_add!(6, 5, ErrorCode::SocHighLevel2);
-        ck_and_add!(6, 6, ErrorCode::SocLowLevel1);
-        ck_and_add!(6, 7, ErrorCode::SocLowLevel2);
-
-        ck_and_add!(7, 0, ErrorCode::DiffVoltLevel1);
-        ck_and_add!(7, 1, ErrorCode::DiffVoltLevel2);
-        ck_and_add!(7, 2, ErrorCode::DiffTempLevel1);
-        ck_and_add!(7, 3, ErrorCode::DiffTempLevel2);
-
-        ck_and_add!(8, 0, ErrorCode::ChargeMosTempHighAlarm);
-        ck_and_add!(8, 1, ErrorCode::DischargeMosTempHighAlarm);
-        ck_and_add!(8, 2, ErrorCode::ChargeMosTempSensorErr);
-        ck_and_add!(8, 3, ErrorCode::DischargeMosTempSensorErr);
-        ck_and_add!(8, 4, ErrorCode::ChargeMosAdhesionErr);
-        ck_and_add!(8, 5, ErrorCode::DischargeMosAdhesionErr);
-        ck_and_add!(8, 6, ErrorCode::ChargeMosOpenCircuitErr);
-        ck_and_add!(8, 7, ErrorCode::DischargeMosOpenCircuitErr);
-
-        ck_and_add!(9, 0, ErrorCode::AfeCollectChipErr);
-        ck_and_add!(9, 1, ErrorCode::VoltageCollectDropped);
-        ck_and_add!(9, 2, ErrorCode::CellTempSensorErr);
-        ck_and_add!(9, 3, ErrorCode::EepromErr);
-        ck_and_add!(9, 4, ErrorCode::RtcErr);
-        ck_and_add!(9, 5, ErrorCode::PrechangeFailure);
-        ck_and_add!(9, 6, ErrorCode::CommunicationFailure);
-        ck_and_add!(9, 7, ErrorCode::InternalCommunicationFailure);
-
-        ck_and_add!(10, 0, ErrorCode::CurrentModuleFault);
-        ck_and_add!(10, 1, ErrorCode::SumVoltageDetectFault);
-        ck_and_add!(10, 2, ErrorCode::ShortCircuitProtectFault);
-        ck_and_add!(10, 3, ErrorCode::LowVoltForbiddenChargeFault);
-
         Ok(result)
     }
 }
@@ -639,6 +1137,147 @@ impl fmt::Display for ErrorCode {
     }
 }
 
+/// Lifetime charge and discharge counters, only provided by newer firmwares
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct CumulativeCapacity {
+    pub charge_ah: f32,
+    pub discharge_ah: f32,
+}
+
+impl CumulativeCapacity {
+    pub fn request(address: Address) -> Vec<u8> {
+        let mut tx_buffer = create_request_header(address, 0x52);
+        calc_crc_and_set(&mut tx_buffer);
+        tx_buffer
+    }
+
+    pub fn reply_size() -> usize {
+        RX_BUFFER_LENGTH
+    }
+
+    pub fn decode(rx_buffer: &[u8]) -> std::result::Result<Self, Error> {
+        validate_len(rx_buffer, Self::reply_size())?;
+        validate_checksum(rx_buffer)?;
+        Ok(Self {
+            charge_ah: u32::from_be_bytes([rx_buffer[4], rx_buffer[5], rx_buffer[6], rx_buffer[7]])
+                as f32
+                / 10.0,
+            discharge_ah: u32::from_be_bytes([
+                rx_buffer[8],
+                rx_buffer[9],
+                rx_buffer[10],
+                rx_buffer[11],
+            ]) as f32
+                / 10.0,
+        })
+    }
+}
+
+pub struct BatteryCode;
+
+impl BatteryCode {
+    // the code is sent as 7 characters per frame, padded with spaces or zeros
+    const N_FRAMES: usize = 5;
+
+    pub fn request(address: Address) -> Vec<u8> {
+        let mut tx_buffer = create_request_header(address, 0x53);
+        calc_crc_and_set(&mut tx_buffer);
+        tx_buffer
+    }
+
+    pub fn reply_size() -> usize {
+        Self::N_FRAMES * RX_BUFFER_LENGTH
+    }
+
+    pub fn decode(rx_buffer: &[u8]) -> std::result::Result<String, Error> {
+        Self::decode_numbered(rx_buffer, FrameNumbering::OneBased)
+    }
+
+    pub fn decode_numbered(
+        rx_buffer: &[u8],
+        numbering: FrameNumbering,
+    ) -> std::result::Result<String, Error> {
+        decode_text(rx_buffer, Self::N_FRAMES, numbering)
+    }
+}
+
+// text replies carry the frame number followed by 7 characters in every frame
+fn decode_text(
+    rx_buffer: &[u8],
+    n_frames: usize,
+    numbering: FrameNumbering,
+) -> std::result::Result<String, Error> {
+    validate_len(rx_buffer, n_frames * RX_BUFFER_LENGTH)?;
+    let mut result = Vec::with_capacity(n_frames * 7);
+    for n_frame in 1..=n_frames {
+        let part = &rx_buffer[((n_frame - 1) * RX_BUFFER_LENGTH)..((n_frame) * RX_BUFFER_LENGTH)];
+        validate_frame_number(numbering, n_frame, part[4])?;
+        validate_checksum(part)?;
+        result.extend_from_slice(&part[5..12]);
+    }
+    Ok(String::from_utf8_lossy(&result)
+        .trim_end_matches(['\0', ' '])
+        .to_string())
+}
+
+pub struct SoftwareVersion;
+
+impl SoftwareVersion {
+    const N_FRAMES: usize = 2;
+
+    pub fn request(address: Address) -> Vec<u8> {
+        let mut tx_buffer = create_request_header(address, 0x62);
+        calc_crc_and_set(&mut tx_buffer);
+        tx_buffer
+    }
+
+    pub fn reply_size() -> usize {
+        Self::N_FRAMES * RX_BUFFER_LENGTH
+    }
+
+    pub fn decode(rx_buffer: &[u8]) -> std::result::Result<String, Error> {
+        Self::decode_numbered(rx_buffer, FrameNumbering::OneBased)
+    }
+
+    pub fn decode_numbered(
+        rx_buffer: &[u8],
+        numbering: FrameNumbering,
+    ) -> std::result::Result<String, Error> {
+        decode_text(rx_buffer, Self::N_FRAMES, numbering)
+    }
+}
+
+pub struct HardwareVersion;
+
+impl HardwareVersion {
+    const N_FRAMES: usize = 2;
+
+    pub fn request(address: Address) -> Vec<u8> {
+        let mut tx_buffer = create_request_header(address, 0x63);
+        calc_crc_and_set(&mut tx_buffer);
+        tx_buffer
+    }
+
+    pub fn reply_size() -> usize {
+        Self::N_FRAMES * RX_BUFFER_LENGTH
+    }
+
+    pub fn decode(rx_buffer: &[u8]) -> std::result::Result<String, Error> {
+        Self::decode_numbered(rx_buffer, FrameNumbering::OneBased)
+    }
+
+    pub fn decode_numbered(
+        rx_buffer: &[u8],
+        numbering: FrameNumbering,
+    ) -> std::result::Result<String, Error> {
+        decode_text(rx_buffer, Self::N_FRAMES, numbering)
+    }
+}
+
 pub struct SetDischargeMosfet;
 
 impl SetDischargeMosfet {
@@ -682,6 +1321,70 @@ impl SetChargeMosfet {
     }
 }
 
+/// Level one and level two over-current alarm thresholds (commands 0x5B/0x1B), stored in the
+/// EEPROM of the BMS, level two is the more severe alarm. The protocol has no runtime current
+/// limit, so dynamic derating, e.g. by temperature or SOC, isn't possible: every write wears
+/// the EEPROM
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct CurrentLimits {
+    // all values in ampere, charge limits are given as positive values
+    pub charge_level1: f32,
+    pub charge_level2: f32,
+    pub discharge_level1: f32,
+    pub discharge_level2: f32,
+}
+
+impl CurrentLimits {
+    pub fn request(address: Address) -> Vec<u8> {
+        let mut tx_buffer = create_request_header(address, 0x5B);
+        calc_crc_and_set(&mut tx_buffer);
+        tx_buffer
+    }
+
+    pub fn reply_size() -> usize {
+        RX_BUFFER_LENGTH
+    }
+
+    pub fn decode(rx_buffer: &[u8]) -> std::result::Result<Self, Error> {
+        validate_len(rx_buffer, Self::reply_size())?;
+        validate_checksum(rx_buffer)?;
+        // charging is a negative current, see Soc
+        Ok(Self {
+            charge_level1: -decode_current(rx_buffer[4], rx_buffer[5]),
+            charge_level2: -decode_current(rx_buffer[6], rx_buffer[7]),
+            discharge_level1: decode_current(rx_buffer[8], rx_buffer[9]),
+            discharge_level2: decode_current(rx_buffer[10], rx_buffer[11]),
+        })
+    }
+}
+
+pub struct SetCurrentLimits;
+
+impl SetCurrentLimits {
+    pub fn request(address: Address, limits: &CurrentLimits) -> Vec<u8> {
+        let mut tx_buffer = create_request_header(address, 0x1B);
+        tx_buffer[4..6].copy_from_slice(&encode_current(-limits.charge_level1.abs()));
+        tx_buffer[6..8].copy_from_slice(&encode_current(-limits.charge_level2.abs()));
+        tx_buffer[8..10].copy_from_slice(&encode_current(limits.discharge_level1.abs()));
+        tx_buffer[10..12].copy_from_slice(&encode_current(limits.discharge_level2.abs()));
+        calc_crc_and_set(&mut tx_buffer);
+        tx_buffer
+    }
+
+    pub fn reply_size() -> usize {
+        RX_BUFFER_LENGTH
+    }
+
+    pub fn decode(rx_buffer: &[u8]) -> std::result::Result<(), Error> {
+        validate_len(rx_buffer, Self::reply_size())?;
+        validate_checksum(rx_buffer)
+    }
+}
+
 pub struct SetSoc;
 
 impl SetSoc {
@@ -713,9 +1416,13 @@ impl SetSoc {
         validate_checksum(rx_buffer)
     }
 }
-pub struct BmsReset;
+/// Restores the factory parameters, there is no documented command for a soft restart
+pub struct FactoryReset;
+
+#[deprecated(note = "renamed to `FactoryReset`")]
+pub type BmsReset = FactoryReset;
 
-impl BmsReset {
+impl FactoryReset {
     pub fn request(address: Address) -> Vec<u8> {
         let mut tx_buffer = create_request_header(address, 0x00);
         calc_crc_and_set(&mut tx_buffer);
@@ -731,3 +1438,193 @@ impl BmsReset {
         validate_checksum(rx_buffer)
     }
 }
+
+/// Undecoded frame of a command not modelled by this crate, e.g. for reverse-engineering
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RawFrame {
+    pub command: u8,
+    pub data: [u8; 8],
+}
+
+impl RawFrame {
+    pub fn request(address: Address, command: u8, data: [u8; 8]) -> Vec<u8> {
+        let mut tx_buffer = create_request_header(address, command);
+        tx_buffer[4..12].copy_from_slice(&data);
+        calc_crc_and_set(&mut tx_buffer);
+        tx_buffer
+    }
+
+    pub fn reply_size() -> usize {
+        RX_BUFFER_LENGTH
+    }
+
+    pub fn decode(rx_buffer: &[u8]) -> std::result::Result<Self, Error> {
+        validate_len(rx_buffer, Self::reply_size())?;
+        validate_checksum(rx_buffer)?;
+        let mut data = [0; 8];
+        data.copy_from_slice(&rx_buffer[4..12]);
+        Ok(Self {
+            command: rx_buffer[2],
+            data,
+        })
+    }
+}
+
+impl fmt::Display for RawFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02X}:", self.command)?;
+        for byte in self.data {
+            write!(f, " {:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // reply frame of the BMS (address 0x01) with a valid checksum
+    fn reply(command: u8, data: [u8; 8]) -> Vec<u8> {
+        let mut frame = vec![START_BYTE, 0x01, command, DATA_LENGTH];
+        frame.extend_from_slice(&data);
+        frame.push(0);
+        calc_crc_and_set(&mut frame);
+        frame
+    }
+
+    fn text_reply(command: u8, text: &[u8], n_frames: usize) -> Vec<u8> {
+        let mut padded = text.to_vec();
+        padded.resize(n_frames * 7, b' ');
+        padded
+            .chunks(7)
+            .enumerate()
+            .flat_map(|(n, chunk)| {
+                let mut data = [n as u8 + 1, 0, 0, 0, 0, 0, 0, 0];
+                data[1..].copy_from_slice(chunk);
+                reply(command, data)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn current_encoding() {
+        assert_eq!(encode_current(0.0), 30000u16.to_be_bytes());
+        assert_eq!(encode_current(-12.3), 29877u16.to_be_bytes());
+        let [high, low] = encode_current(150.5);
+        assert_eq!(decode_current(high, low), 150.5);
+        // out of range values are clamped instead of wrapping around
+        assert_eq!(encode_current(-5000.0), [0, 0]);
+    }
+
+    #[test]
+    fn current_limits_layout() {
+        let limits = CurrentLimits {
+            charge_level1: 100.0,
+            charge_level2: 110.0,
+            discharge_level1: 150.0,
+            discharge_level2: 160.0,
+        };
+        let request = SetCurrentLimits::request(Address::Host, &limits);
+        assert_eq!(&request[..4], &[START_BYTE, 0x40, 0x1B, DATA_LENGTH]);
+        // charge thresholds are sent as negative currents
+        assert_eq!(&request[4..6], &29000u16.to_be_bytes());
+        assert_eq!(&request[6..8], &28900u16.to_be_bytes());
+        assert_eq!(&request[8..10], &31500u16.to_be_bytes());
+        assert_eq!(&request[10..12], &31600u16.to_be_bytes());
+        assert_eq!(request[12], calc_crc(&request));
+
+        // the BMS replies with the layout of the request
+        let mut data = [0; 8];
+        data.copy_from_slice(&request[4..12]);
+        let decoded = CurrentLimits::decode(&reply(0x5B, data)).unwrap();
+        assert_eq!(decoded.charge_level1, 100.0);
+        assert_eq!(decoded.charge_level2, 110.0);
+        assert_eq!(decoded.discharge_level1, 150.0);
+        assert_eq!(decoded.discharge_level2, 160.0);
+    }
+
+    #[test]
+    fn charge_limits_sign_is_ignored() {
+        let limits = CurrentLimits {
+            charge_level1: -100.0,
+            charge_level2: 110.0,
+            discharge_level1: -150.0,
+            discharge_level2: 160.0,
+        };
+        let request = SetCurrentLimits::request(Address::Host, &limits);
+        assert_eq!(&request[4..6], &29000u16.to_be_bytes());
+        assert_eq!(&request[8..10], &31500u16.to_be_bytes());
+    }
+
+    #[test]
+    fn cumulative_capacity() {
+        let mut data = [0; 8];
+        data[..4].copy_from_slice(&123_456u32.to_be_bytes());
+        data[4..].copy_from_slice(&98_765u32.to_be_bytes());
+        let capacity = CumulativeCapacity::decode(&reply(0x52, data)).unwrap();
+        assert_eq!(capacity.charge_ah, 12_345.6);
+        assert_eq!(capacity.discharge_ah, 9_876.5);
+
+        let mut corrupted = reply(0x52, data);
+        corrupted[12] ^= 0xFF;
+        assert!(matches!(
+            CumulativeCapacity::decode(&corrupted),
+            Err(Error::CheckSumError)
+        ));
+    }
+
+    #[test]
+    fn battery_code() {
+        let rx_buffer = text_reply(0x53, b"DL-16S-100A-0042", 5);
+        assert_eq!(BatteryCode::decode(&rx_buffer).unwrap(), "DL-16S-100A-0042");
+    }
+
+    #[test]
+    fn text_padded_with_zeros() {
+        let mut rx_buffer = text_reply(0x62, b"V1.2", 2);
+        for frame in rx_buffer.chunks_mut(RX_BUFFER_LENGTH) {
+            for byte in &mut frame[5..12] {
+                if *byte == b' ' {
+                    *byte = 0;
+                }
+            }
+            calc_crc_and_set(frame);
+        }
+        assert_eq!(SoftwareVersion::decode(&rx_buffer).unwrap(), "V1.2");
+    }
+
+    #[test]
+    fn text_frame_numbering() {
+        let mut rx_buffer = text_reply(0x63, b"HW-2.0", 2);
+        for frame in rx_buffer.chunks_mut(RX_BUFFER_LENGTH) {
+            frame[4] -= 1;
+            calc_crc_and_set(frame);
+        }
+        assert!(matches!(
+            HardwareVersion::decode(&rx_buffer),
+            Err(Error::FrameNoError)
+        ));
+        assert_eq!(
+            HardwareVersion::decode_numbered(&rx_buffer, FrameNumbering::ZeroBased).unwrap(),
+            "HW-2.0"
+        );
+    }
+
+    #[test]
+    fn unsupported_replies() {
+        assert!(validate_supported(0x52, &reply(0x52, [1; 8])).is_ok());
+        assert!(matches!(
+            validate_supported(0x52, &[0; RX_BUFFER_LENGTH]),
+            Err(Error::Unsupported(0x52))
+        ));
+        // an echo of the request carries the host address
+        assert!(matches!(
+            validate_supported(0x52, &CumulativeCapacity::request(Address::Host)),
+            Err(Error::Unsupported(0x52))
+        ));
+    }
+}
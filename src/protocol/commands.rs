@@ -0,0 +1,273 @@
+//! Registry of the command IDs known from the Daly UART protocol documents and field reports.
+//! The BMS replies with the same command ID, so one entry describes request and reply.
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Which firmwares are known to answer a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Support {
+    /// Answered by all known firmwares
+    All,
+    /// Answered by some firmwares only, e.g. newer boards or the smart BMS line
+    Some,
+    /// Documented but not confirmed on real hardware
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CommandInfo {
+    pub id: u8,
+    pub name: &'static str,
+    pub access: Access,
+    /// Number of reply frames, `None` if it depends on the number of cells or sensors
+    pub reply_frames: Option<u8>,
+    pub support: Support,
+    /// Whether this crate implements the command
+    pub implemented: bool,
+}
+
+const fn command(
+    id: u8,
+    name: &'static str,
+    access: Access,
+    reply_frames: Option<u8>,
+    support: Support,
+    implemented: bool,
+) -> CommandInfo {
+    CommandInfo {
+        id,
+        name,
+        access,
+        reply_frames,
+        support,
+        implemented,
+    }
+}
+
+/// All known commands ordered by ID
+pub const ALL: &[CommandInfo] = &[
+    command(
+        0x00,
+        "factory reset",
+        Access::Write,
+        Some(1),
+        Support::Some,
+        true,
+    ),
+    command(
+        0x1B,
+        "set current limits",
+        Access::Write,
+        Some(1),
+        Support::Some,
+        true,
+    ),
+    command(0x21, "set SOC", Access::Write, Some(1), Support::All, true),
+    command(
+        0x50,
+        "rated capacity",
+        Access::Read,
+        Some(1),
+        Support::Unknown,
+        false,
+    ),
+    command(
+        0x51,
+        "board configuration",
+        Access::Read,
+        Some(1),
+        Support::Unknown,
+        false,
+    ),
+    command(
+        0x52,
+        "cumulative capacity",
+        Access::Read,
+        Some(1),
+        Support::Some,
+        true,
+    ),
+    command(
+        0x53,
+        "battery code",
+        Access::Read,
+        Some(5),
+        Support::Some,
+        true,
+    ),
+    command(
+        0x59,
+        "cell voltage thresholds",
+        Access::Read,
+        Some(1),
+        Support::Unknown,
+        false,
+    ),
+    command(
+        0x5A,
+        "pack voltage thresholds",
+        Access::Read,
+        Some(1),
+        Support::Unknown,
+        false,
+    ),
+    command(
+        0x5B,
+        "current limits",
+        Access::Read,
+        Some(1),
+        Support::Some,
+        true,
+    ),
+    command(
+        0x5E,
+        "temperature thresholds",
+        Access::Read,
+        Some(1),
+        Support::Unknown,
+        false,
+    ),
+    command(
+        0x5F,
+        "balance thresholds",
+        Access::Read,
+        Some(1),
+        Support::Unknown,
+        false,
+    ),
+    command(
+        0x60,
+        "short circuit thresholds",
+        Access::Read,
+        Some(1),
+        Support::Unknown,
+        false,
+    ),
+    command(
+        0x62,
+        "software version",
+        Access::Read,
+        Some(2),
+        Support::Some,
+        true,
+    ),
+    command(
+        0x63,
+        "hardware version",
+        Access::Read,
+        Some(2),
+        Support::Some,
+        true,
+    ),
+    command(0x90, "SOC", Access::Read, Some(1), Support::All, true),
+    command(
+        0x91,
+        "voltage range",
+        Access::Read,
+        Some(1),
+        Support::All,
+        true,
+    ),
+    command(
+        0x92,
+        "temperature range",
+        Access::Read,
+        Some(1),
+        Support::All,
+        true,
+    ),
+    command(
+        0x93,
+        "mosfet status",
+        Access::Read,
+        Some(1),
+        Support::All,
+        true,
+    ),
+    command(0x94, "status", Access::Read, Some(1), Support::All, true),
+    command(
+        0x95,
+        "cell voltages",
+        Access::Read,
+        None,
+        Support::All,
+        true,
+    ),
+    command(
+        0x96,
+        "cell temperatures",
+        Access::Read,
+        None,
+        Support::All,
+        true,
+    ),
+    command(
+        0x97,
+        "balancing status",
+        Access::Read,
+        Some(1),
+        Support::All,
+        true,
+    ),
+    command(0x98, "errors", Access::Read, Some(1), Support::All, true),
+    command(
+        0xD9,
+        "set discharge mosfet",
+        Access::Write,
+        Some(1),
+        Support::All,
+        true,
+    ),
+    command(
+        0xDA,
+        "set charge mosfet",
+        Access::Write,
+        Some(1),
+        Support::All,
+        true,
+    ),
+];
+
+pub fn find(id: u8) -> Option<&'static CommandInfo> {
+    ALL.iter().find(|info| info.id == id)
+}
+
+impl fmt::Display for CommandInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02X} {}", self.id, self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sorted_with_unique_ids() {
+        for pair in ALL.windows(2) {
+            assert!(pair[0].id < pair[1].id, "{} before {}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn find_by_id() {
+        for info in ALL {
+            assert_eq!(find(info.id).unwrap().name, info.name);
+        }
+        assert_eq!(find(0x90).unwrap().access, Access::Read);
+        assert!(find(0xFF).is_none());
+    }
+
+    #[test]
+    fn implemented_requests_are_known() {
+        for id in 0x90..=0x98 {
+            assert!(find(id).is_some_and(|info| info.implemented), "{:02X}", id);
+        }
+    }
+}
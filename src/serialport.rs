@@ -1,29 +1,92 @@
+use crate::connection::ConnectionEvent;
+use crate::device::DeviceInfo;
+use crate::error::unsupported_as_none;
+use crate::middleware::FrameMiddleware;
+use crate::parameters::Parameters;
 use crate::protocol::*;
+use crate::snapshot::{BmsSnapshot, Metric, MissedTicks, ReadTime};
 use anyhow::{bail, Context, Result};
-use std::time::{Duration, Instant};
+use std::{
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant, SystemTime},
+};
+
+const WRITE_ATTEMPTS: u32 = 3;
+// IO timeouts to wait for the end of stale replies, a bus which is never quiet is an error
+const STALE_REPLY_TIMEOUTS: u32 = 4;
 
 #[derive(Debug)]
 pub struct DalyBMS {
     serial: Box<dyn serialport::SerialPort>,
     last_execution: Instant,
+    last_command: u8,
     delay: Duration,
     status: Option<Status>,
+    status_limits: StatusLimits,
+    plausibility_limits: Option<PlausibilityLimits>,
+    implausible_readings: u64,
+    quirks: QuirkProfile,
+    mosfet_interlock: bool,
+    read_only: bool,
+    verify_writes: bool,
+    decode_mode: DecodeMode,
+    device_info: Option<DeviceInfo>,
+    connected: bool,
+    operation_timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    event_senders: Vec<std::sync::mpsc::Sender<ConnectionEvent>>,
+    middlewares: Vec<Box<dyn FrameMiddleware>>,
+    firmware_quirks: Vec<FirmwareQuirks>,
+    pipelining: bool,
+    missed_ticks: MissedTicks,
+    // reply of the next command, received by a pipeline of `get_snapshot()`
+    prefetched: Option<Vec<u8>>,
 }
 
 impl DalyBMS {
     pub fn new(port: &str) -> Result<Self> {
-        Ok(Self {
-            serial: serialport::new(port, 9600)
-                .data_bits(serialport::DataBits::Eight)
-                .parity(serialport::Parity::None)
-                .stop_bits(serialport::StopBits::One)
-                .flow_control(serialport::FlowControl::None)
-                .open()
-                .with_context(|| format!("Cannot open serial port '{}'", port))?,
+        Self::with_baud_rate(port, 9600)
+    }
+
+    /// Opens the port with a non standard baud rate, the Daly protocol specifies 9600
+    pub fn with_baud_rate(port: &str, baud_rate: u32) -> Result<Self> {
+        let serial = serialport::new(port, baud_rate)
+            .data_bits(serialport::DataBits::Eight)
+            .parity(serialport::Parity::None)
+            .stop_bits(serialport::StopBits::One)
+            .flow_control(serialport::FlowControl::None)
+            .open()
+            .with_context(|| format!("Cannot open serial port '{}'", port))?;
+        Ok(Self::from_port(serial))
+    }
+
+    /// Uses an already opened port, e.g. a `crate::simulator::SimulatorPort`
+    pub fn from_port(serial: Box<dyn serialport::SerialPort>) -> Self {
+        Self {
+            serial,
             last_execution: Instant::now(),
+            last_command: 0,
             delay: MINIMUM_DELAY,
             status: None,
-        })
+            status_limits: StatusLimits::default(),
+            plausibility_limits: None,
+            implausible_readings: 0,
+            quirks: QuirkProfile::default(),
+            mosfet_interlock: false,
+            read_only: false,
+            verify_writes: false,
+            decode_mode: DecodeMode::Strict,
+            device_info: None,
+            connected: false,
+            operation_timeout: None,
+            deadline: None,
+            event_senders: Vec::new(),
+            middlewares: Vec::new(),
+            firmware_quirks: Vec::new(),
+            pipelining: false,
+            missed_ticks: MissedTicks::default(),
+            prefetched: None,
+        }
     }
 
     fn serial_await_delay(&self) {
@@ -34,6 +97,31 @@ impl DalyBMS {
     }
 
     fn send_bytes(&mut self, tx_buffer: &[u8]) -> Result<()> {
+        if !self.quirks.is_supported(tx_buffer[2]) {
+            log::debug!(
+                "Command {:02X} not sent, unsupported by the quirk profile",
+                tx_buffer[2]
+            );
+            return Err(crate::Error::Unsupported(tx_buffer[2]).into());
+        }
+        if self
+            .prefetched
+            .as_ref()
+            .is_some_and(|reply| reply[2] == tx_buffer[2])
+        {
+            log::trace!(
+                "Command {:02X} not sent, its reply was received by a pipeline",
+                tx_buffer[2]
+            );
+            self.last_command = tx_buffer[2];
+            return Ok(());
+        }
+        self.clear_pending()?;
+        self.serial_await_delay();
+        self.write_frame(tx_buffer)
+    }
+
+    fn clear_pending(&mut self) -> Result<()> {
         // clear all incoming serial to avoid data collision
         loop {
             let pending = self
@@ -52,11 +140,23 @@ impl DalyBMS {
                 break;
             }
         }
-        self.serial_await_delay();
+        Ok(())
+    }
 
-        self.serial
-            .write_all(tx_buffer)
-            .with_context(|| "Cannot write to serial")?;
+    fn write_frame(&mut self, tx_buffer: &[u8]) -> Result<()> {
+        let mut tx_buffer = tx_buffer.to_vec();
+        for middleware in &mut self.middlewares {
+            middleware.on_send(&mut tx_buffer);
+        }
+        self.last_command = tx_buffer[2];
+        let written = self
+            .serial
+            .write_all(&tx_buffer)
+            .with_context(|| "Cannot write to serial");
+        if let Err(err) = &written {
+            self.update_connection(Some(err));
+        }
+        written?;
 
         if false {
             self.serial
@@ -66,21 +166,270 @@ impl DalyBMS {
         Ok(())
     }
 
+    // returns the replies of the pipelined metrics, empty if pipelining isn't possible or failed
+    fn prefetch(&mut self, metrics: &[Metric]) -> Result<Vec<Vec<u8>>> {
+        let status_required = self.status.is_none() && metrics.iter().any(Metric::requires_status);
+        let mut requests: Vec<(Vec<u8>, usize)> = Vec::new();
+        for metric in status_required
+            .then_some(&Metric::Status)
+            .into_iter()
+            .chain(metrics)
+        {
+            if let Some((request, reply_size)) = metric.pipelined_request() {
+                if self.quirks.is_supported(request[2])
+                    && !requests.iter().any(|(other, _)| other[2] == request[2])
+                {
+                    requests.push((request, reply_size));
+                }
+            }
+        }
+        if requests.len() < 2 {
+            return Ok(Vec::new());
+        }
+        match self.transfer_pipelined(&requests) {
+            Ok(replies) => Ok(replies),
+            Err(err) => {
+                log::info!(
+                    "Pipelined requests failed, reading the metrics one by one: {:#}",
+                    err
+                );
+                // replies to the other requests may still be in flight
+                self.discard_until_quiet()?;
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    // discards everything received until no byte arrived for the IO timeout
+    fn discard_until_quiet(&mut self) -> Result<()> {
+        let quiet = self.serial.timeout();
+        let give_up = Instant::now() + quiet * STALE_REPLY_TIMEOUTS;
+        let mut last_byte = Instant::now();
+        while last_byte.elapsed() < quiet {
+            self.remaining()?;
+            if Instant::now() >= give_up {
+                bail!(
+                    "Bus not quiet within {:?} after failed requests",
+                    quiet * STALE_REPLY_TIMEOUTS
+                );
+            }
+            std::thread::sleep(self.delay.max(MINIMUM_DELAY));
+            let pending = self
+                .serial
+                .bytes_to_read()
+                .with_context(|| "Cannot read number of pending bytes")?;
+            if pending > 0 {
+                log::trace!("Discard {} stale bytes", pending);
+                self.serial
+                    .clear(serialport::ClearBuffer::Input)
+                    .with_context(|| "Cannot clear input buffer")?;
+                last_byte = Instant::now();
+            }
+        }
+        Ok(())
+    }
+
+    fn transfer_pipelined(&mut self, requests: &[(Vec<u8>, usize)]) -> Result<Vec<Vec<u8>>> {
+        self.clear_pending()?;
+        self.serial_await_delay();
+        for (request, _) in requests {
+            self.write_frame(request)?;
+        }
+        let mut replies = Vec::new();
+        for (request, reply_size) in requests {
+            self.last_command = request[2];
+            replies.push(self.receive_bytes(*reply_size)?);
+        }
+        Ok(replies)
+    }
+
+    // hands the pipelined reply of the metric to its next command
+    fn use_prefetched(&mut self, replies: &mut Vec<Vec<u8>>, metric: Metric) {
+        self.prefetched = metric.pipelined_request().and_then(|(request, _)| {
+            let index = replies.iter().position(|reply| reply[2] == request[2])?;
+            Some(replies.remove(index))
+        });
+    }
+
+    // shortens the IO timeout to the time left of the current operation while reading
+    fn read_within_operation(&mut self, buffer: &mut [u8]) -> Result<()> {
+        let io_timeout = self.serial.timeout();
+        let remaining = self
+            .remaining()?
+            .filter(|remaining| *remaining < io_timeout);
+        if let Some(remaining) = remaining {
+            self.serial.set_timeout(remaining)?;
+        }
+        let received = self
+            .serial
+            .read_exact(buffer)
+            .with_context(|| "Cannot receive response");
+        if remaining.is_some() {
+            self.serial.set_timeout(io_timeout)?;
+        }
+        received
+    }
+
     fn receive_bytes(&mut self, size: usize) -> Result<Vec<u8>> {
+        let last_command = self.last_command;
+        if let Some(reply) = self.prefetched.take_if(|reply| reply[2] == last_command) {
+            return Ok(reply);
+        }
+
         // Clear out the input buffer
         let mut rx_buffer = vec![0; size];
 
         // Read bytes from the specified serial interface
-        self.serial
-            .read_exact(&mut rx_buffer)
-            .with_context(|| "Cannot receive response")?;
+        let received = self.read_within_operation(&mut rx_buffer);
+        self.update_connection(received.as_ref().err());
+        received?;
 
         self.last_execution = Instant::now();
 
+        if let Some(offset) = find_frame_start(self.last_command, &rx_buffer)
+            .filter(|offset| *offset > 0 && self.decode_mode == DecodeMode::Lenient)
+        {
+            log::info!(
+                "Skipped {} bytes before the reply: {:02X?}",
+                offset,
+                &rx_buffer[..offset]
+            );
+            rx_buffer.drain(..offset);
+            let mut rest = vec![0; offset];
+            self.read_within_operation(&mut rest)?;
+            rx_buffer.extend(rest);
+        }
+
+        for middleware in &mut self.middlewares {
+            middleware.on_receive(&mut rx_buffer);
+        }
         log::trace!("receive_bytes: {:02X?}", rx_buffer);
+        validate_supported(self.last_command, &rx_buffer)?;
+        validate_frames(self.last_command, &rx_buffer, self.decode_mode)?;
         Ok(rx_buffer)
     }
 
+    fn emit(&mut self, event: ConnectionEvent) {
+        log::trace!("connection event: {:?}", event);
+        self.event_senders
+            .retain(|sender| sender.send(event.clone()).is_ok());
+    }
+
+    fn update_connection(&mut self, error: Option<&anyhow::Error>) {
+        match (self.connected, error) {
+            (false, None) => {
+                self.connected = true;
+                self.emit(ConnectionEvent::Connected);
+            }
+            (true, Some(err)) => {
+                self.connected = false;
+                self.emit(ConnectionEvent::Disconnected {
+                    error: format!("{:#}", err),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    // starts the deadline of an operation unless an enclosing operation already did, an
+    // expired deadline is left over from an operation which panicked
+    fn begin_operation(&mut self) -> bool {
+        if self
+            .deadline
+            .is_some_and(|deadline| deadline > Instant::now())
+        {
+            return false;
+        }
+        self.deadline = self
+            .operation_timeout
+            .map(|timeout| Instant::now() + timeout);
+        true
+    }
+
+    fn end_operation(&mut self, started: bool) {
+        if started {
+            self.deadline = None;
+        }
+    }
+
+    // time left of the current operation, fails once the deadline passed
+    fn remaining(&self) -> std::result::Result<Option<Duration>, crate::Error> {
+        let Some(deadline) = self.deadline else {
+            return Ok(None);
+        };
+        let now = Instant::now();
+        if now >= deadline {
+            log::warn!(
+                "Operation timeout of {:?} exceeded",
+                self.operation_timeout.unwrap_or_default()
+            );
+            return Err(crate::Error::Io(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "operation timeout exceeded",
+            )));
+        }
+        Ok(Some(deadline - now))
+    }
+
+    fn within_operation<T>(&mut self, operation: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        let started = self.begin_operation();
+        let result = operation(self);
+        self.end_operation(started);
+        result
+    }
+
+    // counts a failed write verification, returns the error after the last attempt
+    fn verification_failed(
+        &mut self,
+        attempt: &mut u32,
+        name: &'static str,
+        expected: f64,
+        received: f64,
+    ) -> std::result::Result<(), crate::Error> {
+        log::warn!(
+            "Write verification failed - name={} attempt={} expected={} received={}",
+            name,
+            attempt,
+            expected,
+            received
+        );
+        if *attempt >= WRITE_ATTEMPTS {
+            return Err(crate::Error::VerificationFailed {
+                name,
+                expected,
+                received,
+            });
+        }
+        self.remaining()?;
+        *attempt += 1;
+        self.emit(ConnectionEvent::Retry {
+            operation: name,
+            attempt: *attempt,
+        });
+        Ok(())
+    }
+
+    // returns true if the write command must not be sent because of the read only mode
+    fn skip_write(&self, tx_buffer: &[u8]) -> bool {
+        if self.read_only {
+            log::info!("read only mode, frame not sent: {:02X?}", tx_buffer);
+        }
+        self.read_only
+    }
+
+    fn check_plausibility(
+        &mut self,
+        check: impl FnOnce(&PlausibilityLimits) -> std::result::Result<(), crate::Error>,
+    ) -> Result<()> {
+        if let Some(limits) = &self.plausibility_limits {
+            if let Err(err) = check(limits) {
+                self.implausible_readings += 1;
+                return Err(err.into());
+            }
+        }
+        Ok(())
+    }
+
     /// Sets the timeout for I/O operations
     pub fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
         log::trace!("set timeout: {:?}", timeout);
@@ -104,23 +453,144 @@ impl DalyBMS {
         log::trace!("set delay: {:?}", self.delay);
     }
 
+    /// Accepted number of cells and temperature sensors, a status outside these limits is rejected
+    pub fn set_status_limits(&mut self, limits: StatusLimits) {
+        log::trace!("set status limits: {:?}", limits);
+        self.status_limits = limits;
+    }
+
+    /// Rejects physically impossible cell voltages and temperatures, disabled with `None` (default)
+    pub fn set_plausibility_limits(&mut self, limits: Option<PlausibilityLimits>) {
+        log::trace!("set plausibility limits: {:?}", limits);
+        self.plausibility_limits = limits;
+    }
+
+    /// Number of readings rejected by the plausibility limits
+    pub fn implausible_readings(&self) -> u64 {
+        self.implausible_readings
+    }
+
+    /// Correction applied to the current of `get_soc()`
+    pub fn set_current_calibration(&mut self, calibration: CurrentCalibration) {
+        log::trace!("set current calibration: {:?}", calibration);
+        self.quirks.current_calibration = calibration;
+    }
+
+    /// Applies the deviations of a non genuine board, this replaces the current calibration
+    pub fn set_quirk_profile(&mut self, quirks: QuirkProfile) {
+        log::trace!("set quirk profile: {:?}", quirks);
+        self.quirks = quirks;
+    }
+
+    /// Only log the frames of write commands instead of sending them to the BMS
+    pub fn set_read_only(&mut self, enable: bool) {
+        log::trace!("set read only: {:?}", enable);
+        self.read_only = enable;
+    }
+
+    /// Read the state back after writing the SOC or a mosfet and repeat the write if it did not
+    /// take effect, fails with `Error::VerificationFailed` after the last attempt
+    pub fn set_verify_writes(&mut self, enable: bool) {
+        log::trace!("set verify writes: {:?}", enable);
+        self.verify_writes = enable;
+    }
+
+    /// Adds a hook which can inspect or modify every request before it is sent and every reply
+    /// before it is decoded, see `crate::middleware::FrameMiddleware`
+    pub fn add_middleware(&mut self, middleware: impl FrameMiddleware + 'static) {
+        log::trace!("add middleware: {:?}", middleware);
+        self.middlewares.push(Box::new(middleware));
+    }
+
+    /// Sets the table of firmware versions with broken commands, empty by default. Commands of
+    /// the entry matching the software version are skipped after `identify()`
+    pub fn set_firmware_quirks(&mut self, table: Vec<FirmwareQuirks>) {
+        log::trace!("set firmware quirks: {:?}", table);
+        self.firmware_quirks = table;
+    }
+
+    /// Sends the requests of all metrics of `get_snapshot()` with a single frame reply back to
+    /// back and reads the replies afterwards, which saves the delay between the commands. Not every
+    /// firmware tolerates this, the metrics are read one by one if the pipeline fails
+    pub fn set_pipelining(&mut self, enable: bool) {
+        log::trace!("set pipelining: {}", enable);
+        self.pipelining = enable;
+    }
+
+    /// What `watch()` does when a polling cycle takes longer than the interval,
+    /// `MissedTicks::Delay` by default
+    pub fn set_missed_ticks(&mut self, missed_ticks: MissedTicks) {
+        log::trace!("set missed ticks: {:?}", missed_ticks);
+        self.missed_ticks = missed_ticks;
+    }
+
+    /// Lenient mode tolerates replies of boards which deviate from the protocol, see `DecodeMode`
+    pub fn set_decode_mode(&mut self, mode: DecodeMode) {
+        log::trace!("set decode mode: {:?}", mode);
+        self.decode_mode = mode;
+    }
+
+    /// Limits the total time of operations with several round trips, i.e. multi frame reads,
+    /// snapshots and verified writes including their retries. `None` only applies the IO
+    /// timeout to every single read.
+    pub fn set_operation_timeout(&mut self, timeout: Option<Duration>) {
+        log::trace!("set operation timeout: {:?}", timeout);
+        self.operation_timeout = timeout;
+    }
+
+    /// Receives the connection events of this client, see `ConnectionEvent`
+    pub fn subscribe_events(&mut self) -> std::sync::mpsc::Receiver<ConnectionEvent> {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        self.event_senders.push(sender);
+        receiver
+    }
+
+    /// Refuse to enable the charge mosfet while a blocking error is active, see `ErrorCode::blocks_charging()`
+    pub fn set_mosfet_interlock(&mut self, enable: bool) {
+        log::trace!("set mosfet interlock: {:?}", enable);
+        self.mosfet_interlock = enable;
+    }
+
+    /// Health check with the cheapest command, returns the round trip time without the delay
+    /// between the commands
+    pub fn ping(&mut self) -> Result<Duration> {
+        self.clear_pending()?;
+        self.serial_await_delay();
+        let start = Instant::now();
+        self.write_frame(&Soc::request(Address::Host))?;
+        Soc::decode(&self.receive_bytes(Soc::reply_size())?)?;
+        Ok(start.elapsed())
+    }
+
     pub fn get_soc(&mut self) -> Result<Soc> {
         self.send_bytes(&Soc::request(Address::Host))?;
-        Ok(Soc::decode(&self.receive_bytes(Soc::reply_size())?)?)
+        let mut soc = Soc::decode(&self.receive_bytes(Soc::reply_size())?)?;
+        soc.current = self.quirks.current_calibration.apply(soc.current);
+        Ok(soc)
     }
 
     pub fn get_cell_voltage_range(&mut self) -> Result<CellVoltageRange> {
         self.send_bytes(&CellVoltageRange::request(Address::Host))?;
-        Ok(CellVoltageRange::decode(
-            &self.receive_bytes(CellVoltageRange::reply_size())?,
-        )?)
+        let range = CellVoltageRange::decode(&self.receive_bytes(CellVoltageRange::reply_size())?)?;
+        self.check_plausibility(|limits| {
+            limits.check_cell_voltages([range.highest_voltage, range.lowest_voltage])
+        })?;
+        Ok(range)
     }
 
     pub fn get_temperature_range(&mut self) -> Result<TemperatureRange> {
         self.send_bytes(&TemperatureRange::request(Address::Host))?;
-        Ok(TemperatureRange::decode(
-            &self.receive_bytes(TemperatureRange::reply_size())?,
-        )?)
+        let mut range =
+            TemperatureRange::decode(&self.receive_bytes(TemperatureRange::reply_size())?)?;
+        range.highest_temperature = self.quirks.apply_temperature(range.highest_temperature);
+        range.lowest_temperature = self.quirks.apply_temperature(range.lowest_temperature);
+        self.check_plausibility(|limits| {
+            limits.check_temperatures([
+                range.highest_temperature.into(),
+                range.lowest_temperature.into(),
+            ])
+        })?;
+        Ok(range)
     }
 
     pub fn get_mosfet_status(&mut self) -> Result<MosfetStatus> {
@@ -133,35 +603,49 @@ impl DalyBMS {
     pub fn get_status(&mut self) -> Result<Status> {
         self.send_bytes(&Status::request(Address::Host))?;
         let status = Status::decode(&self.receive_bytes(Status::reply_size())?)?;
+        status.validate(&self.status_limits)?;
         self.status = Some(status.clone());
         Ok(status)
     }
 
     pub fn get_cell_voltages(&mut self) -> Result<Vec<f32>> {
-        let n_cells = if let Some(status) = &self.status {
-            status.cells
-        } else {
-            bail!("get_status() has to be called at least once before calling get_cell_voltages()");
-        };
-        self.send_bytes(&CellVoltages::request(Address::Host))?;
-        Ok(CellVoltages::decode(
-            &self.receive_bytes(CellVoltages::reply_size(n_cells))?,
-            n_cells,
-        )?)
+        self.within_operation(|bms| {
+            let n_cells = if let Some(status) = &bms.status {
+                status.cells
+            } else {
+                bail!("get_status() has to be called at least once before calling get_cell_voltages()");
+            };
+            bms.send_bytes(&CellVoltages::request(Address::Host))?;
+            let voltages = CellVoltages::decode_numbered(
+                &bms.receive_bytes(CellVoltages::reply_size(n_cells))?,
+                n_cells,
+                bms.quirks.frame_numbering,
+            )?;
+            bms.check_plausibility(|limits| limits.check_cell_voltages(voltages.iter().copied()))?;
+            Ok(voltages)
+        })
     }
 
     pub fn get_cell_temperatures(&mut self) -> Result<Vec<i32>> {
-        let n_sensors = if let Some(status) = &self.status {
-            status.temperature_sensors
-        } else {
-            bail!("get_status() has to be called at least once before calling get_cell_temperatures()");
-        };
+        self.within_operation(|bms| {
+            let n_sensors = if let Some(status) = &bms.status {
+                status.temperature_sensors
+            } else {
+                bail!("get_status() has to be called at least once before calling get_cell_temperatures()");
+            };
 
-        self.send_bytes(&CellTemperatures::request(Address::Host))?;
-        Ok(CellTemperatures::decode(
-            &self.receive_bytes(CellTemperatures::reply_size(n_sensors))?,
-            n_sensors,
-        )?)
+            bms.send_bytes(&CellTemperatures::request(Address::Host))?;
+            let mut temperatures = CellTemperatures::decode_numbered(
+                &bms.receive_bytes(CellTemperatures::reply_size(n_sensors))?,
+                n_sensors,
+                bms.quirks.frame_numbering,
+            )?;
+            for temperature in temperatures.iter_mut() {
+                *temperature += i32::from(bms.quirks.temperature_offset);
+            }
+            bms.check_plausibility(|limits| limits.check_temperatures(temperatures.iter().copied()))?;
+            Ok(temperatures)
+        })
     }
 
     pub fn get_balancing_status(&mut self) -> Result<Vec<bool>> {
@@ -187,29 +671,464 @@ impl DalyBMS {
         )?)
     }
 
+    /// Reads the given metrics, the status is read first if required by another metric
+    pub fn get_snapshot(&mut self, metrics: &[Metric]) -> Result<BmsSnapshot> {
+        let cycle_start = Instant::now();
+        let snapshot = self.within_operation(|bms| {
+            let mut snapshot = BmsSnapshot::default();
+            let mut replies = if bms.pipelining {
+                bms.prefetch(metrics)?
+            } else {
+                Vec::new()
+            };
+            if metrics.contains(&Metric::Status)
+                || (bms.status.is_none() && metrics.iter().any(Metric::requires_status))
+            {
+                let at = SystemTime::now();
+                let start = Instant::now();
+                bms.use_prefetched(&mut replies, Metric::Status);
+                let status = bms.get_status()?;
+                if metrics.contains(&Metric::Status) {
+                    snapshot.status = Some(status);
+                    snapshot.read_times.insert(
+                        Metric::Status,
+                        ReadTime {
+                            at,
+                            latency: start.elapsed(),
+                        },
+                    );
+                }
+            }
+            for metric in metrics {
+                let at = SystemTime::now();
+                let start = Instant::now();
+                bms.use_prefetched(&mut replies, *metric);
+                match metric {
+                    Metric::Status => continue,
+                    Metric::Soc => snapshot.soc = Some(bms.get_soc()?),
+                    Metric::Mosfet => snapshot.mosfet_status = Some(bms.get_mosfet_status()?),
+                    Metric::VoltageRange => {
+                        snapshot.cell_voltage_range = Some(bms.get_cell_voltage_range()?)
+                    }
+                    Metric::TemperatureRange => {
+                        snapshot.temperature_range = Some(bms.get_temperature_range()?)
+                    }
+                    Metric::CellVoltages => snapshot.cell_voltages = Some(bms.get_cell_voltages()?),
+                    Metric::CellTemperatures => {
+                        snapshot.cell_temperatures = Some(bms.get_cell_temperatures()?)
+                    }
+                    Metric::Balancing => {
+                        snapshot.balancing_status = Some(bms.get_balancing_status()?)
+                    }
+                    Metric::Errors => snapshot.errors = Some(bms.get_errors()?),
+                }
+                snapshot.read_times.insert(
+                    *metric,
+                    ReadTime {
+                        at,
+                        latency: start.elapsed(),
+                    },
+                );
+            }
+            snapshot.duration = Some(cycle_start.elapsed());
+            Ok(snapshot)
+        });
+        // a reply left over by a failed command must not be used later
+        self.prefetched = None;
+        snapshot
+    }
+
+    /// Only writes the discharge mosfet if its state differs, returns `true` if it was written
+    pub fn ensure_discharge_mosfet(&mut self, enable: bool) -> Result<bool> {
+        if self.get_mosfet_status()?.discharging_mosfet == enable {
+            return Ok(false);
+        }
+        self.set_discharge_mosfet(enable)?;
+        Ok(true)
+    }
+
+    /// Only writes the charge mosfet if its state differs, returns `true` if it was written
+    pub fn ensure_charge_mosfet(&mut self, enable: bool) -> Result<bool> {
+        if self.get_mosfet_status()?.charging_mosfet == enable {
+            return Ok(false);
+        }
+        self.set_charge_mosfet(enable)?;
+        Ok(true)
+    }
+
     pub fn set_discharge_mosfet(&mut self, enable: bool) -> Result<()> {
-        self.send_bytes(&SetDischargeMosfet::request(Address::Host, enable))?;
-        Ok(SetDischargeMosfet::decode(
-            &self.receive_bytes(SetDischargeMosfet::reply_size())?,
-        )?)
+        self.within_operation(|bms| {
+            let tx_buffer = SetDischargeMosfet::request(Address::Host, enable);
+            if bms.skip_write(&tx_buffer) {
+                return Ok(());
+            }
+            let mut attempt = 1;
+            loop {
+                bms.send_bytes(&tx_buffer)?;
+                SetDischargeMosfet::decode(&bms.receive_bytes(SetDischargeMosfet::reply_size())?)?;
+                if !bms.verify_writes {
+                    return Ok(());
+                }
+                let received = bms.get_mosfet_status()?.discharging_mosfet;
+                if received == enable {
+                    return Ok(());
+                }
+                bms.verification_failed(
+                    &mut attempt,
+                    "discharge mosfet",
+                    u8::from(enable).into(),
+                    u8::from(received).into(),
+                )?;
+            }
+        })
     }
 
     pub fn set_charge_mosfet(&mut self, enable: bool) -> Result<()> {
-        self.send_bytes(&SetChargeMosfet::request(Address::Host, enable))?;
-        Ok(SetChargeMosfet::decode(
-            &self.receive_bytes(SetChargeMosfet::reply_size())?,
+        self.within_operation(|bms| {
+            if enable && bms.mosfet_interlock {
+                let blocking: Vec<ErrorCode> = bms
+                    .get_errors()?
+                    .into_iter()
+                    .filter(|error| error.blocks_charging())
+                    .collect();
+                if !blocking.is_empty() {
+                    bail!(
+                        "Refusing to enable charge mosfet, active errors: {:?}",
+                        blocking
+                    );
+                }
+            }
+            let tx_buffer = SetChargeMosfet::request(Address::Host, enable);
+            if bms.skip_write(&tx_buffer) {
+                return Ok(());
+            }
+            let mut attempt = 1;
+            loop {
+                bms.send_bytes(&tx_buffer)?;
+                SetChargeMosfet::decode(&bms.receive_bytes(SetChargeMosfet::reply_size())?)?;
+                if !bms.verify_writes {
+                    return Ok(());
+                }
+                let received = bms.get_mosfet_status()?.charging_mosfet;
+                if received == enable {
+                    return Ok(());
+                }
+                bms.verification_failed(
+                    &mut attempt,
+                    "charge mosfet",
+                    u8::from(enable).into(),
+                    u8::from(received).into(),
+                )?;
+            }
+        })
+    }
+
+    /// Reads the lifetime charge and discharge counters, returns `None` if the firmware
+    /// does not support the command. A timeout is an error, commands a firmware never answers
+    /// can be skipped with `QuirkProfile::unsupported_commands`
+    pub fn get_cumulative_capacity(&mut self) -> Result<Option<CumulativeCapacity>> {
+        let result = self
+            .send_bytes(&CumulativeCapacity::request(Address::Host))
+            .and_then(|_| self.receive_bytes(CumulativeCapacity::reply_size()))
+            .and_then(|rx_buffer| Ok(CumulativeCapacity::decode(&rx_buffer)?));
+        unsupported_as_none(result, "Cumulative capacity")
+    }
+
+    /// Reads the battery pack code, a free text stored in the BMS to identify the pack
+    pub fn get_battery_code(&mut self) -> Result<String> {
+        self.within_operation(|bms| {
+            bms.send_bytes(&BatteryCode::request(Address::Host))?;
+            Ok(BatteryCode::decode_numbered(
+                &bms.receive_bytes(BatteryCode::reply_size())?,
+                bms.quirks.frame_numbering,
+            )?)
+        })
+    }
+
+    /// Reads the firmware version, not answered by all firmwares
+    pub fn get_software_version(&mut self) -> Result<String> {
+        self.within_operation(|bms| {
+            bms.send_bytes(&SoftwareVersion::request(Address::Host))?;
+            Ok(SoftwareVersion::decode_numbered(
+                &bms.receive_bytes(SoftwareVersion::reply_size())?,
+                bms.quirks.frame_numbering,
+            )?)
+        })
+    }
+
+    /// Reads the hardware version, not answered by all firmwares
+    pub fn get_hardware_version(&mut self) -> Result<String> {
+        self.within_operation(|bms| {
+            bms.send_bytes(&HardwareVersion::request(Address::Host))?;
+            Ok(HardwareVersion::decode_numbered(
+                &bms.receive_bytes(HardwareVersion::reply_size())?,
+                bms.quirks.frame_numbering,
+            )?)
+        })
+    }
+
+    /// Reads the identity of the BMS and keeps it for `device_info()`, call it once after
+    /// connecting. Values of commands the firmware does not support are left empty, a timeout
+    /// is an error.
+    pub fn identify(&mut self) -> Result<DeviceInfo> {
+        self.within_operation(|bms| {
+            let status = bms.get_status()?;
+            let software_version =
+                unsupported_as_none(bms.get_software_version(), "Software version")?;
+            // skip the broken commands of a known firmware before reading the rest
+            if let Some(version) = &software_version {
+                bms.quirks.apply_firmware(version, &bms.firmware_quirks);
+            }
+            let info = DeviceInfo {
+                cells: status.cells,
+                temperature_sensors: status.temperature_sensors,
+                battery_code: unsupported_as_none(bms.get_battery_code(), "Battery code")?,
+                software_version,
+                hardware_version: unsupported_as_none(
+                    bms.get_hardware_version(),
+                    "Hardware version",
+                )?,
+            };
+            log::debug!("device info: {:?}", info);
+            bms.device_info = Some(info.clone());
+            Ok(info)
+        })
+    }
+
+    /// The identity read by the last `identify()`
+    pub fn device_info(&self) -> Option<&DeviceInfo> {
+        self.device_info.as_ref()
+    }
+
+    /// Sends a command not modelled by this crate and returns the undecoded reply.
+    /// Raw commands may change the BMS configuration so they are refused in read-only mode.
+    pub fn raw_command(&mut self, command: u8, data: [u8; 8]) -> Result<RawFrame> {
+        let tx_buffer = RawFrame::request(Address::Host, command, data);
+        if self.skip_write(&tx_buffer) {
+            bail!("Raw command {:02X} not sent in read-only mode", command);
+        }
+        self.send_bytes(&tx_buffer)?;
+        Ok(RawFrame::decode(
+            &self.receive_bytes(RawFrame::reply_size())?,
         )?)
     }
 
+    pub fn get_current_limits(&mut self) -> Result<CurrentLimits> {
+        self.send_bytes(&CurrentLimits::request(Address::Host))?;
+        Ok(CurrentLimits::decode(
+            &self.receive_bytes(CurrentLimits::reply_size())?,
+        )?)
+    }
+
+    pub fn set_current_limits(&mut self, limits: &CurrentLimits) -> Result<()> {
+        let tx_buffer = SetCurrentLimits::request(Address::Host, limits);
+        if self.skip_write(&tx_buffer) {
+            return Ok(());
+        }
+        self.send_bytes(&tx_buffer)?;
+        Ok(SetCurrentLimits::decode(
+            &self.receive_bytes(SetCurrentLimits::reply_size())?,
+        )?)
+    }
+
+    /// Reads all configuration parameters, parameters not supported by the firmware are `None`
+    pub fn get_parameters(&mut self) -> Result<Parameters> {
+        let current_limits = self.get_current_limits();
+        Ok(Parameters {
+            current_limits: unsupported_as_none(current_limits, "Current limits")?,
+        })
+    }
+
+    /// Writes all parameters which are not `None`
+    pub fn set_parameters(&mut self, parameters: &Parameters) -> Result<()> {
+        if let Some(limits) = &parameters.current_limits {
+            self.set_current_limits(limits)?;
+        }
+        Ok(())
+    }
+
+    /// Sets the level two charge over-current alarm threshold in the EEPROM, the level one
+    /// threshold is lowered if it exceeds the new one. Both levels are read and written back,
+    /// see `CurrentLimits`, don't use it for dynamic derating
+    pub fn set_charge_current_limit(&mut self, amps: f32) -> Result<()> {
+        let mut limits = self.get_current_limits()?;
+        limits.charge_level2 = amps;
+        limits.charge_level1 = limits.charge_level1.min(amps);
+        self.set_current_limits(&limits)
+    }
+
+    /// Sets the level two discharge over-current alarm threshold in the EEPROM, the level one
+    /// threshold is lowered if it exceeds the new one. Both levels are read and written back,
+    /// see `CurrentLimits`, don't use it for dynamic derating
+    pub fn set_discharge_current_limit(&mut self, amps: f32) -> Result<()> {
+        let mut limits = self.get_current_limits()?;
+        limits.discharge_level2 = amps;
+        limits.discharge_level1 = limits.discharge_level1.min(amps);
+        self.set_current_limits(&limits)
+    }
+
     pub fn set_soc(&mut self, soc_percent: f32) -> Result<()> {
-        self.send_bytes(&SetSoc::request(Address::Host, soc_percent))?;
-        Ok(SetSoc::decode(&self.receive_bytes(SetSoc::reply_size())?)?)
+        self.within_operation(|bms| {
+            let tx_buffer = SetSoc::request(Address::Host, soc_percent);
+            if bms.skip_write(&tx_buffer) {
+                return Ok(());
+            }
+            let expected = soc_percent.clamp(0.0, 100.0);
+            let mut attempt = 1;
+            loop {
+                bms.send_bytes(&tx_buffer)?;
+                SetSoc::decode(&bms.receive_bytes(SetSoc::reply_size())?)?;
+                if !bms.verify_writes {
+                    return Ok(());
+                }
+                let received = bms.get_soc()?.soc_percent;
+                // the SOC is transferred with a resolution of 0.1%
+                if (received - expected).abs() <= 0.1 {
+                    return Ok(());
+                }
+                bms.verification_failed(&mut attempt, "SOC", expected.into(), received.into())?;
+            }
+        })
     }
 
+    #[deprecated(note = "renamed to `factory_reset()`")]
     pub fn reset(&mut self) -> Result<()> {
-        self.send_bytes(&BmsReset::request(Address::Host))?;
-        Ok(BmsReset::decode(
-            &self.receive_bytes(BmsReset::reply_size())?,
+        self.factory_reset()
+    }
+
+    /// Restores the factory parameters of the BMS
+    pub fn factory_reset(&mut self) -> Result<()> {
+        let tx_buffer = FactoryReset::request(Address::Host);
+        if self.skip_write(&tx_buffer) {
+            return Ok(());
+        }
+        self.send_bytes(&tx_buffer)?;
+        Ok(FactoryReset::decode(
+            &self.receive_bytes(FactoryReset::reply_size())?,
         )?)
     }
 }
+
+impl DalyBMS {
+    /// Moves the client into a thread which reads the given metrics every `interval`,
+    /// the thread stops as soon as the receiver is dropped. The duration of each cycle is
+    /// `BmsSnapshot::duration`, slow cycles are handled as set by `set_missed_ticks()`
+    pub fn watch(
+        mut self,
+        interval: Duration,
+        metrics: Vec<Metric>,
+    ) -> std::sync::mpsc::Receiver<Result<BmsSnapshot>> {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut next_tick = Instant::now();
+            loop {
+                let start = Instant::now();
+                let snapshot = self.get_snapshot(&metrics);
+                log::debug!("polling cycle took {:?}", start.elapsed());
+                if sender.send(snapshot).is_err() {
+                    log::trace!("receiver dropped, stop watching");
+                    break;
+                }
+                next_tick += interval;
+                let now = Instant::now();
+                if next_tick > now {
+                    std::thread::sleep(next_tick - now);
+                    continue;
+                }
+                log::warn!(
+                    "polling cycle overrun by {:?}, interval {:?}",
+                    now - next_tick,
+                    interval
+                );
+                match self.missed_ticks {
+                    MissedTicks::Delay => next_tick = now,
+                    MissedTicks::Skip => {
+                        while next_tick <= now && !interval.is_zero() {
+                            next_tick += interval;
+                        }
+                        std::thread::sleep(next_tick.saturating_duration_since(now));
+                    }
+                    // the next cycles start immediately until `next_tick` is in the future
+                    MissedTicks::Burst => {}
+                }
+            }
+        });
+        receiver
+    }
+}
+
+/// Cloneable handle to a `DalyBMS` shared between threads, e.g. a polling thread and a
+/// web server. Each method locks the client for the whole command, so commands of
+/// different threads never interleave on the bus.
+#[derive(Debug, Clone)]
+pub struct DalyBMSHandle {
+    bms: Arc<Mutex<DalyBMS>>,
+}
+
+impl DalyBMSHandle {
+    pub fn new(bms: DalyBMS) -> Self {
+        Self {
+            bms: Arc::new(Mutex::new(bms)),
+        }
+    }
+
+    /// Locks the client, a client poisoned by a panic in another thread is recovered. The
+    /// deadline of the panicked operation is the only state a failed command leaves behind
+    pub fn lock(&self) -> MutexGuard<'_, DalyBMS> {
+        self.bms.lock().unwrap_or_else(|poisoned| {
+            log::warn!("recover BMS client poisoned by a panic in another thread");
+            self.bms.clear_poison();
+            let mut bms = poisoned.into_inner();
+            // the deadline of the operation which panicked would fail every later command
+            bms.deadline = None;
+            bms
+        })
+    }
+
+    /// Runs several commands without commands of other threads in between
+    pub fn with<T>(&self, operation: impl FnOnce(&mut DalyBMS) -> T) -> T {
+        operation(&mut self.lock())
+    }
+
+    pub fn ping(&self) -> Result<Duration> {
+        self.lock().ping()
+    }
+
+    pub fn get_soc(&self) -> Result<Soc> {
+        self.lock().get_soc()
+    }
+
+    pub fn get_status(&self) -> Result<Status> {
+        self.lock().get_status()
+    }
+
+    pub fn get_mosfet_status(&self) -> Result<MosfetStatus> {
+        self.lock().get_mosfet_status()
+    }
+
+    pub fn get_errors(&self) -> Result<Vec<ErrorCode>> {
+        self.lock().get_errors()
+    }
+
+    pub fn get_snapshot(&self, metrics: &[Metric]) -> Result<BmsSnapshot> {
+        self.lock().get_snapshot(metrics)
+    }
+
+    pub fn set_soc(&self, soc_percent: f32) -> Result<()> {
+        self.lock().set_soc(soc_percent)
+    }
+
+    pub fn set_charge_mosfet(&self, enable: bool) -> Result<()> {
+        self.lock().set_charge_mosfet(enable)
+    }
+
+    pub fn set_discharge_mosfet(&self, enable: bool) -> Result<()> {
+        self.lock().set_discharge_mosfet(enable)
+    }
+}
+
+impl From<DalyBMS> for DalyBMSHandle {
+    fn from(bms: DalyBMS) -> Self {
+        Self::new(bms)
+    }
+}
@@ -0,0 +1,225 @@
+use crate::{
+    protocol::{Soc, TemperatureRange},
+    snapshot::{BmsSnapshot, Metric},
+};
+use std::time::SystemTime;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum SessionKind {
+    Charge,
+    Discharge,
+}
+
+/// Summary of a finished charge or discharge session
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct SessionSummary {
+    pub kind: SessionKind,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    /// Transferred charge and energy, always positive
+    pub ampere_hours: f32,
+    pub watt_hours: f32,
+    pub min_temperature: Option<i8>,
+    pub max_temperature: Option<i8>,
+    pub end_soc_percent: f32,
+}
+
+#[derive(Debug, Clone)]
+struct Sample {
+    at: SystemTime,
+    current: f32,
+    voltage: f32,
+}
+
+/// Detects charge and discharge sessions from the sign of the current of consecutive readings
+#[derive(Debug, Clone)]
+pub struct SessionDetector {
+    idle_current: f32,
+    session: Option<SessionSummary>,
+    last_sample: Option<Sample>,
+}
+
+impl SessionDetector {
+    /// Currents with an absolute value up to `idle_current` ampere count as idle
+    pub fn new(idle_current: f32) -> Self {
+        Self {
+            idle_current: idle_current.abs(),
+            session: None,
+            last_sample: None,
+        }
+    }
+
+    fn kind(&self, current: f32) -> Option<SessionKind> {
+        // charging is a negative current, see `Soc`
+        if current < -self.idle_current {
+            Some(SessionKind::Charge)
+        } else if current > self.idle_current {
+            Some(SessionKind::Discharge)
+        } else {
+            None
+        }
+    }
+
+    /// Feeds a snapshot with at least the `Soc`, returns the summary when a session ended
+    pub fn update(&mut self, snapshot: &BmsSnapshot) -> Option<SessionSummary> {
+        let soc = snapshot.soc.as_ref()?;
+        let at = snapshot
+            .read_times
+            .get(&Metric::Soc)
+            .map(|read_time| read_time.at)
+            .unwrap_or_else(SystemTime::now);
+        self.update_at(at, soc, snapshot.temperature_range.as_ref())
+    }
+
+    pub fn update_at(
+        &mut self,
+        at: SystemTime,
+        soc: &Soc,
+        temperature_range: Option<&TemperatureRange>,
+    ) -> Option<SessionSummary> {
+        let kind = self.kind(soc.current);
+        let sample = Sample {
+            at,
+            current: soc.current,
+            voltage: soc.total_voltage,
+        };
+        let last_sample = self.last_sample.replace(sample.clone());
+
+        let mut finished = None;
+        if self.session.as_ref().map(|session| session.kind) != kind {
+            finished = self.session.take().map(|mut session| {
+                session.end = at;
+                session
+            });
+            self.session = kind.map(|kind| SessionSummary {
+                kind,
+                start: at,
+                end: at,
+                ampere_hours: 0.0,
+                watt_hours: 0.0,
+                min_temperature: None,
+                max_temperature: None,
+                end_soc_percent: soc.soc_percent,
+            });
+        } else if let (Some(session), Some(last_sample)) = (&mut self.session, last_sample) {
+            // trapezoidal integration between the two readings
+            let hours = at
+                .duration_since(last_sample.at)
+                .unwrap_or_default()
+                .as_secs_f32()
+                / 3600.0;
+            let current = (last_sample.current + sample.current) / 2.0;
+            let voltage = (last_sample.voltage + sample.voltage) / 2.0;
+            session.ampere_hours += current.abs() * hours;
+            session.watt_hours += (current * voltage).abs() * hours;
+            session.end = at;
+            session.end_soc_percent = soc.soc_percent;
+        }
+
+        if let (Some(session), Some(range)) = (&mut self.session, temperature_range) {
+            session.min_temperature = Some(
+                session
+                    .min_temperature
+                    .map_or(range.lowest_temperature, |min| {
+                        min.min(range.lowest_temperature)
+                    }),
+            );
+            session.max_temperature = Some(
+                session
+                    .max_temperature
+                    .map_or(range.highest_temperature, |max| {
+                        max.max(range.highest_temperature)
+                    }),
+            );
+        }
+        finished
+    }
+
+    /// The session in progress, `end` is the time of the latest reading
+    pub fn current_session(&self) -> Option<&SessionSummary> {
+        self.session.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn soc(current: f32, total_voltage: f32) -> Soc {
+        Soc {
+            total_voltage,
+            current,
+            soc_percent: 50.0,
+        }
+    }
+
+    fn at(seconds: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn trapezoidal_integration() {
+        let mut detector = SessionDetector::new(0.5);
+        assert!(detector.update_at(at(0), &soc(10.0, 50.0), None).is_none());
+        assert!(detector
+            .update_at(at(3600), &soc(20.0, 52.0), None)
+            .is_none());
+
+        let session = detector.current_session().unwrap();
+        assert_eq!(session.kind, SessionKind::Discharge);
+        assert!((session.ampere_hours - 15.0).abs() < 1e-3);
+        assert!((session.watt_hours - 15.0 * 51.0).abs() < 1e-2);
+        assert_eq!(session.end, at(3600));
+    }
+
+    #[test]
+    fn sign_change_ends_session() {
+        let mut detector = SessionDetector::new(0.5);
+        detector.update_at(at(0), &soc(-10.0, 50.0), None);
+        detector.update_at(at(1800), &soc(-10.0, 50.0), None);
+
+        let finished = detector.update_at(at(3600), &soc(5.0, 50.0), None).unwrap();
+        assert_eq!(finished.kind, SessionKind::Charge);
+        assert!((finished.ampere_hours - 5.0).abs() < 1e-3);
+        assert_eq!(finished.end, at(3600));
+        // the new session starts without the charge of the previous one
+        let session = detector.current_session().unwrap();
+        assert_eq!(session.kind, SessionKind::Discharge);
+        assert_eq!(session.ampere_hours, 0.0);
+    }
+
+    #[test]
+    fn idle_current_ends_session() {
+        let mut detector = SessionDetector::new(0.5);
+        detector.update_at(at(0), &soc(3.0, 50.0), None);
+        assert!(detector.update_at(at(60), &soc(-0.2, 50.0), None).is_some());
+        assert!(detector.current_session().is_none());
+        assert!(detector.update_at(at(120), &soc(0.4, 50.0), None).is_none());
+    }
+
+    #[test]
+    fn temperature_extremes() {
+        let mut detector = SessionDetector::new(0.5);
+        let range = |lowest, highest| TemperatureRange {
+            highest_temperature: highest,
+            highest_sensor: 1,
+            lowest_temperature: lowest,
+            lowest_sensor: 2,
+        };
+        detector.update_at(at(0), &soc(5.0, 50.0), Some(&range(10, 20)));
+        detector.update_at(at(60), &soc(5.0, 50.0), Some(&range(8, 18)));
+        detector.update_at(at(120), &soc(5.0, 50.0), Some(&range(12, 25)));
+
+        let session = detector.current_session().unwrap();
+        assert_eq!(session.min_temperature, Some(8));
+        assert_eq!(session.max_temperature, Some(25));
+    }
+}
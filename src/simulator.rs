@@ -0,0 +1,604 @@
+//! Simulated BMS answering request frames like a real device, useful to test clients and
+//! tools without hardware. With the `serialport` feature `SimulatorPort` exposes the
+//! simulator as `serialport::SerialPort`, see `serialport::DalyBMS::from_port()`.
+//! Faults like delayed or corrupted replies can be injected per command, see
+//! `Simulator::inject_fault()`.
+
+use crate::protocol::*;
+use std::time::{Duration, Instant};
+
+const BMS_ADDRESS: u8 = 0x01;
+const FRAME_LENGTH: usize = 13;
+
+/// Deviation of a reply from the protocol, frames are counted from 0 within the reply
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// The reply is sent after this time, see `Simulator::reply()`
+    Delay(Duration),
+    /// No frame of the reply is sent
+    NoReply,
+    DropFrame(usize),
+    CorruptChecksum(usize),
+    /// The frame carries the number of the following frame, for multi frame replies
+    WrongFrameNumber(usize),
+    DuplicateFrame(usize),
+}
+
+/// A fault and the replies it is applied to
+#[derive(Debug, Clone, PartialEq)]
+pub struct InjectedFault {
+    /// Command of the affected replies, `None` for all commands
+    pub command: Option<u8>,
+    pub fault: Fault,
+    /// Number of replies still affected, `None` for every reply
+    pub remaining: Option<u32>,
+}
+
+/// Reply frames and the time the simulated BMS waits before sending them
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SimulatedReply {
+    pub bytes: Vec<u8>,
+    pub delay: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct Simulator {
+    pub total_voltage: f32,
+    pub current: f32,
+    pub soc_percent: f32,
+    pub mode: MosfetMode,
+    pub charging_mosfet: bool,
+    pub discharging_mosfet: bool,
+    pub bms_cycles: u8,
+    pub capacity_ah: f32,
+    pub charger_running: bool,
+    pub load_running: bool,
+    pub cycles: u16,
+    pub cell_voltages: Vec<f32>,
+    pub cell_temperatures: Vec<i32>,
+    pub balancing: Vec<bool>,
+    pub errors: Vec<ErrorCode>,
+    pub current_limits: CurrentLimits,
+    pub battery_code: String,
+    pub software_version: String,
+    pub hardware_version: String,
+    /// Lifetime counters, `None` simulates a firmware without the command
+    pub cumulative_capacity: Option<CumulativeCapacity>,
+    /// Number of answered requests
+    pub requests: u64,
+    /// Shortest time between two consecutive requests
+    pub min_request_gap: Option<Duration>,
+    /// Faults applied to the replies in the order of injection
+    pub faults: Vec<InjectedFault>,
+    last_request: Option<Instant>,
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        let cell_voltages: Vec<f32> = (0..16).map(|n| 3.3 + n as f32 * 0.001).collect();
+        Self {
+            // the total voltage is reported with a resolution of 0.1V
+            total_voltage: (cell_voltages.iter().sum::<f32>() * 10.0).round() / 10.0,
+            current: -2.5,
+            soc_percent: 75.3,
+            mode: MosfetMode::Charging,
+            charging_mosfet: true,
+            discharging_mosfet: true,
+            bms_cycles: 12,
+            capacity_ah: 75.3,
+            charger_running: true,
+            load_running: false,
+            cycles: 12,
+            balancing: vec![false; cell_voltages.len()],
+            cell_voltages,
+            cell_temperatures: vec![21, 22, 20],
+            errors: Vec::new(),
+            current_limits: CurrentLimits {
+                charge_level1: 90.0,
+                charge_level2: 100.0,
+                discharge_level1: 180.0,
+                discharge_level2: 200.0,
+            },
+            battery_code: String::from("SIMULATED-PACK-01"),
+            software_version: String::from("SIM-SW-1.0"),
+            hardware_version: String::from("SIM-HW-1.0"),
+            cumulative_capacity: Some(CumulativeCapacity {
+                charge_ah: 1234.5,
+                discharge_ah: 1180.2,
+            }),
+            requests: 0,
+            min_request_gap: None,
+            faults: Vec::new(),
+            last_request: None,
+        }
+    }
+}
+
+// 7 characters per frame, padded with spaces
+fn text_frames(command: u8, text: &str, n_frames: usize) -> Vec<u8> {
+    let mut chars = text.as_bytes().to_vec();
+    chars.resize(n_frames * 7, b' ');
+    chars
+        .chunks(7)
+        .enumerate()
+        .flat_map(|(n_frame, chars)| {
+            let mut reply = [0; 8];
+            reply[0] = n_frame as u8 + 1;
+            reply[1..8].copy_from_slice(chars);
+            reply_frame(command, reply)
+        })
+        .collect()
+}
+
+fn reply_frame(command: u8, data: [u8; 8]) -> Vec<u8> {
+    let mut frame = vec![START_BYTE, BMS_ADDRESS, command, DATA_LENGTH];
+    frame.extend_from_slice(&data);
+    frame.push(0);
+    calc_crc_and_set(&mut frame);
+    frame
+}
+
+fn temperature_byte(temperature: i32) -> u8 {
+    (temperature + 40).clamp(0, u8::MAX as i32) as u8
+}
+
+impl Simulator {
+    /// Applies `fault` to the next `count` replies to `command`, or to every reply if `count`
+    /// is `None`. A `command` of `None` affects the replies to all commands
+    pub fn inject_fault(&mut self, command: Option<u8>, fault: Fault, count: Option<u32>) {
+        self.faults.push(InjectedFault {
+            command,
+            fault,
+            remaining: count,
+        });
+    }
+
+    pub fn clear_faults(&mut self) {
+        self.faults.clear();
+    }
+
+    /// Returns the reply to a request frame, an empty reply if the BMS would not answer.
+    /// Injected faults are applied, the delay of `Fault::Delay` is ignored, see `reply()`
+    pub fn respond(&mut self, request: &[u8]) -> Vec<u8> {
+        self.reply(request).bytes
+    }
+
+    /// Returns the reply to a request frame with the injected faults applied
+    pub fn reply(&mut self, request: &[u8]) -> SimulatedReply {
+        let bytes = self.answer(request);
+        if bytes.is_empty() {
+            return SimulatedReply::default();
+        }
+        let command = request[2];
+        let mut frames: Vec<Vec<u8>> = bytes.chunks(FRAME_LENGTH).map(<[u8]>::to_vec).collect();
+        let mut delay = Duration::ZERO;
+        for injected in self.faults.iter_mut().filter(|injected| {
+            injected.command.is_none_or(|affected| affected == command)
+                && injected.remaining != Some(0)
+        }) {
+            if let Some(remaining) = &mut injected.remaining {
+                *remaining -= 1;
+            }
+            log::trace!(
+                "simulator injects {:?} into the reply to {:02X}",
+                injected.fault,
+                command
+            );
+            match injected.fault {
+                Fault::Delay(duration) => delay += duration,
+                Fault::NoReply => frames.clear(),
+                Fault::DropFrame(n) if n < frames.len() => {
+                    frames.remove(n);
+                }
+                Fault::CorruptChecksum(n) => {
+                    if let Some(frame) = frames.get_mut(n) {
+                        frame[FRAME_LENGTH - 1] = !frame[FRAME_LENGTH - 1];
+                    }
+                }
+                Fault::WrongFrameNumber(n) => {
+                    if let Some(frame) = frames.get_mut(n) {
+                        frame[4] = frame[4].wrapping_add(1);
+                        calc_crc_and_set(frame);
+                    }
+                }
+                Fault::DuplicateFrame(n) if n < frames.len() => {
+                    frames.insert(n, frames[n].clone());
+                }
+                Fault::DropFrame(_) | Fault::DuplicateFrame(_) => {}
+            }
+        }
+        self.faults.retain(|injected| injected.remaining != Some(0));
+        SimulatedReply {
+            bytes: frames.concat(),
+            delay,
+        }
+    }
+
+    fn answer(&mut self, request: &[u8]) -> Vec<u8> {
+        if request.len() != FRAME_LENGTH
+            || request[0] != START_BYTE
+            || request[FRAME_LENGTH - 1] != calc_crc(request)
+        {
+            log::trace!("simulator ignores invalid request: {:02X?}", request);
+            return Vec::new();
+        }
+        let now = Instant::now();
+        if let Some(last_request) = self.last_request {
+            let gap = now.duration_since(last_request);
+            self.min_request_gap = Some(self.min_request_gap.map_or(gap, |min| min.min(gap)));
+        }
+        self.last_request = Some(now);
+        self.requests += 1;
+
+        let command = request[2];
+        let data = &request[4..12];
+        match command {
+            0x90 => {
+                let mut reply = [0; 8];
+                let voltage = ((self.total_voltage * 10.0).round() as u16).to_be_bytes();
+                reply[0..2].copy_from_slice(&voltage);
+                reply[2..4].copy_from_slice(&voltage);
+                reply[4..6].copy_from_slice(&encode_current(self.current));
+                reply[6..8]
+                    .copy_from_slice(&((self.soc_percent * 10.0).round() as u16).to_be_bytes());
+                reply_frame(command, reply)
+            }
+            0x91 => {
+                let mut reply = [0; 8];
+                let mut cells: Vec<(usize, f32)> =
+                    self.cell_voltages.iter().copied().enumerate().collect();
+                cells.sort_by(|a, b| a.1.total_cmp(&b.1));
+                if let (Some(lowest), Some(highest)) = (cells.first(), cells.last()) {
+                    reply[0..2]
+                        .copy_from_slice(&((highest.1 * 1000.0).round() as u16).to_be_bytes());
+                    reply[2] = highest.0 as u8 + 1;
+                    reply[3..5]
+                        .copy_from_slice(&((lowest.1 * 1000.0).round() as u16).to_be_bytes());
+                    reply[5] = lowest.0 as u8 + 1;
+                }
+                reply_frame(command, reply)
+            }
+            0x92 => {
+                let mut reply = [0; 8];
+                let mut sensors: Vec<(usize, i32)> =
+                    self.cell_temperatures.iter().copied().enumerate().collect();
+                sensors.sort_by_key(|sensor| sensor.1);
+                if let (Some(lowest), Some(highest)) = (sensors.first(), sensors.last()) {
+                    reply[0] = temperature_byte(highest.1);
+                    reply[1] = highest.0 as u8 + 1;
+                    reply[2] = temperature_byte(lowest.1);
+                    reply[3] = lowest.0 as u8 + 1;
+                }
+                reply_frame(command, reply)
+            }
+            0x93 => {
+                let mut reply = [0; 8];
+                reply[0] = match self.mode {
+                    MosfetMode::Stationary => 0,
+                    MosfetMode::Charging => 1,
+                    MosfetMode::Discharging => 2,
+                };
+                reply[1] = self.charging_mosfet.into();
+                reply[2] = self.discharging_mosfet.into();
+                reply[3] = self.bms_cycles;
+                reply[4..8]
+                    .copy_from_slice(&((self.capacity_ah * 1000.0).round() as u32).to_be_bytes());
+                reply_frame(command, reply)
+            }
+            0x94 => {
+                let mut reply = [0; 8];
+                reply[0] = self.cell_voltages.len() as u8;
+                reply[1] = self.cell_temperatures.len() as u8;
+                reply[2] = self.charger_running.into();
+                reply[3] = self.load_running.into();
+                reply[5..7].copy_from_slice(&self.cycles.to_be_bytes());
+                reply_frame(command, reply)
+            }
+            0x95 => self
+                .cell_voltages
+                .chunks(3)
+                .enumerate()
+                .flat_map(|(n_frame, voltages)| {
+                    let mut reply = [0; 8];
+                    reply[0] = n_frame as u8 + 1;
+                    for (i, voltage) in voltages.iter().enumerate() {
+                        reply[1 + i * 2..3 + i * 2]
+                            .copy_from_slice(&((voltage * 1000.0).round() as u16).to_be_bytes());
+                    }
+                    reply_frame(command, reply)
+                })
+                .collect(),
+            0x96 => self
+                .cell_temperatures
+                .chunks(7)
+                .enumerate()
+                .flat_map(|(n_frame, temperatures)| {
+                    let mut reply = [0; 8];
+                    reply[0] = n_frame as u8 + 1;
+                    for (i, temperature) in temperatures.iter().enumerate() {
+                        reply[1 + i] = temperature_byte(*temperature);
+                    }
+                    reply_frame(command, reply)
+                })
+                .collect(),
+            0x97 => {
+                let mut reply = [0; 8];
+                for (n_cell, balancing) in self.balancing.iter().enumerate().take(48) {
+                    if *balancing {
+                        reply[n_cell / 8] |= 1 << (n_cell % 8);
+                    }
+                }
+                reply_frame(command, reply)
+            }
+            0x98 => {
+                let mut reply = [0; 8];
+                for (error_code, byte, position) in ERROR_CODE_BITS {
+                    if self.errors.contains(&error_code) {
+                        reply[byte - 4] |= 1 << position;
+                    }
+                }
+                reply_frame(command, reply)
+            }
+            0x52 => match &self.cumulative_capacity {
+                Some(capacity) => {
+                    let mut reply = [0; 8];
+                    reply[0..4].copy_from_slice(
+                        &((capacity.charge_ah * 10.0).round() as u32).to_be_bytes(),
+                    );
+                    reply[4..8].copy_from_slice(
+                        &((capacity.discharge_ah * 10.0).round() as u32).to_be_bytes(),
+                    );
+                    reply_frame(command, reply)
+                }
+                None => Vec::new(),
+            },
+            0x53 => text_frames(command, &self.battery_code, 5),
+            0x62 => text_frames(command, &self.software_version, 2),
+            0x63 => text_frames(command, &self.hardware_version, 2),
+            0x5B => {
+                let mut reply = [0; 8];
+                reply[0..2].copy_from_slice(&encode_current(-self.current_limits.charge_level1));
+                reply[2..4].copy_from_slice(&encode_current(-self.current_limits.charge_level2));
+                reply[4..6].copy_from_slice(&encode_current(self.current_limits.discharge_level1));
+                reply[6..8].copy_from_slice(&encode_current(self.current_limits.discharge_level2));
+                reply_frame(command, reply)
+            }
+            0x1B => {
+                self.current_limits = CurrentLimits {
+                    charge_level1: -decode_current(data[0], data[1]),
+                    charge_level2: -decode_current(data[2], data[3]),
+                    discharge_level1: decode_current(data[4], data[5]),
+                    discharge_level2: decode_current(data[6], data[7]),
+                };
+                reply_frame(command, [0; 8])
+            }
+            0xD9 => {
+                self.discharging_mosfet = data[0] != 0;
+                reply_frame(command, [0; 8])
+            }
+            0xDA => {
+                self.charging_mosfet = data[0] != 0;
+                reply_frame(command, [0; 8])
+            }
+            0x21 => {
+                self.soc_percent = u16::from_be_bytes([data[6], data[7]]) as f32 / 10.0;
+                reply_frame(command, [0; 8])
+            }
+            0x00 => reply_frame(command, [0; 8]),
+            _ => {
+                log::trace!("simulator does not know command {:02X?}", command);
+                Vec::new()
+            }
+        }
+    }
+}
+
+#[cfg(feature = "serialport")]
+pub use self::port::SimulatorPort;
+
+#[cfg(feature = "serialport")]
+mod port {
+    use super::{Simulator, FRAME_LENGTH};
+    use std::{
+        collections::VecDeque,
+        io,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    /// A `serialport::SerialPort` connected to a `Simulator`, the simulator is shared
+    /// so its state can be inspected and changed while a client owns the port. Replies delayed
+    /// by `Fault::Delay` can be read after the delay
+    #[derive(Debug)]
+    pub struct SimulatorPort {
+        simulator: Arc<Mutex<Simulator>>,
+        tx_buffer: Vec<u8>,
+        // received bytes with the time they arrive
+        rx_buffer: Mutex<VecDeque<(Instant, u8)>>,
+        timeout: Duration,
+    }
+
+    impl SimulatorPort {
+        pub fn new(simulator: Arc<Mutex<Simulator>>) -> Self {
+            Self {
+                simulator,
+                tx_buffer: Vec::new(),
+                rx_buffer: Mutex::new(VecDeque::new()),
+                timeout: Duration::from_millis(500),
+            }
+        }
+
+        fn rx_buffer(&self) -> std::sync::MutexGuard<'_, VecDeque<(Instant, u8)>> {
+            self.rx_buffer.lock().unwrap_or_else(|err| err.into_inner())
+        }
+    }
+
+    impl io::Read for SimulatorPort {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let timed_out = || io::Error::new(io::ErrorKind::TimedOut, "Operation timed out");
+            let Some((arrival, _)) = self.rx_buffer().front().copied() else {
+                return Err(timed_out());
+            };
+            let wait = arrival.saturating_duration_since(Instant::now());
+            if wait > self.timeout {
+                std::thread::sleep(self.timeout);
+                return Err(timed_out());
+            }
+            std::thread::sleep(wait);
+            let now = Instant::now();
+            let mut rx_buffer = self.rx_buffer();
+            let len = buf
+                .len()
+                .min(rx_buffer.iter().take_while(|(at, _)| *at <= now).count());
+            for (byte, (_, received)) in buf.iter_mut().zip(rx_buffer.drain(..len)) {
+                *byte = received;
+            }
+            Ok(len)
+        }
+    }
+
+    impl io::Write for SimulatorPort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.tx_buffer.extend_from_slice(buf);
+            while self.tx_buffer.len() >= FRAME_LENGTH {
+                let request: Vec<u8> = self.tx_buffer.drain(..FRAME_LENGTH).collect();
+                let reply = self
+                    .simulator
+                    .lock()
+                    .unwrap_or_else(|err| err.into_inner())
+                    .reply(&request);
+                let mut rx_buffer = self.rx_buffer();
+                // a reply can't overtake a delayed one
+                let arrival = rx_buffer
+                    .back()
+                    .map_or(Instant::now(), |(at, _)| *at)
+                    .max(Instant::now() + reply.delay);
+                rx_buffer.extend(reply.bytes.into_iter().map(|byte| (arrival, byte)));
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl serialport::SerialPort for SimulatorPort {
+        fn name(&self) -> Option<String> {
+            Some(String::from("simulator"))
+        }
+
+        fn baud_rate(&self) -> serialport::Result<u32> {
+            Ok(9600)
+        }
+
+        fn data_bits(&self) -> serialport::Result<serialport::DataBits> {
+            Ok(serialport::DataBits::Eight)
+        }
+
+        fn flow_control(&self) -> serialport::Result<serialport::FlowControl> {
+            Ok(serialport::FlowControl::None)
+        }
+
+        fn parity(&self) -> serialport::Result<serialport::Parity> {
+            Ok(serialport::Parity::None)
+        }
+
+        fn stop_bits(&self) -> serialport::Result<serialport::StopBits> {
+            Ok(serialport::StopBits::One)
+        }
+
+        fn timeout(&self) -> Duration {
+            self.timeout
+        }
+
+        fn set_baud_rate(&mut self, _baud_rate: u32) -> serialport::Result<()> {
+            Ok(())
+        }
+
+        fn set_data_bits(&mut self, _data_bits: serialport::DataBits) -> serialport::Result<()> {
+            Ok(())
+        }
+
+        fn set_flow_control(
+            &mut self,
+            _flow_control: serialport::FlowControl,
+        ) -> serialport::Result<()> {
+            Ok(())
+        }
+
+        fn set_parity(&mut self, _parity: serialport::Parity) -> serialport::Result<()> {
+            Ok(())
+        }
+
+        fn set_stop_bits(&mut self, _stop_bits: serialport::StopBits) -> serialport::Result<()> {
+            Ok(())
+        }
+
+        fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+            self.timeout = timeout;
+            Ok(())
+        }
+
+        fn write_request_to_send(&mut self, _level: bool) -> serialport::Result<()> {
+            Ok(())
+        }
+
+        fn write_data_terminal_ready(&mut self, _level: bool) -> serialport::Result<()> {
+            Ok(())
+        }
+
+        fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+            Ok(true)
+        }
+
+        fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+            Ok(true)
+        }
+
+        fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+            Ok(false)
+        }
+
+        fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+            Ok(true)
+        }
+
+        fn bytes_to_read(&self) -> serialport::Result<u32> {
+            let now = Instant::now();
+            Ok(self
+                .rx_buffer()
+                .iter()
+                .take_while(|(at, _)| *at <= now)
+                .count() as u32)
+        }
+
+        fn bytes_to_write(&self) -> serialport::Result<u32> {
+            Ok(0)
+        }
+
+        fn clear(&self, buffer_to_clear: serialport::ClearBuffer) -> serialport::Result<()> {
+            if !matches!(buffer_to_clear, serialport::ClearBuffer::Output) {
+                // delayed replies still arrive after clearing, like on a real port
+                let now = Instant::now();
+                self.rx_buffer().retain(|(at, _)| *at > now);
+            }
+            Ok(())
+        }
+
+        fn try_clone(&self) -> serialport::Result<Box<dyn serialport::SerialPort>> {
+            Err(serialport::Error::new(
+                serialport::ErrorKind::Unknown,
+                "Cloning the simulator port is not supported",
+            ))
+        }
+
+        fn set_break(&self) -> serialport::Result<()> {
+            Ok(())
+        }
+
+        fn clear_break(&self) -> serialport::Result<()> {
+            Ok(())
+        }
+    }
+}
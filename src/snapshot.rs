@@ -0,0 +1,314 @@
+use crate::protocol::*;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Values which can be read from the BMS as part of a `BmsSnapshot`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum Metric {
+    Status,
+    Soc,
+    Mosfet,
+    VoltageRange,
+    TemperatureRange,
+    CellVoltages,
+    CellTemperatures,
+    Balancing,
+    Errors,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 9] = [
+        Metric::Status,
+        Metric::Soc,
+        Metric::Mosfet,
+        Metric::VoltageRange,
+        Metric::TemperatureRange,
+        Metric::CellVoltages,
+        Metric::CellTemperatures,
+        Metric::Balancing,
+        Metric::Errors,
+    ];
+
+    /// The number of cells or sensors of the `Status` is required to read this metric
+    pub fn requires_status(&self) -> bool {
+        matches!(
+            self,
+            Metric::CellVoltages | Metric::CellTemperatures | Metric::Balancing
+        )
+    }
+
+    /// Request and reply size of the metrics with a single frame reply, which can be pipelined
+    #[cfg(any(feature = "serialport", feature = "tokio-serial-async"))]
+    pub(crate) fn pipelined_request(&self) -> Option<(Vec<u8>, usize)> {
+        match self {
+            Metric::Status => Some((Status::request(Address::Host), Status::reply_size())),
+            Metric::Soc => Some((Soc::request(Address::Host), Soc::reply_size())),
+            Metric::Mosfet => Some((
+                MosfetStatus::request(Address::Host),
+                MosfetStatus::reply_size(),
+            )),
+            Metric::VoltageRange => Some((
+                CellVoltageRange::request(Address::Host),
+                CellVoltageRange::reply_size(),
+            )),
+            Metric::TemperatureRange => Some((
+                TemperatureRange::request(Address::Host),
+                TemperatureRange::reply_size(),
+            )),
+            Metric::Balancing => Some((
+                CellBalanceState::request(Address::Host),
+                CellBalanceState::reply_size(),
+            )),
+            Metric::Errors => Some((ErrorCode::request(Address::Host), ErrorCode::reply_size())),
+            Metric::CellVoltages | Metric::CellTemperatures => None,
+        }
+    }
+}
+
+impl Metric {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metric::Status => "status",
+            Metric::Soc => "soc",
+            Metric::Mosfet => "mosfet",
+            Metric::VoltageRange => "voltage-range",
+            Metric::TemperatureRange => "temperature-range",
+            Metric::CellVoltages => "cell-voltages",
+            Metric::CellTemperatures => "cell-temperatures",
+            Metric::Balancing => "balancing",
+            Metric::Errors => "errors",
+        }
+    }
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Metric::ALL
+            .into_iter()
+            .find(|metric| metric.name() == s)
+            .ok_or_else(|| {
+                let closest = Metric::ALL
+                    .iter()
+                    .map(|metric| (edit_distance(s, metric.name()), metric.name()))
+                    .min();
+                match closest {
+                    Some((distance, name)) if distance <= 2 => {
+                        format!("unknown metric '{}', did you mean '{}'?", s, name)
+                    }
+                    _ => {
+                        let names: Vec<&str> = Metric::ALL.iter().map(Metric::name).collect();
+                        format!("unknown metric '{}', valid: {}", s, names.join(", "))
+                    }
+                }
+            })
+    }
+}
+
+// Levenshtein distance, used to suggest a metric for a typo
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// When a metric was read and how long the request took
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ReadTime {
+    pub at: SystemTime,
+    pub latency: Duration,
+}
+
+/// All values read from the BMS in one polling cycle, metrics which were not requested are `None`
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+// missing metrics and read times are accepted, e.g. from snapshots of older versions
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct BmsSnapshot {
+    pub status: Option<Status>,
+    pub soc: Option<Soc>,
+    pub mosfet_status: Option<MosfetStatus>,
+    pub cell_voltage_range: Option<CellVoltageRange>,
+    pub temperature_range: Option<TemperatureRange>,
+    pub cell_voltages: Option<Vec<f32>>,
+    pub cell_temperatures: Option<Vec<i32>>,
+    pub balancing_status: Option<Vec<bool>>,
+    pub errors: Option<Vec<ErrorCode>>,
+    /// Read time of every metric in this snapshot, retries may delay single metrics
+    pub read_times: BTreeMap<Metric, ReadTime>,
+    /// Time to read the whole snapshot, i.e. the duration of a polling cycle of `watch()`
+    pub duration: Option<Duration>,
+}
+
+/// What `watch()` does when a polling cycle takes longer than the interval
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MissedTicks {
+    /// The next cycle starts right after the slow one, the following cycles keep the
+    /// interval from there
+    #[default]
+    Delay,
+    /// The missed ticks are skipped, the next cycle starts at the next tick of the
+    /// original schedule
+    Skip,
+    /// The missed ticks are polled back to back until the original schedule is caught up
+    Burst,
+}
+
+/// JSON Schema of a `BmsSnapshot` or of the value of one metric in it
+#[cfg(feature = "schemars")]
+pub fn json_schema(metric: Option<Metric>) -> schemars::Schema {
+    use schemars::schema_for;
+    match metric {
+        None => schema_for!(BmsSnapshot),
+        Some(Metric::Status) => schema_for!(Status),
+        Some(Metric::Soc) => schema_for!(Soc),
+        Some(Metric::Mosfet) => schema_for!(MosfetStatus),
+        Some(Metric::VoltageRange) => schema_for!(CellVoltageRange),
+        Some(Metric::TemperatureRange) => schema_for!(TemperatureRange),
+        Some(Metric::CellVoltages) => schema_for!(Vec<f32>),
+        Some(Metric::CellTemperatures) => schema_for!(Vec<i32>),
+        Some(Metric::Balancing) => schema_for!(Vec<bool>),
+        Some(Metric::Errors) => schema_for!(Vec<ErrorCode>),
+    }
+}
+
+/// Decimal places of the output values, the values read from the BMS are rounded to it.
+/// Rust formats numbers independent of the locale, the decimal separator is always '.'.
+/// More than `Precision::MAX_DIGITS` places exceed the resolution of `f32`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Precision {
+    /// Total and cell voltages
+    pub voltage: u8,
+    /// Current and capacity
+    pub current: u8,
+    pub soc: u8,
+}
+
+impl Default for Precision {
+    fn default() -> Self {
+        Self {
+            voltage: 3,
+            current: 1,
+            soc: 1,
+        }
+    }
+}
+
+impl Precision {
+    pub const MAX_DIGITS: u8 = 6;
+
+    /// Rounds to at most `MAX_DIGITS` places
+    pub fn round(value: f32, digits: u8) -> f32 {
+        let factor = 10f32.powi(digits.min(Self::MAX_DIGITS).into());
+        (value * factor).round() / factor
+    }
+
+    pub fn round_soc(&self, soc: &mut Soc) {
+        soc.total_voltage = Self::round(soc.total_voltage, self.voltage);
+        soc.current = Self::round(soc.current, self.current);
+        soc.soc_percent = Self::round(soc.soc_percent, self.soc);
+    }
+
+    pub fn round_voltage_range(&self, range: &mut CellVoltageRange) {
+        range.highest_voltage = Self::round(range.highest_voltage, self.voltage);
+        range.lowest_voltage = Self::round(range.lowest_voltage, self.voltage);
+    }
+
+    pub fn round_voltages(&self, voltages: &mut [f32]) {
+        for voltage in voltages {
+            *voltage = Self::round(*voltage, self.voltage);
+        }
+    }
+
+    pub fn round_snapshot(&self, snapshot: &mut BmsSnapshot) {
+        if let Some(soc) = &mut snapshot.soc {
+            self.round_soc(soc);
+        }
+        if let Some(range) = &mut snapshot.cell_voltage_range {
+            self.round_voltage_range(range);
+        }
+        if let Some(voltages) = &mut snapshot.cell_voltages {
+            self.round_voltages(voltages);
+        }
+        if let Some(mosfet_status) = &mut snapshot.mosfet_status {
+            mosfet_status.capacity_ah = Self::round(mosfet_status.capacity_ah, self.current);
+        }
+    }
+}
+
+impl fmt::Display for Precision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "voltage={},current={},soc={}",
+            self.voltage, self.current, self.soc
+        )
+    }
+}
+
+/// Parses a comma separated list like "voltage=2,soc=0", quantities not given keep the default
+impl FromStr for Precision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut precision = Precision::default();
+        for part in s.split(',').filter(|part| !part.is_empty()) {
+            let (name, digits) = part
+                .split_once('=')
+                .ok_or_else(|| format!("expected <quantity>=<digits>, got '{}'", part))?;
+            let digits: u8 = digits
+                .trim()
+                .parse()
+                .ok()
+                .filter(|digits| *digits <= Self::MAX_DIGITS)
+                .ok_or_else(|| {
+                    format!(
+                        "invalid number of digits '{}', expected 0 to {}",
+                        digits,
+                        Self::MAX_DIGITS
+                    )
+                })?;
+            match name.trim() {
+                "voltage" => precision.voltage = digits,
+                "current" => precision.current = digits,
+                "soc" => precision.soc = digits,
+                name => {
+                    return Err(format!(
+                        "unknown quantity '{}', expected voltage, current or soc",
+                        name
+                    ))
+                }
+            }
+        }
+        Ok(precision)
+    }
+}
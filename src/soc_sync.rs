@@ -0,0 +1,165 @@
+use crate::protocol::Soc;
+use std::time::{Duration, SystemTime};
+
+/// Detects a full pack: the voltage reached the absorb level while the charge current fell
+/// below the tail current for at least `hold`. The BMS estimator drifts over time, so this is
+/// the moment to resynchronize the SOC to 100%.
+#[derive(Debug, Clone)]
+pub struct FullChargeDetector {
+    pub absorb_voltage: f32,
+    pub tail_current: f32,
+    pub hold: Duration,
+    since: Option<SystemTime>,
+    triggered: bool,
+}
+
+impl FullChargeDetector {
+    pub fn new(absorb_voltage: f32, tail_current: f32, hold: Duration) -> Self {
+        Self {
+            absorb_voltage,
+            tail_current: tail_current.abs(),
+            hold,
+            since: None,
+            triggered: false,
+        }
+    }
+
+    pub fn update(&mut self, soc: &Soc) -> bool {
+        self.update_at(SystemTime::now(), soc)
+    }
+
+    /// Returns `true` once per full charge, the detector is armed again as soon as the
+    /// conditions are no longer met
+    pub fn update_at(&mut self, at: SystemTime, soc: &Soc) -> bool {
+        // charging is a negative current, see `Soc`
+        let full = soc.total_voltage >= self.absorb_voltage
+            && soc.current <= 0.0
+            && -soc.current <= self.tail_current;
+        if !full {
+            self.since = None;
+            self.triggered = false;
+            return false;
+        }
+        let since = *self.since.get_or_insert(at);
+        if !self.triggered && at.duration_since(since).unwrap_or_default() >= self.hold {
+            self.triggered = true;
+            return true;
+        }
+        false
+    }
+}
+
+/// Detects an empty pack: the lowest cell reached the empty voltage. To not fight the BMS
+/// estimator the detector is only armed again after the cell recovered by `hysteresis` and
+/// triggers at most once per `min_interval`.
+#[derive(Debug, Clone)]
+pub struct EmptyDetector {
+    pub empty_cell_voltage: f32,
+    pub hysteresis: f32,
+    pub min_interval: Duration,
+    armed: bool,
+    last_trigger: Option<SystemTime>,
+}
+
+impl EmptyDetector {
+    pub fn new(empty_cell_voltage: f32, hysteresis: f32, min_interval: Duration) -> Self {
+        Self {
+            empty_cell_voltage,
+            hysteresis: hysteresis.abs(),
+            min_interval,
+            armed: true,
+            last_trigger: None,
+        }
+    }
+
+    pub fn update(&mut self, lowest_cell_voltage: f32) -> bool {
+        self.update_at(SystemTime::now(), lowest_cell_voltage)
+    }
+
+    pub fn update_at(&mut self, at: SystemTime, lowest_cell_voltage: f32) -> bool {
+        if lowest_cell_voltage >= self.empty_cell_voltage + self.hysteresis {
+            self.armed = true;
+            return false;
+        }
+        if !self.armed || lowest_cell_voltage > self.empty_cell_voltage {
+            return false;
+        }
+        if self.last_trigger.is_some_and(|last_trigger| {
+            at.duration_since(last_trigger).unwrap_or_default() < self.min_interval
+        }) {
+            return false;
+        }
+        self.armed = false;
+        self.last_trigger = Some(at);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn soc(total_voltage: f32, current: f32) -> Soc {
+        Soc {
+            total_voltage,
+            current,
+            soc_percent: 97.0,
+        }
+    }
+
+    fn at(seconds: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn full_charge_after_hold() {
+        let mut detector = FullChargeDetector::new(55.2, 2.0, Duration::from_secs(60));
+        assert!(!detector.update_at(at(0), &soc(55.3, -1.5)));
+        assert!(!detector.update_at(at(30), &soc(55.3, -1.5)));
+        assert!(detector.update_at(at(60), &soc(55.3, -1.5)));
+        // once per full charge
+        assert!(!detector.update_at(at(120), &soc(55.3, -1.0)));
+    }
+
+    #[test]
+    fn full_charge_hold_restarts() {
+        let mut detector = FullChargeDetector::new(55.2, 2.0, Duration::from_secs(60));
+        detector.update_at(at(0), &soc(55.3, -1.5));
+        // the charge current is above the tail current again
+        assert!(!detector.update_at(at(30), &soc(55.3, -5.0)));
+        assert!(!detector.update_at(at(60), &soc(55.3, -1.5)));
+        assert!(detector.update_at(at(120), &soc(55.3, -1.5)));
+    }
+
+    #[test]
+    fn full_charge_rearms() {
+        let mut detector = FullChargeDetector::new(55.2, 2.0, Duration::ZERO);
+        assert!(detector.update_at(at(0), &soc(55.3, -1.0)));
+        // a discharge current is not a full pack
+        assert!(!detector.update_at(at(10), &soc(55.3, 3.0)));
+        assert!(detector.update_at(at(20), &soc(55.3, -1.0)));
+    }
+
+    #[test]
+    fn empty_hysteresis() {
+        let mut detector = EmptyDetector::new(2.9, 0.1, Duration::ZERO);
+        assert!(!detector.update_at(at(0), 2.95));
+        assert!(detector.update_at(at(10), 2.9));
+        assert!(!detector.update_at(at(20), 2.85));
+        // recovered, but not by the hysteresis
+        assert!(!detector.update_at(at(30), 2.99));
+        assert!(!detector.update_at(at(40), 2.88));
+        assert!(!detector.update_at(at(50), 3.05));
+        assert!(detector.update_at(at(60), 2.88));
+    }
+
+    #[test]
+    fn empty_min_interval() {
+        let mut detector = EmptyDetector::new(2.9, 0.1, Duration::from_secs(3600));
+        assert!(detector.update_at(at(0), 2.85));
+        detector.update_at(at(60), 3.1);
+        assert!(!detector.update_at(at(120), 2.85));
+        // still armed after the interval
+        assert!(detector.update_at(at(3600), 2.85));
+    }
+}
@@ -1,22 +1,98 @@
+//! Asynchronous client based on tokio-serial.
+//!
+//! Every command is a transaction of the states *idle* -> *sending* -> *receiving* -> *idle*.
+//! A transaction is marked as pending as soon as the request is written and only completes
+//! after the whole reply was read. If the future of a command is dropped (e.g. by
+//! `tokio::select!`, a timeout or `DalyBMS::cancellable()`) or the reply times out, the
+//! transaction stays pending. The next command then waits until no byte arrived for the IO
+//! timeout and discards the stale reply before sending, so an aborted command never
+//! corrupts the reply of the following one.
+//!
+//! There are no `try_` variants of the commands: they take `&mut self`, so there is no lock
+//! which could be busy, and a command which must not wait is aborted by dropping its future
+//! or with `DalyBMS::cancellable()`.
+
+use crate::connection::ConnectionEvent;
+use crate::device::DeviceInfo;
+use crate::error::unsupported_as_none;
+use crate::middleware::FrameMiddleware;
+use crate::parameters::Parameters;
 use crate::protocol::*;
-use anyhow::{bail, Context, Result};
-use std::time::{Duration, Instant};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use crate::snapshot::{BmsSnapshot, Metric, MissedTicks, ReadTime};
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::{Stream, StreamExt};
+use std::{
+    collections::BinaryHeap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{broadcast, mpsc, oneshot, watch},
+    task::JoinHandle,
+};
 use tokio_serial::{SerialPort, SerialPortBuilderExt};
 
+const WRITE_ATTEMPTS: u32 = 3;
+// IO timeouts to wait for the end of a stale reply, a bus which is never quiet is an error
+const STALE_REPLY_TIMEOUTS: u32 = 4;
+
+// Clears the deadline of an operation when dropped. The operation which set the deadline owns
+// it, so it's also cleared if the future of the operation is dropped before it completed,
+// otherwise every later command would fail with an exceeded operation timeout
+#[derive(Debug)]
+struct OperationDeadline(Option<Arc<Mutex<Option<Instant>>>>);
+
+impl Drop for OperationDeadline {
+    fn drop(&mut self) {
+        if let Some(deadline) = &self.0 {
+            *deadline.lock().unwrap_or_else(PoisonError::into_inner) = None;
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct DalyBMS {
     serial: tokio_serial::SerialStream,
     last_execution: Instant,
+    last_command: u8,
     io_timeout: Duration,
     delay: Duration,
     status: Option<Status>,
+    status_limits: StatusLimits,
+    plausibility_limits: Option<PlausibilityLimits>,
+    implausible_readings: u64,
+    quirks: QuirkProfile,
+    mosfet_interlock: bool,
+    read_only: bool,
+    verify_writes: bool,
+    decode_mode: DecodeMode,
+    pending_transaction: bool,
+    device_info: Option<DeviceInfo>,
+    connected: bool,
+    operation_timeout: Option<Duration>,
+    // shared with the `OperationDeadline` of the operation which set it
+    deadline: Arc<Mutex<Option<Instant>>>,
+    events: broadcast::Sender<ConnectionEvent>,
+    middlewares: Vec<Box<dyn FrameMiddleware>>,
+    firmware_quirks: Vec<FirmwareQuirks>,
+    pipelining: bool,
+    missed_ticks: MissedTicks,
+    // reply of the next command, received by a pipeline of `get_snapshot()`
+    prefetched: Option<Vec<u8>>,
 }
 
 impl DalyBMS {
     pub fn new(port: &str) -> Result<Self> {
+        Self::with_baud_rate(port, 9600)
+    }
+
+    /// Opens the port with a non standard baud rate, the Daly protocol specifies 9600
+    pub fn with_baud_rate(port: &str, baud_rate: u32) -> Result<Self> {
         Ok(Self {
-            serial: tokio_serial::new(port, 9600)
+            serial: tokio_serial::new(port, baud_rate)
                 .data_bits(tokio_serial::DataBits::Eight)
                 .parity(tokio_serial::Parity::None)
                 .stop_bits(tokio_serial::StopBits::One)
@@ -24,9 +100,29 @@ impl DalyBMS {
                 .open_native_async()
                 .with_context(|| format!("Cannot open serial port '{}'", port))?,
             last_execution: Instant::now(),
+            last_command: 0,
             delay: MINIMUM_DELAY,
             io_timeout: Duration::from_secs(5),
             status: None,
+            status_limits: StatusLimits::default(),
+            plausibility_limits: None,
+            implausible_readings: 0,
+            quirks: QuirkProfile::default(),
+            mosfet_interlock: false,
+            read_only: false,
+            verify_writes: false,
+            decode_mode: DecodeMode::Strict,
+            device_info: None,
+            connected: false,
+            operation_timeout: None,
+            deadline: Arc::new(Mutex::new(None)),
+            events: broadcast::channel(16).0,
+            middlewares: Vec::new(),
+            firmware_quirks: Vec::new(),
+            pipelining: false,
+            missed_ticks: MissedTicks::default(),
+            prefetched: None,
+            pending_transaction: false,
         })
     }
 
@@ -37,7 +133,67 @@ impl DalyBMS {
         }
     }
 
+    // A previous transaction was aborted, wait until the bus is quiet and discard the stale reply
+    // The BMS may still be processing the aborted command and start its reply later, so the
+    // bus only counts as quiet after no byte arrived for the whole IO timeout
+    async fn discard_stale_reply(&mut self) -> Result<()> {
+        log::debug!("discard stale reply of an aborted command");
+        let poll_interval = self.delay.max(MINIMUM_DELAY);
+        let give_up = Instant::now() + self.io_timeout * STALE_REPLY_TIMEOUTS;
+        let mut last_byte = Instant::now();
+        while last_byte.elapsed() < self.io_timeout {
+            if Instant::now() >= give_up {
+                bail!(
+                    "Bus not quiet within {:?} after an aborted command",
+                    self.io_timeout * STALE_REPLY_TIMEOUTS
+                );
+            }
+            tokio::time::sleep(poll_interval).await;
+            let pending = self
+                .serial
+                .bytes_to_read()
+                .with_context(|| "Cannot read number of pending bytes")?;
+            if pending > 0 {
+                log::trace!("Discard {} stale bytes", pending);
+                self.serial
+                    .clear(tokio_serial::ClearBuffer::Input)
+                    .with_context(|| "Cannot clear input buffer")?;
+                last_byte = Instant::now();
+            }
+        }
+        self.pending_transaction = false;
+        Ok(())
+    }
+
     async fn send_bytes(&mut self, tx_buffer: &[u8]) -> Result<()> {
+        if !self.quirks.is_supported(tx_buffer[2]) {
+            log::debug!(
+                "Command {:02X} not sent, unsupported by the quirk profile",
+                tx_buffer[2]
+            );
+            return Err(crate::Error::Unsupported(tx_buffer[2]).into());
+        }
+        if self
+            .prefetched
+            .as_ref()
+            .is_some_and(|reply| reply[2] == tx_buffer[2])
+        {
+            log::trace!(
+                "Command {:02X} not sent, its reply was received by a pipeline",
+                tx_buffer[2]
+            );
+            self.last_command = tx_buffer[2];
+            return Ok(());
+        }
+        self.clear_pending().await?;
+        self.serial_await_delay().await;
+        self.write_frame(tx_buffer).await
+    }
+
+    async fn clear_pending(&mut self) -> Result<()> {
+        if self.pending_transaction {
+            self.discard_stale_reply().await?;
+        }
         // clear all incoming serial to avoid data collision
         loop {
             let pending = self
@@ -57,11 +213,24 @@ impl DalyBMS {
                 break;
             }
         }
-        self.serial_await_delay().await;
+        Ok(())
+    }
 
-        tokio::time::timeout(self.io_timeout, self.serial.write_all(tx_buffer))
+    async fn write_frame(&mut self, tx_buffer: &[u8]) -> Result<()> {
+        self.pending_transaction = true;
+        let mut tx_buffer = tx_buffer.to_vec();
+        for middleware in &mut self.middlewares {
+            middleware.on_send(&mut tx_buffer);
+        }
+        self.last_command = tx_buffer[2];
+        let written = tokio::time::timeout(self.io_timeout, self.serial.write_all(&tx_buffer))
             .await
-            .with_context(|| "Cannot write to serial")??;
+            .with_context(|| "Cannot write to serial")
+            .and_then(|result| Ok(result?));
+        if let Err(err) = &written {
+            self.update_connection(Some(err));
+        }
+        written?;
 
         if false {
             tokio::time::timeout(self.io_timeout, self.serial.flush())
@@ -71,21 +240,222 @@ impl DalyBMS {
         Ok(())
     }
 
+    // returns the replies of the pipelined metrics, empty if pipelining isn't possible or failed
+    async fn prefetch(&mut self, metrics: &[Metric]) -> Vec<Vec<u8>> {
+        let status_required = self.status.is_none() && metrics.iter().any(Metric::requires_status);
+        let mut requests: Vec<(Vec<u8>, usize)> = Vec::new();
+        for metric in status_required
+            .then_some(&Metric::Status)
+            .into_iter()
+            .chain(metrics)
+        {
+            if let Some((request, reply_size)) = metric.pipelined_request() {
+                if self.quirks.is_supported(request[2])
+                    && !requests.iter().any(|(other, _)| other[2] == request[2])
+                {
+                    requests.push((request, reply_size));
+                }
+            }
+        }
+        if requests.len() < 2 {
+            return Vec::new();
+        }
+        match self.transfer_pipelined(&requests).await {
+            Ok(replies) => replies,
+            Err(err) => {
+                log::info!(
+                    "Pipelined requests failed, reading the metrics one by one: {:#}",
+                    err
+                );
+                // replies to the other requests may still be in flight, the next command
+                // waits until the bus is quiet
+                self.pending_transaction = true;
+                Vec::new()
+            }
+        }
+    }
+
+    async fn transfer_pipelined(&mut self, requests: &[(Vec<u8>, usize)]) -> Result<Vec<Vec<u8>>> {
+        self.clear_pending().await?;
+        self.serial_await_delay().await;
+        for (request, _) in requests {
+            self.write_frame(request).await?;
+        }
+        let mut replies = Vec::new();
+        for (request, reply_size) in requests {
+            self.last_command = request[2];
+            replies.push(self.receive_bytes(*reply_size).await?);
+        }
+        Ok(replies)
+    }
+
+    // hands the pipelined reply of the metric to its next command
+    fn use_prefetched(&mut self, replies: &mut Vec<Vec<u8>>, metric: Metric) {
+        self.prefetched = metric.pipelined_request().and_then(|(request, _)| {
+            let index = replies.iter().position(|reply| reply[2] == request[2])?;
+            Some(replies.remove(index))
+        });
+    }
+
     async fn receive_bytes(&mut self, size: usize) -> Result<Vec<u8>> {
+        let last_command = self.last_command;
+        if let Some(reply) = self.prefetched.take_if(|reply| reply[2] == last_command) {
+            return Ok(reply);
+        }
+
         // Clear out the input buffer
         let mut rx_buffer = vec![0; size];
 
         // Read bytes from the specified serial interface
-        tokio::time::timeout(self.io_timeout, self.serial.read_exact(&mut rx_buffer))
-            .await
-            .with_context(|| "Cannot receive response")??;
+        let received =
+            tokio::time::timeout(self.read_timeout()?, self.serial.read_exact(&mut rx_buffer))
+                .await
+                .with_context(|| "Cannot receive response")
+                .and_then(|result| Ok(result?));
+        self.update_connection(received.as_ref().err());
+        received?;
 
         self.last_execution = Instant::now();
+        self.pending_transaction = false;
+
+        if let Some(offset) = find_frame_start(self.last_command, &rx_buffer)
+            .filter(|offset| *offset > 0 && self.decode_mode == DecodeMode::Lenient)
+        {
+            log::info!(
+                "Skipped {} bytes before the reply: {:02X?}",
+                offset,
+                &rx_buffer[..offset]
+            );
+            rx_buffer.drain(..offset);
+            let mut rest = vec![0; offset];
+            tokio::time::timeout(self.read_timeout()?, self.serial.read_exact(&mut rest))
+                .await
+                .with_context(|| "Cannot receive response")??;
+            rx_buffer.extend(rest);
+        }
 
+        for middleware in &mut self.middlewares {
+            middleware.on_receive(&mut rx_buffer);
+        }
         log::trace!("receive_bytes: {:02X?}", rx_buffer);
+        validate_supported(self.last_command, &rx_buffer)?;
+        validate_frames(self.last_command, &rx_buffer, self.decode_mode)?;
         Ok(rx_buffer)
     }
 
+    fn emit(&mut self, event: ConnectionEvent) {
+        log::trace!("connection event: {:?}", event);
+        // fails only if nobody subscribed
+        let _ = self.events.send(event);
+    }
+
+    fn update_connection(&mut self, error: Option<&anyhow::Error>) {
+        match (self.connected, error) {
+            (false, None) => {
+                self.connected = true;
+                self.emit(ConnectionEvent::Connected);
+            }
+            (true, Some(err)) => {
+                self.connected = false;
+                self.emit(ConnectionEvent::Disconnected {
+                    error: format!("{:#}", err),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    // starts the deadline of an operation unless an enclosing operation already did, the
+    // deadline lasts as long as the returned `OperationDeadline`
+    fn begin_operation(&mut self) -> OperationDeadline {
+        let mut deadline = self.deadline.lock().unwrap_or_else(PoisonError::into_inner);
+        if deadline.is_some_and(|deadline| deadline > Instant::now()) {
+            return OperationDeadline(None);
+        }
+        *deadline = self
+            .operation_timeout
+            .map(|timeout| Instant::now() + timeout);
+        OperationDeadline(Some(self.deadline.clone()))
+    }
+
+    // time left of the current operation, fails once the deadline passed
+    fn remaining(&self) -> std::result::Result<Option<Duration>, crate::Error> {
+        let Some(deadline) = *self.deadline.lock().unwrap_or_else(PoisonError::into_inner) else {
+            return Ok(None);
+        };
+        let now = Instant::now();
+        if now >= deadline {
+            log::warn!(
+                "Operation timeout of {:?} exceeded",
+                self.operation_timeout.unwrap_or_default()
+            );
+            return Err(crate::Error::Io(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "operation timeout exceeded",
+            )));
+        }
+        Ok(Some(deadline - now))
+    }
+
+    // the IO timeout shortened to the time left of the current operation
+    fn read_timeout(&self) -> std::result::Result<Duration, crate::Error> {
+        Ok(self
+            .remaining()?
+            .map_or(self.io_timeout, |remaining| remaining.min(self.io_timeout)))
+    }
+
+    // counts a failed write verification, returns the error after the last attempt
+    fn verification_failed(
+        &mut self,
+        attempt: &mut u32,
+        name: &'static str,
+        expected: f64,
+        received: f64,
+    ) -> std::result::Result<(), crate::Error> {
+        log::warn!(
+            "Write verification failed - name={} attempt={} expected={} received={}",
+            name,
+            attempt,
+            expected,
+            received
+        );
+        if *attempt >= WRITE_ATTEMPTS {
+            return Err(crate::Error::VerificationFailed {
+                name,
+                expected,
+                received,
+            });
+        }
+        self.remaining()?;
+        *attempt += 1;
+        self.emit(ConnectionEvent::Retry {
+            operation: name,
+            attempt: *attempt,
+        });
+        Ok(())
+    }
+
+    // returns true if the write command must not be sent because of the read only mode
+    fn skip_write(&self, tx_buffer: &[u8]) -> bool {
+        if self.read_only {
+            log::info!("read only mode, frame not sent: {:02X?}", tx_buffer);
+        }
+        self.read_only
+    }
+
+    fn check_plausibility(
+        &mut self,
+        check: impl FnOnce(&PlausibilityLimits) -> std::result::Result<(), crate::Error>,
+    ) -> Result<()> {
+        if let Some(limits) = &self.plausibility_limits {
+            if let Err(err) = check(limits) {
+                self.implausible_readings += 1;
+                return Err(err.into());
+            }
+        }
+        Ok(())
+    }
+
     /// Sets the timeout for I/O operations
     pub fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
         log::trace!("set timeout: {:?}", timeout);
@@ -111,25 +481,159 @@ impl DalyBMS {
         log::trace!("set delay: {:?}", self.delay);
     }
 
+    /// Accepted number of cells and temperature sensors, a status outside these limits is rejected
+    pub fn set_status_limits(&mut self, limits: StatusLimits) {
+        log::trace!("set status limits: {:?}", limits);
+        self.status_limits = limits;
+    }
+
+    /// Rejects physically impossible cell voltages and temperatures, disabled with `None` (default)
+    pub fn set_plausibility_limits(&mut self, limits: Option<PlausibilityLimits>) {
+        log::trace!("set plausibility limits: {:?}", limits);
+        self.plausibility_limits = limits;
+    }
+
+    /// Number of readings rejected by the plausibility limits
+    pub fn implausible_readings(&self) -> u64 {
+        self.implausible_readings
+    }
+
+    /// Correction applied to the current of `get_soc()`
+    pub fn set_current_calibration(&mut self, calibration: CurrentCalibration) {
+        log::trace!("set current calibration: {:?}", calibration);
+        self.quirks.current_calibration = calibration;
+    }
+
+    /// Applies the deviations of a non genuine board, this replaces the current calibration
+    pub fn set_quirk_profile(&mut self, quirks: QuirkProfile) {
+        log::trace!("set quirk profile: {:?}", quirks);
+        self.quirks = quirks;
+    }
+
+    /// Only log the frames of write commands instead of sending them to the BMS
+    pub fn set_read_only(&mut self, enable: bool) {
+        log::trace!("set read only: {:?}", enable);
+        self.read_only = enable;
+    }
+
+    /// Read the state back after writing the SOC or a mosfet and repeat the write if it did not
+    /// take effect, fails with `Error::VerificationFailed` after the last attempt
+    pub fn set_verify_writes(&mut self, enable: bool) {
+        log::trace!("set verify writes: {:?}", enable);
+        self.verify_writes = enable;
+    }
+
+    /// Adds a hook which can inspect or modify every request before it is sent and every reply
+    /// before it is decoded, see `crate::middleware::FrameMiddleware`
+    pub fn add_middleware(&mut self, middleware: impl FrameMiddleware + 'static) {
+        log::trace!("add middleware: {:?}", middleware);
+        self.middlewares.push(Box::new(middleware));
+    }
+
+    /// Sets the table of firmware versions with broken commands, empty by default. Commands of
+    /// the entry matching the software version are skipped after `identify()`
+    pub fn set_firmware_quirks(&mut self, table: Vec<FirmwareQuirks>) {
+        log::trace!("set firmware quirks: {:?}", table);
+        self.firmware_quirks = table;
+    }
+
+    /// Sends the requests of all metrics of `get_snapshot()` with a single frame reply back to
+    /// back and reads the replies afterwards, which saves the delay between the commands. Not every
+    /// firmware tolerates this, the metrics are read one by one if the pipeline fails
+    pub fn set_pipelining(&mut self, enable: bool) {
+        log::trace!("set pipelining: {}", enable);
+        self.pipelining = enable;
+    }
+
+    /// What `watch()` does when a polling cycle takes longer than the interval,
+    /// `MissedTicks::Delay` by default
+    pub fn set_missed_ticks(&mut self, missed_ticks: MissedTicks) {
+        log::trace!("set missed ticks: {:?}", missed_ticks);
+        self.missed_ticks = missed_ticks;
+    }
+
+    /// Lenient mode tolerates replies of boards which deviate from the protocol, see `DecodeMode`
+    pub fn set_decode_mode(&mut self, mode: DecodeMode) {
+        log::trace!("set decode mode: {:?}", mode);
+        self.decode_mode = mode;
+    }
+
+    /// Limits the total time of operations with several round trips, i.e. multi frame reads,
+    /// snapshots and verified writes including their retries. `None` only applies the IO
+    /// timeout to every single read.
+    pub fn set_operation_timeout(&mut self, timeout: Option<Duration>) {
+        log::trace!("set operation timeout: {:?}", timeout);
+        self.operation_timeout = timeout;
+    }
+
+    /// Receives the connection events of this client, see `ConnectionEvent`
+    pub fn subscribe_events(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.events.subscribe()
+    }
+
+    /// Refuse to enable the charge mosfet while a blocking error is active, see `ErrorCode::blocks_charging()`
+    pub fn set_mosfet_interlock(&mut self, enable: bool) {
+        log::trace!("set mosfet interlock: {:?}", enable);
+        self.mosfet_interlock = enable;
+    }
+
+    /// Runs an operation which is aborted as soon as `cancel` completes, e.g. the
+    /// `cancelled()` future of a `tokio_util::sync::CancellationToken`.
+    /// The client stays usable after an abort, see the module documentation.
+    pub async fn cancellable<T, F, C>(&mut self, cancel: C, operation: F) -> Result<T>
+    where
+        F: for<'a> FnOnce(&'a mut Self) -> Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>,
+        C: Future<Output = ()>,
+    {
+        tokio::select! {
+            result = operation(self) => result,
+            _ = cancel => bail!("Operation cancelled"),
+        }
+    }
+
+    /// Health check with the cheapest command, returns the round trip time without the delay
+    /// between the commands
+    pub async fn ping(&mut self) -> Result<Duration> {
+        self.clear_pending().await?;
+        self.serial_await_delay().await;
+        let start = Instant::now();
+        self.write_frame(&Soc::request(Address::Host)).await?;
+        Soc::decode(&self.receive_bytes(Soc::reply_size()).await?)?;
+        Ok(start.elapsed())
+    }
+
     pub async fn get_soc(&mut self) -> Result<Soc> {
         self.send_bytes(&Soc::request(Address::Host)).await?;
-        Ok(Soc::decode(&self.receive_bytes(Soc::reply_size()).await?)?)
+        let mut soc = Soc::decode(&self.receive_bytes(Soc::reply_size()).await?)?;
+        soc.current = self.quirks.current_calibration.apply(soc.current);
+        Ok(soc)
     }
 
     pub async fn get_cell_voltage_range(&mut self) -> Result<CellVoltageRange> {
         self.send_bytes(&CellVoltageRange::request(Address::Host))
             .await?;
-        Ok(CellVoltageRange::decode(
-            &self.receive_bytes(CellVoltageRange::reply_size()).await?,
-        )?)
+        let range =
+            CellVoltageRange::decode(&self.receive_bytes(CellVoltageRange::reply_size()).await?)?;
+        self.check_plausibility(|limits| {
+            limits.check_cell_voltages([range.highest_voltage, range.lowest_voltage])
+        })?;
+        Ok(range)
     }
 
     pub async fn get_temperature_range(&mut self) -> Result<TemperatureRange> {
         self.send_bytes(&TemperatureRange::request(Address::Host))
             .await?;
-        Ok(TemperatureRange::decode(
-            &self.receive_bytes(TemperatureRange::reply_size()).await?,
-        )?)
+        let mut range =
+            TemperatureRange::decode(&self.receive_bytes(TemperatureRange::reply_size()).await?)?;
+        range.highest_temperature = self.quirks.apply_temperature(range.highest_temperature);
+        range.lowest_temperature = self.quirks.apply_temperature(range.lowest_temperature);
+        self.check_plausibility(|limits| {
+            limits.check_temperatures([
+                range.highest_temperature.into(),
+                range.lowest_temperature.into(),
+            ])
+        })?;
+        Ok(range)
     }
 
     pub async fn get_mosfet_status(&mut self) -> Result<MosfetStatus> {
@@ -143,41 +647,63 @@ impl DalyBMS {
     pub async fn get_status(&mut self) -> Result<Status> {
         self.send_bytes(&Status::request(Address::Host)).await?;
         let status = Status::decode(&self.receive_bytes(Status::reply_size()).await?)?;
+        status.validate(&self.status_limits)?;
         self.status = Some(status.clone());
         Ok(status)
     }
 
     pub async fn get_cell_voltages(&mut self) -> Result<Vec<f32>> {
-        let n_cells = if let Some(status) = &self.status {
-            status.cells
-        } else {
-            bail!("get_status() has to be called at least once before calling get_cell_voltages()");
-        };
-        self.send_bytes(&CellVoltages::request(Address::Host))
-            .await?;
-        Ok(CellVoltages::decode(
-            &self
-                .receive_bytes(CellVoltages::reply_size(n_cells))
-                .await?,
-            n_cells,
-        )?)
+        let operation = self.begin_operation();
+        let result: Result<Vec<f32>> = async {
+            let n_cells = if let Some(status) = &self.status {
+                status.cells
+            } else {
+                bail!("get_status() has to be called at least once before calling get_cell_voltages()");
+            };
+            self.send_bytes(&CellVoltages::request(Address::Host))
+                .await?;
+            let voltages = CellVoltages::decode_numbered(
+                &self
+                    .receive_bytes(CellVoltages::reply_size(n_cells))
+                    .await?,
+                n_cells,
+                self.quirks.frame_numbering,
+            )?;
+            self.check_plausibility(|limits| limits.check_cell_voltages(voltages.iter().copied()))?;
+            Ok(voltages)
+        }
+        .await;
+        drop(operation);
+        result
     }
 
     pub async fn get_cell_temperatures(&mut self) -> Result<Vec<i32>> {
-        let n_sensors = if let Some(status) = &self.status {
-            status.temperature_sensors
-        } else {
-            bail!("get_status() has to be called at least once before calling get_cell_temperatures()");
-        };
+        let operation = self.begin_operation();
+        let result: Result<Vec<i32>> = async {
+            let n_sensors = if let Some(status) = &self.status {
+                status.temperature_sensors
+            } else {
+                bail!("get_status() has to be called at least once before calling get_cell_temperatures()");
+            };
 
-        self.send_bytes(&CellTemperatures::request(Address::Host))
-            .await?;
-        Ok(CellTemperatures::decode(
-            &self
-                .receive_bytes(CellTemperatures::reply_size(n_sensors))
-                .await?,
-            n_sensors,
-        )?)
+            self.send_bytes(&CellTemperatures::request(Address::Host))
+                .await?;
+            let mut temperatures = CellTemperatures::decode_numbered(
+                &self
+                    .receive_bytes(CellTemperatures::reply_size(n_sensors))
+                    .await?,
+                n_sensors,
+                self.quirks.frame_numbering,
+            )?;
+            for temperature in temperatures.iter_mut() {
+                *temperature += i32::from(self.quirks.temperature_offset);
+            }
+            self.check_plausibility(|limits| limits.check_temperatures(temperatures.iter().copied()))?;
+            Ok(temperatures)
+        }
+        .await;
+        drop(operation);
+        result
     }
 
     pub async fn get_balancing_status(&mut self) -> Result<Vec<bool>> {
@@ -204,34 +730,781 @@ impl DalyBMS {
         )?)
     }
 
+    /// Reads the given metrics, the status is read first if required by another metric
+    pub async fn get_snapshot(&mut self, metrics: &[Metric]) -> Result<BmsSnapshot> {
+        let cycle_start = Instant::now();
+        let operation = self.begin_operation();
+        let result: Result<BmsSnapshot> = async {
+            let mut snapshot = BmsSnapshot::default();
+            let mut replies = if self.pipelining {
+                self.prefetch(metrics).await
+            } else {
+                Vec::new()
+            };
+            if metrics.contains(&Metric::Status)
+                || (self.status.is_none() && metrics.iter().any(Metric::requires_status))
+            {
+                let at = SystemTime::now();
+                let start = Instant::now();
+                self.use_prefetched(&mut replies, Metric::Status);
+                let status = self.get_status().await?;
+                if metrics.contains(&Metric::Status) {
+                    snapshot.status = Some(status);
+                    snapshot.read_times.insert(
+                        Metric::Status,
+                        ReadTime {
+                            at,
+                            latency: start.elapsed(),
+                        },
+                    );
+                }
+            }
+            for metric in metrics {
+                let at = SystemTime::now();
+                let start = Instant::now();
+                self.use_prefetched(&mut replies, *metric);
+                match metric {
+                    Metric::Status => continue,
+                    Metric::Soc => snapshot.soc = Some(self.get_soc().await?),
+                    Metric::Mosfet => {
+                        snapshot.mosfet_status = Some(self.get_mosfet_status().await?)
+                    }
+                    Metric::VoltageRange => {
+                        snapshot.cell_voltage_range = Some(self.get_cell_voltage_range().await?)
+                    }
+                    Metric::TemperatureRange => {
+                        snapshot.temperature_range = Some(self.get_temperature_range().await?)
+                    }
+                    Metric::CellVoltages => {
+                        snapshot.cell_voltages = Some(self.get_cell_voltages().await?)
+                    }
+                    Metric::CellTemperatures => {
+                        snapshot.cell_temperatures = Some(self.get_cell_temperatures().await?)
+                    }
+                    Metric::Balancing => {
+                        snapshot.balancing_status = Some(self.get_balancing_status().await?)
+                    }
+                    Metric::Errors => snapshot.errors = Some(self.get_errors().await?),
+                }
+                snapshot.read_times.insert(
+                    *metric,
+                    ReadTime {
+                        at,
+                        latency: start.elapsed(),
+                    },
+                );
+            }
+            snapshot.duration = Some(cycle_start.elapsed());
+            Ok(snapshot)
+        }
+        .await;
+        drop(operation);
+        // a reply left over by a failed command must not be used later
+        self.prefetched = None;
+        result
+    }
+
+    /// Only writes the discharge mosfet if its state differs, returns `true` if it was written
+    pub async fn ensure_discharge_mosfet(&mut self, enable: bool) -> Result<bool> {
+        if self.get_mosfet_status().await?.discharging_mosfet == enable {
+            return Ok(false);
+        }
+        self.set_discharge_mosfet(enable).await?;
+        Ok(true)
+    }
+
+    /// Only writes the charge mosfet if its state differs, returns `true` if it was written
+    pub async fn ensure_charge_mosfet(&mut self, enable: bool) -> Result<bool> {
+        if self.get_mosfet_status().await?.charging_mosfet == enable {
+            return Ok(false);
+        }
+        self.set_charge_mosfet(enable).await?;
+        Ok(true)
+    }
+
     pub async fn set_discharge_mosfet(&mut self, enable: bool) -> Result<()> {
-        self.send_bytes(&SetDischargeMosfet::request(Address::Host, enable))
-            .await?;
-        Ok(SetDischargeMosfet::decode(
-            &self.receive_bytes(SetDischargeMosfet::reply_size()).await?,
-        )?)
+        let operation = self.begin_operation();
+        let result: Result<()> = async {
+            let tx_buffer = SetDischargeMosfet::request(Address::Host, enable);
+            if self.skip_write(&tx_buffer) {
+                return Ok(());
+            }
+            let mut attempt = 1;
+            loop {
+                self.send_bytes(&tx_buffer).await?;
+                SetDischargeMosfet::decode(
+                    &self.receive_bytes(SetDischargeMosfet::reply_size()).await?,
+                )?;
+                if !self.verify_writes {
+                    return Ok(());
+                }
+                let received = self.get_mosfet_status().await?.discharging_mosfet;
+                if received == enable {
+                    return Ok(());
+                }
+                self.verification_failed(
+                    &mut attempt,
+                    "discharge mosfet",
+                    u8::from(enable).into(),
+                    u8::from(received).into(),
+                )?;
+            }
+        }
+        .await;
+        drop(operation);
+        result
     }
 
     pub async fn set_charge_mosfet(&mut self, enable: bool) -> Result<()> {
-        self.send_bytes(&SetChargeMosfet::request(Address::Host, enable))
-            .await?;
-        Ok(SetChargeMosfet::decode(
-            &self.receive_bytes(SetChargeMosfet::reply_size()).await?,
+        let operation = self.begin_operation();
+        let result: Result<()> = async {
+            if enable && self.mosfet_interlock {
+                let blocking: Vec<ErrorCode> = self
+                    .get_errors()
+                    .await?
+                    .into_iter()
+                    .filter(|error| error.blocks_charging())
+                    .collect();
+                if !blocking.is_empty() {
+                    bail!(
+                        "Refusing to enable charge mosfet, active errors: {:?}",
+                        blocking
+                    );
+                }
+            }
+            let tx_buffer = SetChargeMosfet::request(Address::Host, enable);
+            if self.skip_write(&tx_buffer) {
+                return Ok(());
+            }
+            let mut attempt = 1;
+            loop {
+                self.send_bytes(&tx_buffer).await?;
+                SetChargeMosfet::decode(&self.receive_bytes(SetChargeMosfet::reply_size()).await?)?;
+                if !self.verify_writes {
+                    return Ok(());
+                }
+                let received = self.get_mosfet_status().await?.charging_mosfet;
+                if received == enable {
+                    return Ok(());
+                }
+                self.verification_failed(
+                    &mut attempt,
+                    "charge mosfet",
+                    u8::from(enable).into(),
+                    u8::from(received).into(),
+                )?;
+            }
+        }
+        .await;
+        drop(operation);
+        result
+    }
+
+    /// Reads the lifetime charge and discharge counters, returns `None` if the firmware
+    /// does not support the command. A timeout is an error, commands a firmware never answers
+    /// can be skipped with `QuirkProfile::unsupported_commands`
+    pub async fn get_cumulative_capacity(&mut self) -> Result<Option<CumulativeCapacity>> {
+        let result = async {
+            self.send_bytes(&CumulativeCapacity::request(Address::Host))
+                .await?;
+            Ok(CumulativeCapacity::decode(
+                &self.receive_bytes(CumulativeCapacity::reply_size()).await?,
+            )?)
+        }
+        .await;
+        unsupported_as_none(result, "Cumulative capacity")
+    }
+
+    /// Reads the battery pack code, a free text stored in the BMS to identify the pack
+    pub async fn get_battery_code(&mut self) -> Result<String> {
+        let operation = self.begin_operation();
+        let result: Result<String> = async {
+            self.send_bytes(&BatteryCode::request(Address::Host))
+                .await?;
+            Ok(BatteryCode::decode_numbered(
+                &self.receive_bytes(BatteryCode::reply_size()).await?,
+                self.quirks.frame_numbering,
+            )?)
+        }
+        .await;
+        drop(operation);
+        result
+    }
+
+    /// Reads the firmware version, not answered by all firmwares
+    pub async fn get_software_version(&mut self) -> Result<String> {
+        let operation = self.begin_operation();
+        let result: Result<String> = async {
+            self.send_bytes(&SoftwareVersion::request(Address::Host))
+                .await?;
+            Ok(SoftwareVersion::decode_numbered(
+                &self.receive_bytes(SoftwareVersion::reply_size()).await?,
+                self.quirks.frame_numbering,
+            )?)
+        }
+        .await;
+        drop(operation);
+        result
+    }
+
+    /// Reads the hardware version, not answered by all firmwares
+    pub async fn get_hardware_version(&mut self) -> Result<String> {
+        let operation = self.begin_operation();
+        let result: Result<String> = async {
+            self.send_bytes(&HardwareVersion::request(Address::Host))
+                .await?;
+            Ok(HardwareVersion::decode_numbered(
+                &self.receive_bytes(HardwareVersion::reply_size()).await?,
+                self.quirks.frame_numbering,
+            )?)
+        }
+        .await;
+        drop(operation);
+        result
+    }
+
+    /// Reads the identity of the BMS and keeps it for `device_info()`, call it once after
+    /// connecting. Values of commands the firmware does not support are left empty, a timeout
+    /// is an error.
+    pub async fn identify(&mut self) -> Result<DeviceInfo> {
+        let operation = self.begin_operation();
+        let result: Result<DeviceInfo> = async {
+            let status = self.get_status().await?;
+            let software_version =
+                unsupported_as_none(self.get_software_version().await, "Software version")?;
+            // skip the broken commands of a known firmware before reading the rest
+            if let Some(version) = &software_version {
+                self.quirks.apply_firmware(version, &self.firmware_quirks);
+            }
+            let info = DeviceInfo {
+                cells: status.cells,
+                temperature_sensors: status.temperature_sensors,
+                battery_code: unsupported_as_none(self.get_battery_code().await, "Battery code")?,
+                software_version,
+                hardware_version: unsupported_as_none(
+                    self.get_hardware_version().await,
+                    "Hardware version",
+                )?,
+            };
+            log::debug!("device info: {:?}", info);
+            self.device_info = Some(info.clone());
+            Ok(info)
+        }
+        .await;
+        drop(operation);
+        result
+    }
+
+    /// The identity read by the last `identify()`
+    pub fn device_info(&self) -> Option<&DeviceInfo> {
+        self.device_info.as_ref()
+    }
+
+    /// Sends a command not modelled by this crate and returns the undecoded reply.
+    /// Raw commands may change the BMS configuration so they are refused in read-only mode.
+    pub async fn raw_command(&mut self, command: u8, data: [u8; 8]) -> Result<RawFrame> {
+        let tx_buffer = RawFrame::request(Address::Host, command, data);
+        if self.skip_write(&tx_buffer) {
+            bail!("Raw command {:02X} not sent in read-only mode", command);
+        }
+        self.send_bytes(&tx_buffer).await?;
+        Ok(RawFrame::decode(
+            &self.receive_bytes(RawFrame::reply_size()).await?,
         )?)
     }
 
-    pub async fn set_soc(&mut self, soc_percent: f32) -> Result<()> {
-        self.send_bytes(&SetSoc::request(Address::Host, soc_percent))
+    pub async fn get_current_limits(&mut self) -> Result<CurrentLimits> {
+        self.send_bytes(&CurrentLimits::request(Address::Host))
             .await?;
-        Ok(SetSoc::decode(
-            &self.receive_bytes(SetSoc::reply_size()).await?,
+        Ok(CurrentLimits::decode(
+            &self.receive_bytes(CurrentLimits::reply_size()).await?,
+        )?)
+    }
+
+    pub async fn set_current_limits(&mut self, limits: &CurrentLimits) -> Result<()> {
+        let tx_buffer = SetCurrentLimits::request(Address::Host, limits);
+        if self.skip_write(&tx_buffer) {
+            return Ok(());
+        }
+        self.send_bytes(&tx_buffer).await?;
+        Ok(SetCurrentLimits::decode(
+            &self.receive_bytes(SetCurrentLimits::reply_size()).await?,
         )?)
     }
 
+    /// Reads all configuration parameters, parameters not supported by the firmware are `None`
+    pub async fn get_parameters(&mut self) -> Result<Parameters> {
+        let current_limits = self.get_current_limits().await;
+        Ok(Parameters {
+            current_limits: unsupported_as_none(current_limits, "Current limits")?,
+        })
+    }
+
+    /// Writes all parameters which are not `None`
+    pub async fn set_parameters(&mut self, parameters: &Parameters) -> Result<()> {
+        if let Some(limits) = &parameters.current_limits {
+            self.set_current_limits(limits).await?;
+        }
+        Ok(())
+    }
+
+    /// Sets the level two charge over-current alarm threshold in the EEPROM, the level one
+    /// threshold is lowered if it exceeds the new one. Both levels are read and written back,
+    /// see `CurrentLimits`, don't use it for dynamic derating
+    pub async fn set_charge_current_limit(&mut self, amps: f32) -> Result<()> {
+        let mut limits = self.get_current_limits().await?;
+        limits.charge_level2 = amps;
+        limits.charge_level1 = limits.charge_level1.min(amps);
+        self.set_current_limits(&limits).await
+    }
+
+    /// Sets the level two discharge over-current alarm threshold in the EEPROM, the level one
+    /// threshold is lowered if it exceeds the new one. Both levels are read and written back,
+    /// see `CurrentLimits`, don't use it for dynamic derating
+    pub async fn set_discharge_current_limit(&mut self, amps: f32) -> Result<()> {
+        let mut limits = self.get_current_limits().await?;
+        limits.discharge_level2 = amps;
+        limits.discharge_level1 = limits.discharge_level1.min(amps);
+        self.set_current_limits(&limits).await
+    }
+
+    pub async fn set_soc(&mut self, soc_percent: f32) -> Result<()> {
+        let operation = self.begin_operation();
+        let result: Result<()> = async {
+            let tx_buffer = SetSoc::request(Address::Host, soc_percent);
+            if self.skip_write(&tx_buffer) {
+                return Ok(());
+            }
+            let expected = soc_percent.clamp(0.0, 100.0);
+            let mut attempt = 1;
+            loop {
+                self.send_bytes(&tx_buffer).await?;
+                SetSoc::decode(&self.receive_bytes(SetSoc::reply_size()).await?)?;
+                if !self.verify_writes {
+                    return Ok(());
+                }
+                let received = self.get_soc().await?.soc_percent;
+                // the SOC is transferred with a resolution of 0.1%
+                if (received - expected).abs() <= 0.1 {
+                    return Ok(());
+                }
+                self.verification_failed(&mut attempt, "SOC", expected.into(), received.into())?;
+            }
+        }
+        .await;
+        drop(operation);
+        result
+    }
+
+    #[deprecated(note = "renamed to `factory_reset()`")]
     pub async fn reset(&mut self) -> Result<()> {
-        self.send_bytes(&BmsReset::request(Address::Host)).await?;
-        Ok(BmsReset::decode(
-            &self.receive_bytes(BmsReset::reply_size()).await?,
+        self.factory_reset().await
+    }
+
+    /// Restores the factory parameters of the BMS
+    pub async fn factory_reset(&mut self) -> Result<()> {
+        let tx_buffer = FactoryReset::request(Address::Host);
+        if self.skip_write(&tx_buffer) {
+            return Ok(());
+        }
+        self.send_bytes(&tx_buffer).await?;
+        Ok(FactoryReset::decode(
+            &self.receive_bytes(FactoryReset::reply_size()).await?,
         )?)
     }
 }
+
+/// Priority of a command queued on a `SharedDalyBMS`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Periodic polling
+    Low,
+    /// Write commands and alert related reads
+    High,
+}
+
+type Task = Box<
+    dyn for<'a> FnOnce(&'a mut DalyBMS) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> + Send,
+>;
+
+struct Job {
+    priority: Priority,
+    sequence: u64,
+    task: Task,
+}
+
+impl PartialEq for Job {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for Job {}
+
+impl PartialOrd for Job {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Job {
+    // higher priority first, same priority in order of arrival
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+/// Cloneable handle to a `DalyBMS` which is owned by a worker task.
+///
+/// Commands from all handles are serialized onto the bus, pending commands with
+/// `Priority::High` are executed before pending ones with `Priority::Low`.
+/// Must be created within a tokio runtime.
+#[derive(Debug, Clone)]
+pub struct SharedDalyBMS {
+    sender: mpsc::UnboundedSender<(Priority, Task)>,
+}
+
+impl SharedDalyBMS {
+    pub fn new(bms: DalyBMS) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(Self::worker(bms, receiver));
+        Self { sender }
+    }
+
+    async fn worker(mut bms: DalyBMS, mut receiver: mpsc::UnboundedReceiver<(Priority, Task)>) {
+        let mut queue = BinaryHeap::new();
+        let mut sequence: u64 = 0;
+        let mut push = |queue: &mut BinaryHeap<Job>, (priority, task): (Priority, Task)| {
+            queue.push(Job {
+                priority,
+                sequence,
+                task,
+            });
+            sequence += 1;
+        };
+        loop {
+            while let Ok(item) = receiver.try_recv() {
+                push(&mut queue, item);
+            }
+            if let Some(job) = queue.pop() {
+                (job.task)(&mut bms).await;
+            } else if let Some(item) = receiver.recv().await {
+                push(&mut queue, item);
+            } else {
+                log::trace!("all handles dropped, stop worker");
+                break;
+            }
+        }
+    }
+
+    /// Queues an arbitrary operation on the client and waits for its result
+    pub async fn execute<T, F>(&self, priority: Priority, operation: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: for<'a> FnOnce(&'a mut DalyBMS) -> Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>
+            + Send
+            + 'static,
+    {
+        let (reply_sender, reply_receiver) = oneshot::channel();
+        let task: Task = Box::new(move |bms: &mut DalyBMS| {
+            Box::pin(async move {
+                // the caller may have given up waiting, nothing to do then
+                let _ = reply_sender.send(operation(bms).await);
+            })
+        });
+        self.sender
+            .send((priority, task))
+            .map_err(|_| anyhow!("BMS worker task is not running"))?;
+        reply_receiver
+            .await
+            .with_context(|| "BMS worker task is not running")?
+    }
+
+    pub async fn ping(&self) -> Result<Duration> {
+        self.execute(Priority::High, |bms| Box::pin(bms.ping()))
+            .await
+    }
+
+    pub async fn get_soc(&self) -> Result<Soc> {
+        self.execute(Priority::Low, |bms| Box::pin(bms.get_soc()))
+            .await
+    }
+
+    pub async fn get_cell_voltage_range(&self) -> Result<CellVoltageRange> {
+        self.execute(Priority::Low, |bms| Box::pin(bms.get_cell_voltage_range()))
+            .await
+    }
+
+    pub async fn get_temperature_range(&self) -> Result<TemperatureRange> {
+        self.execute(Priority::Low, |bms| Box::pin(bms.get_temperature_range()))
+            .await
+    }
+
+    pub async fn get_mosfet_status(&self) -> Result<MosfetStatus> {
+        self.execute(Priority::Low, |bms| Box::pin(bms.get_mosfet_status()))
+            .await
+    }
+
+    pub async fn get_status(&self) -> Result<Status> {
+        self.execute(Priority::Low, |bms| Box::pin(bms.get_status()))
+            .await
+    }
+
+    pub async fn get_cell_voltages(&self) -> Result<Vec<f32>> {
+        self.execute(Priority::Low, |bms| Box::pin(bms.get_cell_voltages()))
+            .await
+    }
+
+    pub async fn get_cell_temperatures(&self) -> Result<Vec<i32>> {
+        self.execute(Priority::Low, |bms| Box::pin(bms.get_cell_temperatures()))
+            .await
+    }
+
+    pub async fn get_balancing_status(&self) -> Result<Vec<bool>> {
+        self.execute(Priority::Low, |bms| Box::pin(bms.get_balancing_status()))
+            .await
+    }
+
+    pub async fn get_errors(&self) -> Result<Vec<ErrorCode>> {
+        self.execute(Priority::High, |bms| Box::pin(bms.get_errors()))
+            .await
+    }
+
+    pub async fn get_current_limits(&self) -> Result<CurrentLimits> {
+        self.execute(Priority::Low, |bms| Box::pin(bms.get_current_limits()))
+            .await
+    }
+
+    pub async fn get_cumulative_capacity(&self) -> Result<Option<CumulativeCapacity>> {
+        self.execute(Priority::Low, |bms| Box::pin(bms.get_cumulative_capacity()))
+            .await
+    }
+
+    pub async fn get_battery_code(&self) -> Result<String> {
+        self.execute(Priority::Low, |bms| Box::pin(bms.get_battery_code()))
+            .await
+    }
+
+    pub async fn get_software_version(&self) -> Result<String> {
+        self.execute(Priority::Low, |bms| Box::pin(bms.get_software_version()))
+            .await
+    }
+
+    pub async fn get_hardware_version(&self) -> Result<String> {
+        self.execute(Priority::Low, |bms| Box::pin(bms.get_hardware_version()))
+            .await
+    }
+
+    pub async fn identify(&self) -> Result<DeviceInfo> {
+        self.execute(Priority::Low, |bms| Box::pin(bms.identify()))
+            .await
+    }
+
+    pub async fn subscribe_events(&self) -> Result<broadcast::Receiver<ConnectionEvent>> {
+        self.execute(Priority::High, |bms| {
+            Box::pin(async move { Ok(bms.subscribe_events()) })
+        })
+        .await
+    }
+
+    pub async fn device_info(&self) -> Result<Option<DeviceInfo>> {
+        self.execute(Priority::Low, |bms| {
+            Box::pin(async move { Ok(bms.device_info().cloned()) })
+        })
+        .await
+    }
+
+    pub async fn raw_command(&self, command: u8, data: [u8; 8]) -> Result<RawFrame> {
+        self.execute(Priority::High, move |bms| {
+            Box::pin(bms.raw_command(command, data))
+        })
+        .await
+    }
+
+    pub async fn get_snapshot(&self, metrics: &[Metric]) -> Result<BmsSnapshot> {
+        let metrics = metrics.to_vec();
+        self.execute(Priority::Low, move |bms| {
+            Box::pin(async move { bms.get_snapshot(&metrics).await })
+        })
+        .await
+    }
+
+    pub async fn ensure_discharge_mosfet(&self, enable: bool) -> Result<bool> {
+        self.execute(Priority::High, move |bms| {
+            Box::pin(bms.ensure_discharge_mosfet(enable))
+        })
+        .await
+    }
+
+    pub async fn ensure_charge_mosfet(&self, enable: bool) -> Result<bool> {
+        self.execute(Priority::High, move |bms| {
+            Box::pin(bms.ensure_charge_mosfet(enable))
+        })
+        .await
+    }
+
+    pub async fn set_discharge_mosfet(&self, enable: bool) -> Result<()> {
+        self.execute(Priority::High, move |bms| {
+            Box::pin(bms.set_discharge_mosfet(enable))
+        })
+        .await
+    }
+
+    pub async fn set_charge_mosfet(&self, enable: bool) -> Result<()> {
+        self.execute(Priority::High, move |bms| {
+            Box::pin(bms.set_charge_mosfet(enable))
+        })
+        .await
+    }
+
+    pub async fn set_current_limits(&self, limits: &CurrentLimits) -> Result<()> {
+        let limits = limits.clone();
+        self.execute(Priority::High, move |bms| {
+            Box::pin(async move { bms.set_current_limits(&limits).await })
+        })
+        .await
+    }
+
+    pub async fn get_parameters(&self) -> Result<Parameters> {
+        self.execute(Priority::Low, |bms| Box::pin(bms.get_parameters()))
+            .await
+    }
+
+    pub async fn set_parameters(&self, parameters: &Parameters) -> Result<()> {
+        let parameters = parameters.clone();
+        self.execute(Priority::High, move |bms| {
+            Box::pin(async move { bms.set_parameters(&parameters).await })
+        })
+        .await
+    }
+
+    pub async fn set_charge_current_limit(&self, amps: f32) -> Result<()> {
+        self.execute(Priority::High, move |bms| {
+            Box::pin(bms.set_charge_current_limit(amps))
+        })
+        .await
+    }
+
+    pub async fn set_discharge_current_limit(&self, amps: f32) -> Result<()> {
+        self.execute(Priority::High, move |bms| {
+            Box::pin(bms.set_discharge_current_limit(amps))
+        })
+        .await
+    }
+
+    pub async fn set_soc(&self, soc_percent: f32) -> Result<()> {
+        self.execute(Priority::High, move |bms| {
+            Box::pin(bms.set_soc(soc_percent))
+        })
+        .await
+    }
+
+    pub async fn factory_reset(&self) -> Result<()> {
+        self.execute(Priority::High, |bms| Box::pin(bms.factory_reset()))
+            .await
+    }
+}
+
+impl DalyBMS {
+    /// Reads the given metrics every `interval` and yields the snapshots as stream. The
+    /// duration of each cycle is `BmsSnapshot::duration`, slow cycles are handled as set by
+    /// `set_missed_ticks()`
+    pub fn watch(
+        &mut self,
+        interval: Duration,
+        metrics: Vec<Metric>,
+    ) -> impl Stream<Item = Result<BmsSnapshot>> + '_ {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(match self.missed_ticks {
+            MissedTicks::Delay => tokio::time::MissedTickBehavior::Delay,
+            MissedTicks::Skip => tokio::time::MissedTickBehavior::Skip,
+            MissedTicks::Burst => tokio::time::MissedTickBehavior::Burst,
+        });
+        futures_util::stream::unfold(
+            (self, ticker, metrics),
+            |(bms, mut ticker, metrics)| async move {
+                ticker.tick().await;
+                let start = Instant::now();
+                let snapshot = bms.get_snapshot(&metrics).await;
+                let elapsed = start.elapsed();
+                log::debug!("polling cycle took {:?}", elapsed);
+                if elapsed > ticker.period() {
+                    log::warn!(
+                        "polling cycle overrun by {:?}, interval {:?}",
+                        elapsed - ticker.period(),
+                        ticker.period()
+                    );
+                }
+                Some((snapshot, (bms, ticker, metrics)))
+            },
+        )
+    }
+}
+
+/// Result of one polling cycle of `SnapshotBroadcast`, the error is shared by all consumers
+pub type BroadcastResult = std::result::Result<Arc<BmsSnapshot>, Arc<anyhow::Error>>;
+
+/// Polls the BMS in a background task and shares the result of every polling cycle with any
+/// number of consumers, so they don't have to issue their own serial traffic. Failed cycles are
+/// shared as `Err`, so consumers learn that the link to the BMS is down.
+///
+/// A slow subscriber never blocks the polling, it misses the oldest results instead
+/// (see `tokio::sync::broadcast`). Consumers only interested in the newest result
+/// should use `latest()`. The polling stops when the `SnapshotBroadcast` is dropped.
+#[derive(Debug)]
+pub struct SnapshotBroadcast {
+    sender: broadcast::Sender<BroadcastResult>,
+    latest: watch::Receiver<Option<BroadcastResult>>,
+    task: JoinHandle<()>,
+}
+
+impl SnapshotBroadcast {
+    /// Must be created within a tokio runtime, `capacity` is the number of results
+    /// buffered for each subscriber, at least one
+    pub fn new(bms: DalyBMS, interval: Duration, metrics: Vec<Metric>, capacity: usize) -> Self {
+        // `broadcast::channel()` panics with a capacity of zero
+        let (sender, _) = broadcast::channel(capacity.max(1));
+        let (latest_sender, latest) = watch::channel(None);
+        let task_sender = sender.clone();
+        let task = tokio::spawn(async move {
+            let mut bms = bms;
+            let mut snapshots = std::pin::pin!(bms.watch(interval, metrics));
+            while let Some(result) = snapshots.next().await {
+                let result = match result {
+                    Ok(snapshot) => Ok(Arc::new(snapshot)),
+                    Err(err) => {
+                        log::warn!("Cannot read snapshot: {:#}", err);
+                        Err(Arc::new(err))
+                    }
+                };
+                latest_sender.send_replace(Some(result.clone()));
+                // having no subscriber at the moment is fine
+                let _ = task_sender.send(result);
+            }
+        });
+        Self {
+            sender,
+            latest,
+            task,
+        }
+    }
+
+    /// Receives the result of every polling cycle after subscribing
+    pub fn subscribe(&self) -> broadcast::Receiver<BroadcastResult> {
+        self.sender.subscribe()
+    }
+
+    /// Receives only the result of the most recent polling cycle, `None` until the first
+    /// cycle finished
+    pub fn latest(&self) -> watch::Receiver<Option<BroadcastResult>> {
+        self.latest.clone()
+    }
+}
+
+impl Drop for SnapshotBroadcast {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
@@ -0,0 +1,52 @@
+//! JavaScript bindings of the frame handling, e.g. for a web page which talks to the BMS via
+//! WebSerial. Build with `cargo rustc --lib --crate-type cdylib --release --target
+//! wasm32-unknown-unknown --no-default-features --features wasm` and generate the JavaScript
+//! glue with `wasm-bindgen`, the serial clients are not available on this target.
+
+use crate::{analyzer, protocol::*};
+use wasm_bindgen::prelude::*;
+
+/// Request of a read command without data, e.g. 0x90 for the SOC
+#[wasm_bindgen(js_name = readRequest)]
+pub fn read_request(command: u8) -> Vec<u8> {
+    RawFrame::request(Address::Host, command, [0; 8])
+}
+
+#[wasm_bindgen(js_name = setSocRequest)]
+pub fn set_soc_request(soc_percent: f32) -> Vec<u8> {
+    SetSoc::request(Address::Host, soc_percent)
+}
+
+#[wasm_bindgen(js_name = setChargeMosfetRequest)]
+pub fn set_charge_mosfet_request(enable: bool) -> Vec<u8> {
+    SetChargeMosfet::request(Address::Host, enable)
+}
+
+#[wasm_bindgen(js_name = setDischargeMosfetRequest)]
+pub fn set_discharge_mosfet_request(enable: bool) -> Vec<u8> {
+    SetDischargeMosfet::request(Address::Host, enable)
+}
+
+#[wasm_bindgen(js_name = decodeSoc)]
+pub fn decode_soc(rx_buffer: &[u8]) -> Result<Soc, JsError> {
+    Ok(Soc::decode(rx_buffer)?)
+}
+
+#[wasm_bindgen(js_name = decodeCellVoltages)]
+pub fn decode_cell_voltages(rx_buffer: &[u8], n_cells: u8) -> Result<Vec<f32>, JsError> {
+    Ok(CellVoltages::decode(rx_buffer, n_cells)?)
+}
+
+#[wasm_bindgen(js_name = decodeCellTemperatures)]
+pub fn decode_cell_temperatures(rx_buffer: &[u8], n_sensors: u8) -> Result<Vec<i32>, JsError> {
+    Ok(CellTemperatures::decode(rx_buffer, n_sensors)?)
+}
+
+/// Describes all frames found in `bytes`, one line per frame like the `decode` subcommand
+#[wasm_bindgen(js_name = describeFrames)]
+pub fn describe_frames(bytes: &[u8]) -> Vec<String> {
+    analyzer::analyze(bytes)
+        .iter()
+        .map(ToString::to_string)
+        .collect()
+}
@@ -0,0 +1,160 @@
+//! Runs the simulator on the master end of a PTY pair, the clients open the slave end like a
+//! real serial port. Shared by the integration tests, not every test uses every helper.
+#![allow(dead_code)]
+
+use dalybms_lib::simulator::Simulator;
+use serialport::{SerialPort, TTYPort};
+use std::{
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+const FRAME_LENGTH: usize = 13;
+const START_BYTE: u8 = 0xA5;
+
+/// How the simulator end answers the next requests
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Behavior {
+    Reply,
+    /// Requests are read but not answered, the client runs into its timeout
+    Silent,
+    /// The reply is written in two parts with a pause in between
+    Split(Duration),
+    /// Bytes which don't belong to a frame are written before the reply
+    LeadingGarbage,
+    /// The next write command is acknowledged without changing the simulator, afterwards
+    /// `Reply` is used again
+    IgnoreWrite,
+}
+
+pub struct PtySimulator {
+    /// Faults injected with `Simulator::inject_fault()` apply to the PTY as well
+    pub simulator: Arc<Mutex<Simulator>>,
+    behavior: Arc<Mutex<Behavior>>,
+    path: String,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl PtySimulator {
+    pub fn start() -> Self {
+        Self::with_simulator(Simulator::default())
+    }
+
+    pub fn with_simulator(simulator: Simulator) -> Self {
+        let (mut master, slave) = TTYPort::pair().expect("Cannot create PTY pair");
+        master
+            .set_timeout(Duration::from_millis(10))
+            .expect("Cannot set PTY timeout");
+        let path = slave.name().expect("PTY slave without name");
+        // the clients open the slave by its path, the PTY stays alive with the master
+        drop(slave);
+
+        let simulator = Arc::new(Mutex::new(simulator));
+        let behavior = Arc::new(Mutex::new(Behavior::Reply));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let simulator = simulator.clone();
+            let behavior = behavior.clone();
+            let stop = stop.clone();
+            thread::spawn(move || serve(master, simulator, behavior, stop))
+        };
+        Self {
+            simulator,
+            behavior,
+            path,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Path of the slave end, e.g. `/dev/pts/3`
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn set_behavior(&self, behavior: Behavior) {
+        *self.behavior.lock().unwrap() = behavior;
+    }
+
+    pub fn requests(&self) -> u64 {
+        self.simulator.lock().unwrap().requests
+    }
+}
+
+impl Drop for PtySimulator {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn is_write_command(command: u8) -> bool {
+    matches!(command, 0x21 | 0xD9 | 0xDA | 0x1B)
+}
+
+fn serve(
+    mut master: TTYPort,
+    simulator: Arc<Mutex<Simulator>>,
+    behavior: Arc<Mutex<Behavior>>,
+    stop: Arc<AtomicBool>,
+) {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 64];
+    while !stop.load(Ordering::Relaxed) {
+        match master.read(&mut chunk) {
+            Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => continue,
+            // no client has the slave end open
+            Err(_) => {
+                thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+        }
+        while buffer.len() >= FRAME_LENGTH {
+            if buffer[0] != START_BYTE {
+                buffer.remove(0);
+                continue;
+            }
+            let request: Vec<u8> = buffer.drain(..FRAME_LENGTH).collect();
+            let current = *behavior.lock().unwrap();
+            let reply = match current {
+                Behavior::IgnoreWrite if is_write_command(request[2]) => {
+                    *behavior.lock().unwrap() = Behavior::Reply;
+                    simulator.lock().unwrap().clone().reply(&request)
+                }
+                _ => simulator.lock().unwrap().reply(&request),
+            };
+            // delay injected with `Fault::Delay`
+            thread::sleep(reply.delay);
+            let reply = reply.bytes;
+            let written = match current {
+                Behavior::Silent => Ok(()),
+                Behavior::Split(pause) => {
+                    let (first, second) = reply.split_at(reply.len() / 2);
+                    master
+                        .write_all(first)
+                        .and_then(|_| master.flush())
+                        .and_then(|_| {
+                            thread::sleep(pause);
+                            master.write_all(second)
+                        })
+                }
+                Behavior::LeadingGarbage => master
+                    .write_all(&[0x00, 0xFF, 0x13])
+                    .and_then(|_| master.write_all(&reply)),
+                Behavior::Reply | Behavior::IgnoreWrite => master.write_all(&reply),
+            };
+            if let Err(err) = written.and_then(|_| master.flush()) {
+                eprintln!("PTY simulator cannot write the reply: {}", err);
+            }
+        }
+    }
+}
@@ -0,0 +1,120 @@
+#![cfg(feature = "serialport")]
+
+use dalybms_lib::{
+    protocol::{Address, CellVoltages},
+    serialport::DalyBMS,
+    simulator::{Fault, Simulator, SimulatorPort},
+};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+const CELL_VOLTAGES: u8 = 0x95;
+const SOC: u8 = 0x90;
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+fn client() -> (Arc<Mutex<Simulator>>, DalyBMS) {
+    let simulator = Arc::new(Mutex::new(Simulator::default()));
+    let mut bms = DalyBMS::from_port(Box::new(SimulatorPort::new(simulator.clone())));
+    bms.set_timeout(TIMEOUT).unwrap();
+    bms.set_delay(Duration::ZERO);
+    bms.get_status().unwrap();
+    (simulator, bms)
+}
+
+fn inject(simulator: &Mutex<Simulator>, command: u8, fault: Fault) {
+    simulator
+        .lock()
+        .unwrap()
+        .inject_fault(Some(command), fault, Some(1));
+}
+
+#[test]
+fn reply_frames() {
+    let mut simulator = Simulator::default();
+    let request = CellVoltages::request(Address::Host);
+    let reply = simulator.respond(&request);
+
+    simulator.inject_fault(None, Fault::DropFrame(1), Some(1));
+    assert_eq!(simulator.respond(&request).len(), reply.len() - 13);
+    simulator.inject_fault(None, Fault::DuplicateFrame(0), Some(1));
+    let duplicated = simulator.respond(&request);
+    assert_eq!(duplicated[..13], duplicated[13..26]);
+    simulator.inject_fault(None, Fault::NoReply, Some(1));
+    assert!(simulator.respond(&request).is_empty());
+    simulator.inject_fault(None, Fault::Delay(TIMEOUT), Some(1));
+    assert_eq!(simulator.reply(&request).delay, TIMEOUT);
+
+    // all faults are used up
+    assert!(simulator.faults.is_empty());
+    assert_eq!(simulator.respond(&request), reply);
+}
+
+#[test]
+fn delay() {
+    let (simulator, mut bms) = client();
+
+    inject(&simulator, SOC, Fault::Delay(TIMEOUT / 2));
+    let start = Instant::now();
+    bms.get_soc().unwrap();
+    assert!(start.elapsed() >= TIMEOUT / 2, "{:?}", start.elapsed());
+
+    inject(&simulator, SOC, Fault::Delay(TIMEOUT * 2));
+    let err = bms.get_soc().unwrap_err();
+    let io_error = err.root_cause().downcast_ref::<std::io::Error>().unwrap();
+    assert_eq!(io_error.kind(), std::io::ErrorKind::TimedOut);
+}
+
+#[test]
+fn faults_per_command() {
+    let (simulator, mut bms) = client();
+    simulator
+        .lock()
+        .unwrap()
+        .inject_fault(Some(CELL_VOLTAGES), Fault::NoReply, None);
+
+    assert!(bms.get_cell_voltages().is_err());
+    assert!(bms.get_cell_voltages().is_err());
+    assert_eq!(bms.get_soc().unwrap().soc_percent, 75.3);
+
+    simulator.lock().unwrap().clear_faults();
+    assert_eq!(bms.get_cell_voltages().unwrap().len(), 16);
+}
+
+#[test]
+fn corrupted_checksum() {
+    let (simulator, mut bms) = client();
+    inject(&simulator, CELL_VOLTAGES, Fault::CorruptChecksum(2));
+
+    assert!(bms.get_cell_voltages().is_err());
+    assert_eq!(bms.get_cell_voltages().unwrap().len(), 16);
+}
+
+#[test]
+fn wrong_frame_number() {
+    let (simulator, mut bms) = client();
+    inject(&simulator, CELL_VOLTAGES, Fault::WrongFrameNumber(1));
+
+    assert!(bms.get_cell_voltages().is_err());
+    assert_eq!(bms.get_cell_voltages().unwrap().len(), 16);
+}
+
+#[test]
+fn dropped_frame() {
+    let (simulator, mut bms) = client();
+    inject(&simulator, CELL_VOLTAGES, Fault::DropFrame(3));
+
+    assert!(bms.get_cell_voltages().is_err());
+    assert_eq!(bms.get_cell_voltages().unwrap().len(), 16);
+}
+
+#[test]
+fn duplicated_frame() {
+    let (simulator, mut bms) = client();
+    inject(&simulator, CELL_VOLTAGES, Fault::DuplicateFrame(0));
+
+    assert!(bms.get_cell_voltages().is_err());
+    // the surplus frame is discarded before the next request
+    assert_eq!(bms.get_cell_voltages().unwrap().len(), 16);
+}
@@ -0,0 +1,240 @@
+#![cfg(all(unix, feature = "serialport"))]
+
+mod common;
+
+use common::{Behavior, PtySimulator};
+use dalybms_lib::{
+    connection::ConnectionEvent,
+    protocol::DecodeMode,
+    serialport::DalyBMS,
+    simulator::Fault,
+    snapshot::{Metric, MissedTicks},
+};
+use std::{
+    sync::mpsc::Receiver,
+    time::{Duration, Instant},
+};
+
+const TIMEOUT: Duration = Duration::from_millis(200);
+
+fn open(pty: &PtySimulator) -> DalyBMS {
+    let mut bms = DalyBMS::new(pty.path()).unwrap();
+    bms.set_timeout(TIMEOUT).unwrap();
+    bms.set_delay(Duration::ZERO);
+    bms
+}
+
+fn events(receiver: &Receiver<ConnectionEvent>) -> Vec<ConnectionEvent> {
+    receiver.try_iter().collect()
+}
+
+#[test]
+fn snapshot() {
+    let pty = PtySimulator::start();
+    let mut bms = open(&pty);
+
+    let snapshot = bms.get_snapshot(&Metric::ALL).unwrap();
+
+    let simulator = pty.simulator.lock().unwrap();
+    assert_eq!(snapshot.status.unwrap().cells, 16);
+    // the cell voltages are transferred in mV
+    for (received, simulated) in snapshot
+        .cell_voltages
+        .unwrap()
+        .iter()
+        .zip(&simulator.cell_voltages)
+    {
+        assert!(
+            (received - simulated).abs() < 0.0005,
+            "{} {}",
+            received,
+            simulated
+        );
+    }
+    assert_eq!(
+        snapshot.cell_temperatures.unwrap(),
+        simulator.cell_temperatures
+    );
+    assert_eq!(snapshot.balancing_status.unwrap().len(), 16);
+    assert_eq!(snapshot.soc.unwrap().soc_percent, simulator.soc_percent);
+}
+
+#[test]
+fn pipelined_snapshot() {
+    let pty = PtySimulator::start();
+    let mut bms = open(&pty);
+    bms.set_pipelining(true);
+
+    let snapshot = bms.get_snapshot(&Metric::ALL).unwrap();
+
+    assert!(snapshot.errors.unwrap().is_empty());
+    assert_eq!(snapshot.cell_voltages.unwrap().len(), 16);
+    // the client must not leave replies behind for the next command
+    assert_eq!(bms.get_soc().unwrap().soc_percent, 75.3);
+}
+
+#[test]
+fn failed_pipeline_falls_back() {
+    let pty = PtySimulator::start();
+    let mut bms = open(&pty);
+    bms.set_pipelining(true);
+    {
+        let mut simulator = pty.simulator.lock().unwrap();
+        simulator.inject_fault(Some(0x94), Fault::NoReply, Some(1));
+        // the remaining pipelined replies are still in flight when the first one times out
+        simulator.inject_fault(Some(0x90), Fault::Delay(TIMEOUT * 2), Some(1));
+    }
+
+    let snapshot = bms.get_snapshot(&Metric::ALL).unwrap();
+
+    assert_eq!(snapshot.status.unwrap().cells, 16);
+    assert_eq!(snapshot.soc.unwrap().soc_percent, 75.3);
+}
+
+#[test]
+fn timeout() {
+    let pty = PtySimulator::start();
+    let mut bms = open(&pty);
+    pty.set_behavior(Behavior::Silent);
+
+    let start = Instant::now();
+    let err = bms.get_soc().unwrap_err();
+
+    assert!(start.elapsed() >= TIMEOUT, "{:?}", start.elapsed());
+    assert!(start.elapsed() < TIMEOUT * 5, "{:?}", start.elapsed());
+    let io_error = err.root_cause().downcast_ref::<std::io::Error>().unwrap();
+    assert_eq!(io_error.kind(), std::io::ErrorKind::TimedOut);
+}
+
+#[test]
+fn operation_timeout() {
+    let pty = PtySimulator::start();
+    let mut bms = open(&pty);
+    bms.set_operation_timeout(Some(Duration::from_millis(50)));
+    bms.get_status().unwrap();
+    pty.set_behavior(Behavior::Silent);
+
+    let start = Instant::now();
+    assert!(bms.get_cell_voltages().is_err());
+    assert!(start.elapsed() < TIMEOUT, "{:?}", start.elapsed());
+}
+
+#[test]
+fn injected_delay() {
+    let pty = PtySimulator::start();
+    let mut bms = open(&pty);
+    pty.simulator
+        .lock()
+        .unwrap()
+        .inject_fault(Some(0x90), Fault::Delay(TIMEOUT / 2), Some(1));
+
+    let start = Instant::now();
+    bms.get_soc().unwrap();
+    assert!(start.elapsed() >= TIMEOUT / 2, "{:?}", start.elapsed());
+    bms.get_soc().unwrap();
+}
+
+#[test]
+fn partial_frames() {
+    let pty = PtySimulator::start();
+    let mut bms = open(&pty);
+    pty.set_behavior(Behavior::Split(Duration::from_millis(50)));
+
+    assert_eq!(bms.get_soc().unwrap().soc_percent, 75.3);
+    // multi frame replies are split in the middle of a frame
+    bms.get_status().unwrap();
+    assert_eq!(bms.get_cell_voltages().unwrap().len(), 16);
+}
+
+#[test]
+fn leading_garbage() {
+    let pty = PtySimulator::start();
+    let mut bms = open(&pty);
+    pty.set_behavior(Behavior::LeadingGarbage);
+
+    assert!(bms.get_soc().is_err());
+
+    // the rest of the reply left by the strict read is cleared before the next request
+    bms.set_decode_mode(DecodeMode::Lenient);
+    assert_eq!(bms.get_soc().unwrap().soc_percent, 75.3);
+}
+
+#[test]
+fn verified_write_is_retried() {
+    let pty = PtySimulator::start();
+    let mut bms = open(&pty);
+    bms.set_verify_writes(true);
+    let receiver = bms.subscribe_events();
+    pty.set_behavior(Behavior::IgnoreWrite);
+
+    bms.set_soc(42.0).unwrap();
+
+    assert_eq!(pty.simulator.lock().unwrap().soc_percent, 42.0);
+    let retries: Vec<_> = events(&receiver)
+        .into_iter()
+        .filter(|event| matches!(event, ConnectionEvent::Retry { .. }))
+        .collect();
+    assert_eq!(
+        retries,
+        vec![ConnectionEvent::Retry {
+            operation: "SOC",
+            attempt: 2
+        }]
+    );
+}
+
+#[test]
+fn reconnect() {
+    let pty = PtySimulator::start();
+    let mut bms = open(&pty);
+    let receiver = bms.subscribe_events();
+
+    bms.get_soc().unwrap();
+    pty.set_behavior(Behavior::Silent);
+    assert!(bms.get_soc().is_err());
+    assert!(bms.get_soc().is_err());
+    pty.set_behavior(Behavior::Reply);
+    bms.get_soc().unwrap();
+
+    let events = events(&receiver);
+    assert!(matches!(
+        events.as_slice(),
+        [
+            ConnectionEvent::Connected,
+            ConnectionEvent::Disconnected { .. },
+            ConnectionEvent::Connected
+        ]
+    ));
+}
+
+#[test]
+fn reopen_port() {
+    let pty = PtySimulator::start();
+    let mut bms = open(&pty);
+    bms.get_status().unwrap();
+    drop(bms);
+
+    let mut bms = open(&pty);
+    assert_eq!(bms.get_status().unwrap().temperature_sensors, 3);
+    assert!(pty.requests() >= 2);
+}
+
+#[test]
+fn watch_skips_missed_ticks() {
+    let pty = PtySimulator::start();
+    let mut bms = open(&pty);
+    bms.set_missed_ticks(MissedTicks::Skip);
+    let interval = Duration::from_millis(100);
+    pty.simulator
+        .lock()
+        .unwrap()
+        .inject_fault(Some(0x90), Fault::Delay(interval * 3 / 2), Some(1));
+
+    let start = Instant::now();
+    let receiver = bms.watch(interval, vec![Metric::Soc]);
+    let slow = receiver.recv().unwrap().unwrap();
+    assert!(slow.duration.unwrap() >= interval, "{:?}", slow.duration);
+    receiver.recv().unwrap().unwrap();
+    // the cycle of the tick missed by the slow one is skipped
+    assert!(start.elapsed() >= interval * 2, "{:?}", start.elapsed());
+}
@@ -0,0 +1,214 @@
+#![cfg(all(unix, feature = "serialport", feature = "tokio-serial-async"))]
+
+mod common;
+
+use common::{Behavior, PtySimulator};
+use dalybms_lib::{
+    connection::ConnectionEvent,
+    simulator::Fault,
+    snapshot::Metric,
+    tokio_serial_async::{DalyBMS, Priority, SharedDalyBMS, SnapshotBroadcast},
+};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::Receiver;
+
+const TIMEOUT: Duration = Duration::from_millis(200);
+
+fn open(pty: &PtySimulator) -> DalyBMS {
+    let mut bms = DalyBMS::new(pty.path()).unwrap();
+    bms.set_timeout(TIMEOUT).unwrap();
+    bms.set_delay(Duration::ZERO);
+    bms
+}
+
+fn events(receiver: &mut Receiver<ConnectionEvent>) -> Vec<ConnectionEvent> {
+    std::iter::from_fn(|| receiver.try_recv().ok()).collect()
+}
+
+#[tokio::test]
+async fn snapshot() {
+    let pty = PtySimulator::start();
+    let mut bms = open(&pty);
+
+    let snapshot = bms.get_snapshot(&Metric::ALL).await.unwrap();
+
+    assert_eq!(snapshot.status.unwrap().cells, 16);
+    assert_eq!(snapshot.cell_voltages.unwrap().len(), 16);
+    assert_eq!(snapshot.cell_temperatures.unwrap(), vec![21, 22, 20]);
+    assert_eq!(snapshot.soc.unwrap().soc_percent, 75.3);
+}
+
+#[tokio::test]
+async fn pipelined_snapshot() {
+    let pty = PtySimulator::start();
+    let mut bms = open(&pty);
+    bms.set_pipelining(true);
+
+    let snapshot = bms.get_snapshot(&Metric::ALL).await.unwrap();
+
+    assert!(snapshot.errors.unwrap().is_empty());
+    assert_eq!(bms.get_soc().await.unwrap().soc_percent, 75.3);
+}
+
+#[tokio::test]
+async fn failed_pipeline_falls_back() {
+    let pty = PtySimulator::start();
+    let mut bms = open(&pty);
+    bms.set_pipelining(true);
+    {
+        let mut simulator = pty.simulator.lock().unwrap();
+        simulator.inject_fault(Some(0x94), Fault::NoReply, Some(1));
+        // the remaining pipelined replies are still in flight when the first one times out
+        simulator.inject_fault(Some(0x90), Fault::Delay(TIMEOUT * 2), Some(1));
+    }
+
+    let snapshot = bms.get_snapshot(&Metric::ALL).await.unwrap();
+
+    assert_eq!(snapshot.status.unwrap().cells, 16);
+    assert_eq!(snapshot.soc.unwrap().soc_percent, 75.3);
+}
+
+#[tokio::test]
+async fn timeout() {
+    let pty = PtySimulator::start();
+    let mut bms = open(&pty);
+    pty.set_behavior(Behavior::Silent);
+
+    let start = Instant::now();
+    assert!(bms.get_soc().await.is_err());
+
+    assert!(start.elapsed() >= TIMEOUT, "{:?}", start.elapsed());
+    assert!(start.elapsed() < TIMEOUT * 5, "{:?}", start.elapsed());
+}
+
+#[tokio::test]
+async fn late_reply_is_discarded() {
+    let pty = PtySimulator::start();
+    let mut bms = open(&pty);
+    pty.simulator
+        .lock()
+        .unwrap()
+        .inject_fault(Some(0x90), Fault::Delay(TIMEOUT * 3 / 2), Some(1));
+
+    assert!(bms.get_soc().await.is_err());
+    // the SOC reply arrives while the client waits for the bus to become quiet
+    assert_eq!(bms.get_status().await.unwrap().cells, 16);
+}
+
+#[tokio::test]
+async fn partial_frames() {
+    let pty = PtySimulator::start();
+    let mut bms = open(&pty);
+    pty.set_behavior(Behavior::Split(Duration::from_millis(50)));
+
+    assert_eq!(bms.get_soc().await.unwrap().soc_percent, 75.3);
+    bms.get_status().await.unwrap();
+    assert_eq!(bms.get_cell_voltages().await.unwrap().len(), 16);
+}
+
+#[tokio::test]
+async fn verified_write_is_retried() {
+    let pty = PtySimulator::start();
+    let mut bms = open(&pty);
+    bms.set_verify_writes(true);
+    let mut receiver = bms.subscribe_events();
+    pty.set_behavior(Behavior::IgnoreWrite);
+
+    bms.set_soc(42.0).await.unwrap();
+
+    assert_eq!(pty.simulator.lock().unwrap().soc_percent, 42.0);
+    assert!(events(&mut receiver).contains(&ConnectionEvent::Retry {
+        operation: "SOC",
+        attempt: 2
+    }));
+}
+
+#[tokio::test]
+async fn reconnect() {
+    let pty = PtySimulator::start();
+    let mut bms = open(&pty);
+    let mut receiver = bms.subscribe_events();
+
+    bms.get_soc().await.unwrap();
+    pty.set_behavior(Behavior::Silent);
+    assert!(bms.get_soc().await.is_err());
+    pty.set_behavior(Behavior::Reply);
+    bms.get_soc().await.unwrap();
+
+    assert!(matches!(
+        events(&mut receiver).as_slice(),
+        [
+            ConnectionEvent::Connected,
+            ConnectionEvent::Disconnected { .. },
+            ConnectionEvent::Connected
+        ]
+    ));
+}
+
+#[tokio::test]
+async fn shared_client() {
+    let pty = PtySimulator::start();
+    let bms = SharedDalyBMS::new(open(&pty));
+
+    let (soc, status) = tokio::join!(
+        bms.get_soc(),
+        bms.execute(Priority::High, |bms| Box::pin(bms.get_status()))
+    );
+
+    assert_eq!(soc.unwrap().soc_percent, 75.3);
+    assert_eq!(status.unwrap().cells, 16);
+}
+
+#[tokio::test]
+async fn broadcast_reports_failed_cycles() {
+    let pty = PtySimulator::start();
+    let broadcast =
+        SnapshotBroadcast::new(open(&pty), Duration::from_millis(50), vec![Metric::Soc], 8);
+    let mut receiver = broadcast.subscribe();
+
+    let snapshot = receiver.recv().await.unwrap().unwrap();
+    assert_eq!(snapshot.soc.as_ref().unwrap().soc_percent, 75.3);
+    pty.set_behavior(Behavior::Silent);
+    // a cycle may have been in flight when the link went down
+    let mut result = receiver.recv().await.unwrap();
+    if result.is_ok() {
+        result = receiver.recv().await.unwrap();
+    }
+    assert!(result.is_err());
+    assert!(broadcast.latest().borrow().as_ref().unwrap().is_err());
+}
+
+#[tokio::test]
+async fn cancelled_operation_clears_deadline() {
+    let pty = PtySimulator::start();
+    let mut bms = open(&pty);
+    bms.set_operation_timeout(Some(Duration::from_millis(300)));
+    bms.get_status().await.unwrap();
+    pty.simulator.lock().unwrap().inject_fault(
+        Some(0x95),
+        Fault::Delay(Duration::from_millis(100)),
+        Some(1),
+    );
+
+    assert!(
+        tokio::time::timeout(Duration::from_millis(50), bms.get_cell_voltages())
+            .await
+            .is_err()
+    );
+
+    // commands without an own deadline must not inherit the one of the dropped operation
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(bms.get_soc().await.unwrap().soc_percent, 75.3);
+    assert!(bms.get_errors().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn broadcast_without_capacity() {
+    let pty = PtySimulator::start();
+    let broadcast =
+        SnapshotBroadcast::new(open(&pty), Duration::from_millis(50), vec![Metric::Soc], 0);
+    let mut latest = broadcast.latest();
+
+    latest.changed().await.unwrap();
+    assert!(latest.borrow().as_ref().unwrap().is_ok());
+}
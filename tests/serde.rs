@@ -0,0 +1,192 @@
+#![cfg(feature = "serde")]
+
+use dalybms_lib::{
+    protocol::*,
+    snapshot::{BmsSnapshot, Metric, ReadTime},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::json;
+use std::time::{Duration, SystemTime};
+
+// the protocol structs don't implement PartialEq, so the JSON values are compared
+fn assert_round_trip<T: Serialize + DeserializeOwned>(value: &T) {
+    let json = serde_json::to_value(value).unwrap();
+    let parsed: T = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(serde_json::to_value(&parsed).unwrap(), json);
+}
+
+fn soc() -> Soc {
+    Soc {
+        total_voltage: 52.9,
+        current: -4.2,
+        soc_percent: 75.3,
+    }
+}
+
+fn status() -> Status {
+    Status {
+        cells: 16,
+        temperature_sensors: 3,
+        charger_running: true,
+        load_running: false,
+        states: IOState {
+            di1: true,
+            di2: false,
+            di3: false,
+            di4: false,
+            do1: false,
+            do2: false,
+            do3: false,
+            do4: true,
+        },
+        cycles: 42,
+    }
+}
+
+#[test]
+fn protocol_structs_round_trip() {
+    assert_round_trip(&soc());
+    assert_round_trip(&status());
+    assert_round_trip(&CellVoltageRange {
+        highest_voltage: 3.345,
+        highest_cell: 4,
+        lowest_voltage: 3.298,
+        lowest_cell: 11,
+    });
+    assert_round_trip(&TemperatureRange {
+        highest_temperature: 22,
+        highest_sensor: 2,
+        lowest_temperature: -5,
+        lowest_sensor: 3,
+    });
+    assert_round_trip(&MosfetStatus {
+        mode: MosfetMode::Charging,
+        charging_mosfet: true,
+        discharging_mosfet: true,
+        bms_cycles: 7,
+        capacity_ah: 204.8,
+    });
+    assert_round_trip(&CurrentLimits {
+        charge_level1: 100.0,
+        charge_level2: 110.0,
+        discharge_level1: 150.0,
+        discharge_level2: 165.5,
+    });
+    assert_round_trip(&CumulativeCapacity {
+        charge_ah: 12345.6,
+        discharge_ah: 12001.2,
+    });
+    assert_round_trip(&vec![
+        ErrorCode::CellVoltHighLevel1,
+        ErrorCode::SumVoltHighLevel1,
+    ]);
+}
+
+#[test]
+fn snapshot_round_trip() {
+    let mut snapshot = BmsSnapshot {
+        status: Some(status()),
+        soc: Some(soc()),
+        cell_voltages: Some(vec![3.301, 3.3, 3.299]),
+        cell_temperatures: Some(vec![21, 22, -3]),
+        balancing_status: Some(vec![false, true, false]),
+        errors: Some(vec![]),
+        ..BmsSnapshot::default()
+    };
+    snapshot.read_times.insert(
+        Metric::Soc,
+        ReadTime {
+            at: SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_123),
+            latency: Duration::from_millis(31),
+        },
+    );
+    assert_round_trip(&snapshot);
+}
+
+#[test]
+fn integers_are_accepted_for_floats() {
+    let soc: Soc = serde_json::from_value(json!({
+        "total_voltage": 53,
+        "current": -4,
+        "soc_percent": 100
+    }))
+    .unwrap();
+    assert_eq!(soc.total_voltage, 53.0);
+    assert_eq!(soc.current, -4.0);
+    assert_eq!(soc.soc_percent, 100.0);
+}
+
+#[test]
+fn missing_snapshot_fields_are_none() {
+    let snapshot: BmsSnapshot = serde_json::from_value(json!({
+        "soc": { "total_voltage": 52.9, "current": 0.0, "soc_percent": 80.0 }
+    }))
+    .unwrap();
+    assert_eq!(snapshot.soc.unwrap().soc_percent, 80.0);
+    assert!(snapshot.status.is_none());
+    assert!(snapshot.cell_voltages.is_none());
+    assert!(snapshot.read_times.is_empty());
+}
+
+#[test]
+fn missing_protocol_fields_are_defaults() {
+    let soc: Soc = serde_json::from_value(json!({ "soc_percent": 80.0 })).unwrap();
+    assert_eq!(soc.soc_percent, 80.0);
+    assert_eq!(soc.total_voltage, 0.0);
+
+    let status: Status = serde_json::from_value(json!({
+        "cells": 16,
+        "states": { "di1": true }
+    }))
+    .unwrap();
+    assert_eq!(status.cells, 16);
+    assert_eq!(status.temperature_sensors, 0);
+    assert!(status.states.di1);
+    assert!(!status.states.do4);
+
+    let mosfet: MosfetStatus = serde_json::from_value(json!({ "charging_mosfet": true })).unwrap();
+    assert_eq!(mosfet.mode, MosfetMode::Stationary);
+    assert!(mosfet.charging_mosfet);
+    assert_eq!(mosfet.capacity_ah, 0.0);
+}
+
+#[test]
+fn rounded_values_round_trip() {
+    let mut snapshot = BmsSnapshot {
+        soc: Some(Soc {
+            total_voltage: 52.92,
+            current: -4.2 * 1.013,
+            soc_percent: 75.34,
+        }),
+        ..BmsSnapshot::default()
+    };
+    dalybms_lib::snapshot::Precision::default().round_snapshot(&mut snapshot);
+    let json = serde_json::to_string(&snapshot).unwrap();
+    let parsed: BmsSnapshot = serde_json::from_str(&json).unwrap();
+    let soc = parsed.soc.unwrap();
+    assert_eq!(soc.total_voltage, 52.92);
+    assert_eq!(soc.current, -4.3);
+    assert_eq!(soc.soc_percent, 75.3);
+}
+
+#[test]
+fn unsupported_parameters_are_rejected() {
+    let parameters: dalybms_lib::parameters::Parameters = serde_json::from_value(json!({
+        "current_limits": {
+            "charge_level1": 100.0,
+            "charge_level2": 110.0,
+            "discharge_level1": 150.0,
+            "discharge_level2": 160.0
+        }
+    }))
+    .unwrap();
+    assert!(parameters.current_limits.is_some());
+
+    // the write commands of the voltage thresholds are unknown
+    assert!(
+        serde_json::from_value::<dalybms_lib::parameters::Parameters>(json!({
+            "cell_voltage_thresholds": { "high_level1": 3.65 }
+        }))
+        .is_err()
+    );
+}